@@ -0,0 +1,20 @@
+# Sends a query to the RustSat REP socket and prints the reply.
+import sys
+
+import msgpack
+import zmq
+
+context = zmq.Context()
+req = context.socket(zmq.REQ)
+req.connect("tcp://127.0.0.1:5553")
+
+queries = {
+    "state": {"type": "satellite_state", "norad_id": 44713},
+    "path": {"type": "path", "from": 0, "to": 1},
+    "visibility": {"type": "visibility", "site": 0, "min_elevation": 25.0},
+    "passes": {"type": "passes", "norad_id": 44713, "site": {"lat": 51.0, "lon": -114.0}},
+}
+
+name = sys.argv[1] if len(sys.argv) > 1 else "path"
+req.send(msgpack.packb({"version": 1, "id": 1, "query": queries[name]}))
+print(msgpack.unpackb(req.recv()))
@@ -41,7 +41,10 @@ received_data = {}
 one_minute = 300
 
 # 定义DataLinkMsg元组，可爱地解包数据！
-DataLinkMsg = namedtuple("DataLinkMsg", ["latencies", "distance", "ts"])
+DataLinkMsg = namedtuple(
+    "DataLinkMsg",
+    ["latencies", "distance", "ts", "link", "hops", "norad_ids", "sim_time"],
+)
 Run = True
 first_ts = None
 
@@ -51,6 +54,9 @@ async def recv_data():
     while Run:
         [received_topic, msg] = await subscriber.recv_multipart()
         received_topic = received_topic.decode('utf-8')
+        # 命令的回执不是DataLinkMsg，跳过~
+        if received_topic == "ack":
+            continue
         data = DataLinkMsg._make(msgpack.unpackb(msg))
 
         # 把接收到的数据存储到全局字典里，让它们绽放光彩！
@@ -0,0 +1,32 @@
+# Sends a command to RustSat over the ZMQ command channel and prints the ack.
+#
+# RustSat connects its SUB socket to 5552 and publishes acks on 5551,
+# so this script binds both ends.
+import sys
+import time
+
+import msgpack
+import zmq
+
+context = zmq.Context()
+pub = context.socket(zmq.PUB)
+pub.bind("tcp://127.0.0.1:5552")
+sub = context.socket(zmq.SUB)
+sub.setsockopt_string(zmq.SUBSCRIBE, "ack")
+sub.bind("tcp://127.0.0.1:5551")
+
+commands = {
+    "snapshot": {"type": "request_snapshot", "satellites": False},
+    "add": {"type": "add_ground_station", "id": 2, "name": "Vancouver", "lat": 49.28, "lon": -123.12},
+    "link": {"type": "add_link", "name": "YVR-YYC", "from": 2, "to": 0},
+    "remove": {"type": "remove_ground_station", "id": 2},
+    "fast": {"type": "set_clock", "rate": 10.0},
+    "cgr": {"type": "set_routing_policy", "algorithm": "contact_graph"},
+}
+
+name = sys.argv[1] if len(sys.argv) > 1 else "snapshot"
+# give the subscriber time to connect
+time.sleep(1.0)
+pub.send_multipart([b"", msgpack.packb({"id": 1, "command": commands[name]})])
+_, ack = sub.recv_multipart()
+print(msgpack.unpackb(ack))
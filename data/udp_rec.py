@@ -0,0 +1,21 @@
+# Receives the fixed-rate UDP delay frames of RustSat (config section "udp").
+import socket
+import struct
+
+HEADER = struct.Struct("<IHHQdQ")
+LINK = struct.Struct("<IHHd")
+
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+sock.bind(("127.0.0.1", 5600))
+last = None
+while True:
+    data, _ = sock.recvfrom(65536)
+    magic, version, n, seq, sim_time, wall_ns = HEADER.unpack_from(data)
+    if magic != 0x54415352 or version != 1:
+        continue
+    if last is not None and seq != last + 1:
+        print(f"skipped {seq - last - 1} ticks")
+    last = seq
+    links = [LINK.unpack_from(data, HEADER.size + i * LINK.size) for i in range(n)]
+    if seq % 1000 == 0:
+        print(seq, sim_time, [(lid, hops, flags, delay * 1e3) for lid, hops, flags, delay in links])
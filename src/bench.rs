@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use sgp4::Elements;
+
+use crate::{
+    celestrak::{spawn_sat, update_lonlat, update_sat_pos, SimClock},
+    tle_provider::{parse_elements, TleFormat},
+};
+
+/// Template orbit of the synthetic constellation, a Starlink shell at 550 km.
+const TEMPLATE: &str = r#"[{
+    "OBJECT_NAME": "BENCH",
+    "OBJECT_ID": "2019-074A",
+    "EPOCH": "2024-01-01T00:00:00.000000",
+    "MEAN_MOTION": 15.06391,
+    "ECCENTRICITY": 0.0001,
+    "INCLINATION": 53.0,
+    "RA_OF_ASC_NODE": 0.0,
+    "ARG_OF_PERICENTER": 90.0,
+    "MEAN_ANOMALY": 0.0,
+    "EPHEMERIS_TYPE": 0,
+    "CLASSIFICATION_TYPE": "U",
+    "NORAD_CAT_ID": 100000,
+    "ELEMENT_SET_NO": 999,
+    "REV_AT_EPOCH": 1,
+    "BSTAR": 0.0001,
+    "MEAN_MOTION_DOT": 0.00001,
+    "MEAN_MOTION_DDOT": 0
+}]"#;
+
+/// `bench` section of the configuration file. With `satellites` set the app
+/// runs the propagation benchmark instead of opening a window.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BenchConfig {
+    /// constellation sizes to measure, e.g. `[10000, 50000]`
+    pub satellites: Vec<usize>,
+    /// measured frames per size
+    pub frames: usize,
+    /// frames run before measuring
+    pub warmup: usize,
+    /// simulation time between frames (s)
+    pub step: f64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            satellites: Vec::new(),
+            frames: 200,
+            warmup: 10,
+            step: 1.0,
+        }
+    }
+}
+
+/// Frame times of one constellation size.
+pub struct BenchResult {
+    pub satellites: usize,
+    pub frames: Vec<Duration>,
+}
+
+impl BenchResult {
+    pub fn mean(&self) -> Duration {
+        self.frames.iter().sum::<Duration>() / self.frames.len().max(1) as u32
+    }
+
+    /// Frame time below which `q` (0..1) of the frames are.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let mut sorted = self.frames.clone();
+        sorted.sort_unstable();
+        let i = ((sorted.len() as f64 * q).ceil() as usize).clamp(1, sorted.len().max(1)) - 1;
+        sorted.get(i).copied().unwrap_or_default()
+    }
+}
+
+/// A Walker constellation of `n` satellites with the template orbit, about
+/// as many planes as satellites per plane.
+pub fn constellation(n: usize) -> Vec<Elements> {
+    let template =
+        &parse_elements(TEMPLATE, TleFormat::Json, "bench template").expect("valid template")[0];
+    let planes = ((n as f64).sqrt().ceil() as usize).max(1);
+    let per_plane = n.div_ceil(planes);
+    (0..n)
+        .map(|i| {
+            let (plane, slot) = (i / per_plane, i % per_plane);
+            let mut elements = template.clone();
+            elements.norad_id = template.norad_id + i as u64;
+            elements.object_name = Some(format!("BENCH-{}", i));
+            elements.right_ascension = plane as f64 * 360.0 / planes as f64;
+            // phase offset between planes so the satellites do not line up
+            elements.mean_anomaly =
+                (slot as f64 * 360.0 / per_plane as f64 + plane as f64 * 360.0 / n as f64) % 360.0;
+            elements
+        })
+        .collect()
+}
+
+/// Runs the propagation systems headless on `n` synthetic satellites and
+/// times every frame.
+pub fn run_size(config: &BenchConfig, n: usize) -> BenchResult {
+    let sats = constellation(n);
+    let start = chrono::DateTime::from_naive_utc_and_offset(sats[0].datetime, chrono::Utc);
+    let mut clock = SimClock::default();
+    clock.set(start);
+
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    app.insert_resource(clock);
+    app.add_systems(Startup, move |mut cmd: Commands, clock: Res<SimClock>| {
+        for elements in &sats {
+            spawn_sat(&mut cmd, elements, &clock.now);
+        }
+    });
+    app.add_systems(Update, (update_sat_pos, update_lonlat).chain());
+
+    let step = chrono::Duration::microseconds((config.step * 1e6) as i64);
+    let mut frames = Vec::with_capacity(config.frames);
+    for frame in 0..config.warmup + config.frames {
+        app.world_mut()
+            .resource_mut::<SimClock>()
+            .set(start + step * (frame as i32 + 1));
+        let t = Instant::now();
+        app.update();
+        if frame >= config.warmup {
+            frames.push(t.elapsed());
+        }
+    }
+    BenchResult {
+        satellites: n,
+        frames,
+    }
+}
+
+/**
+Measures the frame time of the propagation (SGP4 and the geodetic
+coordinates) for every constellation size of the configuration and prints a
+table. The app is built with the minimal plugins only, so the numbers are the
+cost of the propagation systems without rendering.
+*/
+pub fn run(config: &BenchConfig) {
+    println!(
+        "{} frames after {} warmup, {} s steps, {} threads",
+        config.frames,
+        config.warmup,
+        config.step,
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>12}",
+        "satellites", "mean ms", "p95 ms", "max ms", "ns/satellite"
+    );
+    for &n in &config.satellites {
+        if n == 0 {
+            continue;
+        }
+        let result = run_size(config, n);
+        let ms = |d: Duration| d.as_secs_f64() * 1e3;
+        println!(
+            "{:>10} {:>10.3} {:>10.3} {:>10.3} {:>12.1}",
+            n,
+            ms(result.mean()),
+            ms(result.quantile(0.95)),
+            ms(result.quantile(1.0)),
+            result.mean().as_secs_f64() * 1e9 / n as f64
+        );
+    }
+}
@@ -2,10 +2,12 @@ use bevy::prelude::*;
 use tokio::runtime::Builder;
 
 use std::{
-    collections::HashMap,
-    fs::OpenOptions,
-    io::{Read, Write},
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
@@ -13,15 +15,103 @@ use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use sgp4::{Constants, Elements};
 
-/// Fetches online satellite data from the CelesTrak website in JSON format.
-///
-/// # Returns
-/// Returns a list of `sgp4::Elements` containing satellite orbital elements.
-pub(crate) async fn get_online_sat_data() -> Result<Vec<sgp4::Elements>, reqwest::Error> {
-    reqwest::get("https://celestrak.org/NORAD/elements/gp.php?GROUP=STARLINK&FORMAT=JSON")
-        .await?
-        .json::<Vec<sgp4::Elements>>()
-        .await
+use crate::tle_provider::{cache_file, Fetched, TleProvider, TleProviderConfig};
+
+/// Number of retries of a failed download.
+pub const FETCH_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled after every further attempt.
+pub const FETCH_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Errors of the TLE download and cache.
+#[derive(Debug)]
+pub enum TleError {
+    Http(reqwest::Error),
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    /// (source, message) element sets a provider could not decode
+    Format(String, String),
+    /// the cache or the response holds no element set
+    Empty,
+    /// neither the cache nor the download gave element sets
+    Unavailable(Box<TleError>),
+}
+
+impl fmt::Display for TleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TleError::Http(err) => write!(f, "TLE download failed: {}", err),
+            TleError::Io(path, err) => write!(f, "cannot access {:?}: {}", path, err),
+            TleError::Parse(path, err) => write!(f, "invalid TLE data in {:?}: {}", path, err),
+            TleError::Format(source, err) => write!(f, "invalid TLE data from {}: {}", source, err),
+            TleError::Empty => write!(f, "no element sets"),
+            TleError::Unavailable(err) => {
+                write!(
+                    f,
+                    "no TLE data available, starting without satellites ({})",
+                    err
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TleError {}
+
+impl From<reqwest::Error> for TleError {
+    fn from(err: reqwest::Error) -> Self {
+        TleError::Http(err)
+    }
+}
+
+/// Fetches the element sets from the provider, retrying failed HTTP requests
+/// with exponential backoff. Other errors do not go away by asking again.
+pub(crate) async fn get_online_sat_data(provider: &dyn TleProvider) -> Result<Fetched, TleError> {
+    let mut delay = FETCH_BACKOFF;
+    for _ in 0..FETCH_RETRIES {
+        match provider.fetch().await {
+            Err(TleError::Http(err)) => {
+                warn!("TLE download failed: {}, retrying in {:?}", err, delay);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    provider.fetch().await
+}
+
+/// Reads the TLE cache, a cache without element sets is an error.
+pub fn read_tle_cache(path: &Path) -> Result<Vec<Elements>, TleError> {
+    let file = File::open(path).map_err(|err| TleError::Io(path.into(), err))?;
+    let elements: Vec<Elements> = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| TleError::Parse(path.into(), err))?;
+    if elements.is_empty() {
+        return Err(TleError::Empty);
+    }
+    Ok(elements)
+}
+
+/// Writes the TLE cache to a temporary file next to it and renames it over
+/// the old cache, so readers never see a partial file.
+pub fn write_tle_cache(path: &Path, elements: &[Elements]) -> Result<(), TleError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let write = || -> std::io::Result<()> {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut w, elements)?;
+        w.into_inner().map_err(|err| err.into_error())?.sync_all()
+    };
+    write().map_err(|err| TleError::Io(tmp.clone(), err))?;
+    std::fs::rename(&tmp, path).map_err(|err| TleError::Io(path.into(), err))
+}
+
+/// Name of a satellite, its NORAD ID if the element set has none.
+pub fn sat_name(elements: &Elements) -> String {
+    elements
+        .object_name
+        .clone()
+        .unwrap_or_else(|| format!("NORAD {}", elements.norad_id))
 }
 
 #[derive(Component, Serialize, Deserialize)]
@@ -46,10 +136,15 @@ pub struct QueryConfig {
     pub timer: Timer,
 }
 
+#[derive(Resource, Clone)]
+/// Resource holding the provider the element sets are fetched from.
+pub struct TleSource(pub Arc<dyn TleProvider>);
+
 #[derive(Resource)]
 /// Resource holding configurations for the TLE (Two-Line Element) cache.
 pub struct TLECacheConfig {
-    pub file: PathBuf,
+    /// cache of the configured provider, `None` if it is not cached
+    pub file: Option<PathBuf>,
     pub cache: Option<Vec<Elements>>,
 }
 
@@ -73,6 +168,41 @@ pub struct LatLonAlt(pub (f64, f64, f64));
 /// Component holding a timestamp for the TLE data.
 pub struct TLETimeStamp(pub NaiveDateTime);
 
+#[derive(Default, Component, Clone, Copy, Debug)]
+/// Component holding the quality checks of a satellite's element set.
+pub struct TleQuality {
+    /// simulation time minus the TLE epoch (days)
+    pub age: f64,
+    /// perigee height of the mean orbit (km)
+    pub perigee: f64,
+    /// the epoch is too far from the simulation time
+    pub stale: bool,
+    /// the perigee or the drag term indicate a decaying orbit
+    pub decayed: bool,
+    /// SGP4 failed at the simulation time
+    pub diverged: bool,
+}
+
+impl TleQuality {
+    pub fn flagged(&self) -> bool {
+        self.stale || self.decayed || self.diverged
+    }
+
+    /// Short description of the flags, empty if there is none.
+    pub fn flags(&self) -> String {
+        [
+            (self.stale, "stale"),
+            (self.decayed, "decayed"),
+            (self.diverged, "diverged"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
 #[derive(Default, Serialize, Deserialize, Resource)]
 /// Resource to store satellite information, including orbital elements.
 pub struct SatInfo {
@@ -81,57 +211,182 @@ pub struct SatInfo {
 
 /// Retrieves the name of a satellite given its ID.
 pub fn get_name(data: &Res<SatInfo>, id: &&SatID) -> String {
-    data.sats
-        .get(&id.0)
-        .unwrap()
-        .object_name
-        .as_ref()
-        .unwrap()
-        .to_owned()
+    data.sats.get(&id.0).map(sat_name).unwrap_or_default()
 }
 
+#[derive(Event, Clone, Copy, Debug)]
+/// Event sent when a satellite new to the feed is spawned.
+pub struct SatAdded {
+    pub entity: Entity,
+    pub norad_id: u64,
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+/// Event sent when a satellite missing from the feed is despawned.
+pub struct SatRemoved {
+    pub entity: Entity,
+    pub norad_id: u64,
+}
+
+#[derive(Event, Clone, Copy, Debug, Default)]
+/// Event summarizing how a [`SatInfo`] change was applied to the satellites.
+pub struct SatInfoUpdated {
+    pub added: usize,
+    pub removed: usize,
+    /// satellites with an element set of another epoch
+    pub updated: usize,
+}
+
+#[derive(Resource, Default)]
+/// Resource holding the state of the TLE data, shown in the UI.
+pub struct TleStatus {
+    /// last download or cache problem, cleared by a successful update
+    pub warning: Option<String>,
+    /// time of the last successful download
+    pub updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Resource, Clone, Debug)]
+/// Resource holding the simulation time. It follows the wall clock scaled by
+/// `rate` from the last time it was set, and is sampled once per frame so all
+/// systems of a frame see the same instant.
+pub struct SimClock {
+    /// simulation time of the current frame
+    pub now: DateTime<Utc>,
+    /// simulation seconds per wall clock second
+    pub rate: f64,
+    pub paused: bool,
+    /// simulation and wall clock time of the last change
+    anchor: (DateTime<Utc>, DateTime<Utc>),
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        let now = Utc::now();
+        Self {
+            now,
+            rate: 1.0,
+            paused: false,
+            anchor: (now, now),
+        }
+    }
+}
+
+impl SimClock {
+    /// Jumps to `t`, the clock keeps running from there.
+    pub fn set(&mut self, t: DateTime<Utc>) {
+        self.anchor = (t, Utc::now());
+        self.now = t;
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.anchor = (self.now, Utc::now());
+        self.rate = rate;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.anchor = (self.now, Utc::now());
+        self.paused = paused;
+    }
+
+    fn tick(&mut self) {
+        let wall = Utc::now();
+        if self.paused {
+            self.anchor = (self.now, wall);
+            return;
+        }
+        let elapsed = (wall - self.anchor.1).num_microseconds().unwrap_or(0) as f64 * self.rate;
+        self.now = self.anchor.0 + chrono::Duration::microseconds(elapsed as i64);
+    }
+}
+
+pub fn tick_sim_clock(mut clock: ResMut<SimClock>) {
+    clock.tick();
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
+/// Systems propagating the satellites to the current simulation time.
+pub struct PropagationSet;
+
 #[derive(Resource)]
 /// Resource for a Tokio runtime that manages async tasks.
 pub struct Runtime(pub tokio::runtime::Runtime);
 
+/// Fetches the element sets on the runtime, a successful download also
+/// replaces the cache. [`receive_task`] applies the result.
+fn spawn_fetch(
+    cmd: &mut Commands,
+    rt: &Runtime,
+    provider: Arc<dyn TleProvider>,
+    path: Option<PathBuf>,
+) {
+    let task = rt.0.spawn(async move {
+        let fetched = get_online_sat_data(provider.as_ref()).await?;
+        if let (Fetched::Updated(elements), Some(path)) = (&fetched, &path) {
+            if let Err(err) = write_tle_cache(path, elements) {
+                error!("{}", err);
+            }
+        }
+        Ok::<_, TleError>(fetched)
+    });
+    cmd.spawn(TaskWrapper(Some(task)));
+}
+
 /// Spawns a new task to query satellite data every time the timer expires.
 fn update_data(
     mut cmd: Commands,
     rt: Res<Runtime>,
+    source: Res<TleSource>,
+    cache: Res<TLECacheConfig>,
     mut config: ResMut<QueryConfig>,
     time: Res<bevy::time::Time>,
 ) {
     config.timer.tick(time.delta());
     if config.timer.finished() {
-        let task = rt.0.spawn(async { get_online_sat_data().await });
-        cmd.spawn(TaskWrapper(Some(task)));
+        spawn_fetch(&mut cmd, &rt, source.0.clone(), cache.file.clone());
     }
 }
 
-/// Receives results from async tasks and updates the satellite information.
-fn receive_task(
+/// Receives results from finished async tasks and updates the satellite information.
+pub fn receive_task(
     mut cmd: Commands,
     rt: Res<Runtime>,
-    mut tasks: Query<(
-        Entity,
-        &mut TaskWrapper<Result<Vec<Elements>, reqwest::Error>>,
-    )>,
+    mut tasks: Query<(Entity, &mut TaskWrapper<Result<Fetched, TleError>>)>,
     mut sat: ResMut<SatInfo>,
+    mut status: ResMut<TleStatus>,
 ) {
     tasks.iter_mut().for_each(|(e, mut t)| {
+        if t.0.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
         if let Some(task) = t.0.take() {
-            let res = rt.0.block_on(task).unwrap();
-            match res {
-                Ok(res) => {
+            match rt.0.block_on(task) {
+                Ok(Ok(Fetched::NotModified)) => {
+                    status.warning = None;
+                    status.updated = Some(Utc::now());
+                    info!("TLE data not modified");
+                }
+                Ok(Ok(Fetched::Updated(res))) => {
                     let mut sat_info = SatInfo::default();
                     for elements in res {
                         sat_info.sats.insert(elements.norad_id, elements);
                     }
                     *sat = sat_info;
+                    status.warning = None;
+                    status.updated = Some(Utc::now());
                     info!("Message Received! {}", sat.sats.len());
                 }
-                Err(err) => {
+                Ok(Err(err)) => {
                     error!("Failed to update TLE! {}", err);
+                    status.warning = Some(if sat.sats.is_empty() {
+                        TleError::Unavailable(Box::new(err)).to_string()
+                    } else {
+                        err.to_string()
+                    });
+                }
+                Err(err) => {
+                    error!("TLE update task failed! {}", err);
+                    status.warning = Some(err.to_string());
                 }
             }
         }
@@ -140,22 +395,30 @@ fn receive_task(
 }
 
 /// Updates satellite positions based on the latest timestamp and constants.
-fn update_sat_pos(
+/// Satellites are propagated in parallel on the compute task pool.
+pub fn update_sat_pos(
+    clock: Res<SimClock>,
     mut sats: Query<(
         &TLETimeStamp,
         &SGP4Constants,
         &mut TEMEPos,
         &mut TEMEVelocity,
+        &mut TleQuality,
         &Name,
     )>,
 ) {
-    sats.iter_mut()
-        .for_each(|(ts, constants, mut pos, mut vel, n)| {
-            if let Ok((p, v)) = propagate_sat(&ts.0, &constants.0) {
+    let now = clock.now;
+    sats.par_iter_mut()
+        .for_each(|(ts, constants, mut pos, mut vel, mut quality, n)| {
+            if let Ok((p, v)) = propagate_sat_at(&ts.0, &constants.0, &now) {
                 *pos = p;
                 *vel = v;
-            } else {
+                if quality.diverged {
+                    quality.diverged = false;
+                }
+            } else if !quality.diverged {
                 error!("{} diverged", n.as_str());
+                quality.diverged = true;
             }
         });
 }
@@ -177,116 +440,224 @@ fn ecef_to_wgs84(x: f64, y: f64, z: f64) -> (f64, f64, f64) {
     (latitude, longitude, altitude)
 }
 
-/// Updates the geographic coordinates (latitude, longitude, altitude) for each satellite.
-fn update_lonlat(mut cmd: Commands, sats: Query<(Entity, &TEMEPos), Changed<TEMEPos>>) {
-    let datetime: DateTime<Utc> = Utc::now();
-    sats.iter().for_each(|(e, pos)| {
-        let (x, y, z) = map_3d::eci2ecef(
-            map_3d::utc2gst([
-                datetime.year() as i32,
-                datetime.month() as i32,
-                datetime.day() as i32,
-                datetime.hour() as i32,
-                datetime.minute() as i32,
-                datetime.second() as i32,
-            ]),
-            pos.0[0] * 1000.0,
-            pos.0[1] * 1000.0,
-            pos.0[2] * 1000.0,
-        );
-        let (x, y, z) = ecef_to_wgs84(x, y, z);
-        //let (x, y, z) = map_3d::ecef2geodetic(x, y, z, map_3d::Ellipsoid::WGS84);
-        let res = (map_3d::rad2deg(x), map_3d::rad2deg(y), z / 1000.0);
-        cmd.entity(e).insert(LatLonAlt(res));
+/// Greenwich sidereal time (rad) of a UTC time, to the second.
+pub fn gst(datetime: &DateTime<Utc>) -> f64 {
+    map_3d::utc2gst([
+        datetime.year() as i32,
+        datetime.month() as i32,
+        datetime.day() as i32,
+        datetime.hour() as i32,
+        datetime.minute() as i32,
+        datetime.second() as i32,
+    ])
+}
+
+/// Converts a TEME position (km) to geodetic coordinates (latitude deg,
+/// longitude deg, altitude km) with the sidereal time from [`gst`].
+pub fn teme_to_lla_gst(pos: &[f64; 3], gst: f64) -> (f64, f64, f64) {
+    let (x, y, z) = map_3d::eci2ecef(gst, pos[0] * 1000.0, pos[1] * 1000.0, pos[2] * 1000.0);
+    let (x, y, z) = ecef_to_wgs84(x, y, z);
+    //let (x, y, z) = map_3d::ecef2geodetic(x, y, z, map_3d::Ellipsoid::WGS84);
+    (map_3d::rad2deg(x), map_3d::rad2deg(y), z / 1000.0)
+}
+
+/// Converts a TEME position (km) at the given UTC time to geodetic coordinates
+/// (latitude deg, longitude deg, altitude km).
+pub fn teme_to_lla(pos: &[f64; 3], datetime: &DateTime<Utc>) -> (f64, f64, f64) {
+    teme_to_lla_gst(pos, gst(datetime))
+}
+
+/// Updates the geographic coordinates (latitude, longitude, altitude) for each
+/// satellite in place. The sidereal time is computed once per frame.
+pub fn update_lonlat(
+    clock: Res<SimClock>,
+    mut sats: Query<(&TEMEPos, &mut LatLonAlt), Changed<TEMEPos>>,
+) {
+    let gst = gst(&clock.now);
+    sats.par_iter_mut().for_each(|(pos, mut lla)| {
+        lla.0 = teme_to_lla_gst(&pos.0, gst);
     });
 }
 
-fn update_every_sat(mut cmd: Commands, satdata: Res<SatInfo>, sats: Query<(Entity, &SatID)>) {
-    if satdata.is_changed() {
-        sats.iter().for_each(|(e, id)| {
-            if !satdata.sats.contains_key(&id.0) {
-                cmd.entity(e).despawn_recursive();
-            } else {
-                let s = satdata.sats.get(&id.0).unwrap();
-                let constants = sgp4::Constants::from_elements(s).unwrap();
-                cmd.entity(e).insert(SGP4Constants(constants));
-                cmd.entity(e).insert(TLETimeStamp(s.datetime));
-                cmd.entity(e).insert(Name::from(get_name(&satdata, &id)));
+/// Spawns a satellite propagated to `now`, `None` if its elements are invalid.
+/// Every satellite gets its position components here, zeroed if it diverged
+/// at `now`, so the per frame update only writes to existing components and
+/// clears [`TleQuality::diverged`] once the propagation succeeds again.
+pub fn spawn_sat(cmd: &mut Commands, elements: &Elements, now: &DateTime<Utc>) -> Option<Entity> {
+    let id = SatID(elements.norad_id);
+    let constants = match sgp4::Constants::from_elements(elements) {
+        Ok(constants) => constants,
+        Err(err) => {
+            error!("invalid elements of {}: {}", sat_name(elements), err);
+            return None;
+        }
+    };
+    let ts = TLETimeStamp(elements.datetime);
+    let (pos, vel, lla, quality) = match propagate_sat_at(&ts.0, &constants, now) {
+        Ok((pos, vel)) => {
+            let lla = LatLonAlt(teme_to_lla(&pos.0, now));
+            (pos, vel, lla, TleQuality::default())
+        }
+        Err(_) => {
+            error!("{} diverged", sat_name(elements));
+            let quality = TleQuality {
+                diverged: true,
+                ..default()
+            };
+            (
+                TEMEPos::default(),
+                TEMEVelocity::default(),
+                LatLonAlt((0.0, 0.0, 0.0)),
+                quality,
+            )
+        }
+    };
+    let entity = cmd
+        .spawn((
+            id,
+            SGP4Constants(constants),
+            ts,
+            pos,
+            vel,
+            lla,
+            quality,
+            Name::from(sat_name(elements)),
+        ))
+        .id();
+    Some(entity)
+}
+
+/// Applies a changed [`SatInfo`] to the satellites: new NORAD IDs are
+/// spawned, missing ones despawned, and only satellites whose element set has
+/// another epoch get new SGP4 constants.
+pub fn update_every_sat(
+    mut cmd: Commands,
+    satdata: Res<SatInfo>,
+    clock: Res<SimClock>,
+    mut sats: Query<(
+        Entity,
+        &SatID,
+        &mut SGP4Constants,
+        &mut TLETimeStamp,
+        &mut Name,
+    )>,
+    mut added: EventWriter<SatAdded>,
+    mut removed: EventWriter<SatRemoved>,
+    mut summary: EventWriter<SatInfoUpdated>,
+) {
+    if !satdata.is_changed() {
+        return;
+    }
+    let mut update = SatInfoUpdated::default();
+    let mut present = HashSet::new();
+    for (e, id, mut constants, mut ts, mut name) in sats.iter_mut() {
+        let Some(elements) = satdata.sats.get(&id.0) else {
+            cmd.entity(e).despawn_recursive();
+            removed.send(SatRemoved {
+                entity: e,
+                norad_id: id.0,
+            });
+            update.removed += 1;
+            continue;
+        };
+        present.insert(id.0);
+        if elements.datetime == ts.0 {
+            continue;
+        }
+        match sgp4::Constants::from_elements(elements) {
+            Ok(c) => {
+                constants.0 = c;
+                ts.0 = elements.datetime;
+                let n = sat_name(elements);
+                if name.as_str() != n {
+                    *name = Name::from(n);
+                }
+                update.updated += 1;
+            }
+            Err(err) => error!("invalid elements of {}: {}", sat_name(elements), err),
+        }
+    }
+    // spawn in NORAD ID order so entities and query order do not depend on the hash map
+    let mut new_ids: Vec<u64> = satdata
+        .sats
+        .keys()
+        .filter(|id| !present.contains(*id))
+        .copied()
+        .collect();
+    new_ids.sort_unstable();
+    for id in new_ids {
+        if let Some(entity) = spawn_sat(&mut cmd, &satdata.sats[&id], &clock.now) {
+            added.send(SatAdded {
+                entity,
+                norad_id: id,
+            });
+            update.added += 1;
+        }
+    }
+    if update.added + update.removed + update.updated > 0 {
+        info!(
+            "satellites: {} added, {} removed, {} updated",
+            update.added, update.removed, update.updated
+        );
+    }
+    summary.send(update);
+}
+
+/// Reads the TLE cache file, `None` if it is missing or invalid. The flag
+/// tells whether the cache is recent enough to skip the download.
+fn load_tle_cache(path: &Path, timer: &QueryConfig) -> Option<(Vec<Elements>, bool)> {
+    match read_tle_cache(path) {
+        Ok(elements) => {
+            // the newest element set tells when the cache was written
+            let newest = elements
+                .iter()
+                .map(|e| e.datetime)
+                .max()
+                .unwrap_or_default();
+            let age = Utc::now().naive_utc() - newest;
+            // an epoch in the future counts as fresh
+            let fresh = age
+                .to_std()
+                .map_or(true, |age| age <= 7 * timer.timer.duration());
+            if !fresh {
+                info!("TLE cache {:?} is {} days old", path, age.num_days());
             }
-        });
+            Some((elements, fresh))
+        }
+        Err(err) => {
+            warn!("{}", err);
+            None
+        }
     }
 }
 
+/// Spawns the satellites of the cache. If the cache is missing or too old
+/// the element sets are fetched in the background, so the app starts right
+/// away with the outdated cache or without satellites.
 pub fn init_sat_data(
     mut cmd: Commands,
     mut cache: ResMut<TLECacheConfig>,
     timer: Res<QueryConfig>,
+    clock: Res<SimClock>,
     rt: Res<Runtime>,
+    source: Res<TleSource>,
 ) {
-    //attempt read from file
-    let file = std::fs::File::open(cache.file.clone());
-    let mut tle = None;
-    let read_online = match file {
-        Ok(f) => {
-            let j = serde_json::from_reader::<_, Vec<Elements>>(f);
-
-            match j {
-                Ok(j) => {
-                    let sampled_time = j[0].datetime;
-                    let t = Utc::now().naive_utc();
-                    let dt = t - sampled_time;
-                    let d = timer.timer.duration();
-
-                    if dt.to_std().unwrap() > 7 * d {
-                        true
-                    } else {
-                        tle = Some(j);
-                        false
-                    }
-                }
-                Err(err) => {
-                    error!("cannot read {:?}: {}!", cache.file, err);
-                    true
-                }
-            }
-        }
-        Err(err) => {
-            error!("cannot open {:?}: {}!", cache.file, err);
-            true
-        }
-    };
-    println!("j:{:?}", read_online);
-    if read_online {
-        //read from online
-        let data = rt.0.block_on(async { get_online_sat_data().await });
-        tle = match data {
-            Ok(data) => Some(data),
-            Err(err) => {
-                error!("cannot read tle from online {}!", err);
-                tle
-            }
-        };
-        if tle.is_some() {
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(cache.file.clone())
-                .unwrap();
-            file.write(
-                serde_json::to_string(tle.as_ref().unwrap())
-                    .unwrap()
-                    .as_bytes(),
-            )
-            .unwrap();
+    // a preset cache, e.g. from a recorded session, is used as is
+    if cache.cache.is_none() {
+        let loaded = cache
+            .file
+            .as_deref()
+            .and_then(|path| load_tle_cache(path, &timer));
+        let fresh = loaded.as_ref().is_some_and(|(_, fresh)| *fresh);
+        cache.cache = loaded.map(|(elements, _)| elements);
+        if !fresh {
+            info!("loading element sets from {}", source.0.name());
+            spawn_fetch(&mut cmd, &rt, source.0.clone(), cache.file.clone());
         }
     }
-    cache.cache = tle;
-    let s = cache.cache.as_ref().unwrap();
-    let js: Vec<Elements> = serde_json::from_value(serde_json::to_value(s).unwrap()).unwrap();
     let mut sat_info = SatInfo::default();
-    for elements in js {
-        sat_info.sats.insert(elements.norad_id, elements);
+    for elements in cache.cache.iter().flatten() {
+        sat_info.sats.insert(elements.norad_id, elements.clone());
     }
     // for elements in s {
     //     if elements.object_name.as_ref().unwrap().contains(&"STARLINK") {
@@ -294,43 +665,26 @@ pub fn init_sat_data(
     //     }
     // }
 
-    for (_k, elements) in &sat_info.sats {
-        let id = SatID(elements.norad_id);
-
-        let constants = sgp4::Constants::from_elements(elements).unwrap();
-
-        let ts = TLETimeStamp(elements.datetime);
-        if let Ok((pos, vel)) = propagate_sat(&ts.0, &constants) {
-            cmd.spawn((
-                id,
-                SGP4Constants(constants),
-                ts,
-                pos,
-                vel,
-                Name::from(elements.object_name.as_ref().unwrap().clone()),
-            ));
-        } else {
-            error!("{} diverged", elements.object_name.as_ref().unwrap());
-            cmd.spawn((
-                id,
-                SGP4Constants(constants),
-                ts,
-                Name::from(elements.object_name.as_ref().unwrap().clone()),
-            ));
-        }
+    // spawn in NORAD ID order so entities and query order do not depend on the hash map
+    let mut norad_ids: Vec<u64> = sat_info.sats.keys().copied().collect();
+    norad_ids.sort_unstable();
+    for id in norad_ids {
+        spawn_sat(&mut cmd, &sat_info.sats[&id], &clock.now);
     }
     cmd.insert_resource(sat_info);
 }
 
-fn propagate_sat(
+/// Propagates a satellite to an arbitrary UTC time.
+///
+/// Times before the TLE epoch are allowed, SGP4 propagates backwards as well.
+pub fn propagate_sat_at(
     init_ts: &NaiveDateTime,
     constants: &Constants,
+    t: &DateTime<Utc>,
 ) -> Result<(TEMEPos, TEMEVelocity), ()> {
-    let ts = chrono::Utc::now();
-    let ts = ts.naive_utc() - *init_ts;
-    if let Ok(prediction) = constants.propagate(sgp4::MinutesSinceEpoch(
-        ts.to_std().unwrap().as_secs_f64() / 60.0,
-    )) {
+    let ts = t.naive_utc() - *init_ts;
+    let minutes = ts.num_microseconds().unwrap_or(i64::MAX) as f64 / 60.0e6;
+    if let Ok(prediction) = constants.propagate(sgp4::MinutesSinceEpoch(minutes)) {
         let (pos, vel) = (
             TEMEPos(prediction.position),
             TEMEVelocity(prediction.velocity),
@@ -342,11 +696,19 @@ fn propagate_sat(
     }
 }
 
+/// Propagates the satellites. The element sets come from `provider` if set,
+/// otherwise from the provider built from `config`.
 #[derive(Default)]
-pub struct SGP4Plugin;
+pub struct SGP4Plugin {
+    pub config: TleProviderConfig,
+    pub provider: Option<Arc<dyn TleProvider>>,
+}
 
 impl Plugin for SGP4Plugin {
     fn build(&self, app: &mut App) {
+        let provider = self.provider.clone().unwrap_or_else(|| self.config.build());
+        let file = cache_file(provider.as_ref());
+        app.insert_resource(TleSource(provider));
         let rt = Runtime(
             Builder::new_multi_thread()
                 .enable_all()
@@ -354,21 +716,30 @@ impl Plugin for SGP4Plugin {
                 .build()
                 .unwrap(),
         );
-        app.insert_resource(TLECacheConfig {
-            file: "./tle.json".into(),
-            cache: None,
-        });
+        app.insert_resource(TLECacheConfig { file, cache: None });
         app.insert_resource(QueryConfig {
             timer: Timer::new(Duration::from_secs(60 * 24 * 24), TimerMode::Repeating),
         });
         app.insert_resource(rt);
         app.insert_resource(SatInfo::default());
+        app.init_resource::<TleStatus>();
+        app.add_event::<SatAdded>();
+        app.add_event::<SatRemoved>();
+        app.add_event::<SatInfoUpdated>();
+        app.init_resource::<SimClock>();
+        app.add_systems(First, tick_sim_clock);
         app.add_systems(Startup, init_sat_data);
         app.add_systems(PreUpdate, update_data);
         app.add_systems(
             Update,
-            (receive_task, update_every_sat, update_sat_pos).chain(),
+            (
+                receive_task,
+                update_every_sat,
+                update_sat_pos,
+                update_lonlat,
+            )
+                .chain()
+                .in_set(PropagationSet),
         );
-        app.add_systems(Update, update_lonlat);
     }
 }
@@ -3,16 +3,23 @@ use bevy_egui::{
     egui::{self, Ui},
     EguiContexts, EguiPlugin, EguiSet,
 };
-use rfd::{AsyncFileDialog, FileHandle};
+use rfd::AsyncFileDialog;
 
-use std::{
-    collections::HashMap,
-    env,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::HashMap, env};
 use tokio::sync::oneshot::{self, error::TryRecvError};
 
-use crate::groundstation::GSConfigs;
+use crate::contact_plan::{generate_contact_plan, ContactPlanConfig};
+use crate::datalink::{DataLinkHistory, Failover, KShortestPaths};
+use crate::export::{
+    csv::{CsvExport, CsvField, UnitStyle},
+    czml::CzmlExport,
+    geo::{GeoExport, GeoFormat},
+    oem::{OemExport, OemFormat, OemFrame, OemObject},
+};
+use crate::groundstation::{Antennas, GSConfigs, GroundStationID};
+use crate::handover::{Handover, HandoverConfig, HandoverPolicyKind, HandoverStats};
+use crate::routing::{compare_algorithms, ComparisonReport, Router, RoutingKind};
+use crate::topology::{ScenarioSat, ScenarioSource, TopologyConfig};
 use crate::*;
 
 /// Stores the current cursor position as a Vec2.
@@ -27,9 +34,42 @@ pub struct QueriedEvent;
 #[derive(Default, Resource)]
 pub struct SatConfigs {
     pub sat_color: Color,
-    pub table_data: Vec<[String; 7]>,
+    pub table_data: Vec<[String; 8]>,
     pub visible: Vec<Entity>,
-    pub rx: Option<oneshot::Receiver<Option<FileHandle>>>,
+    pub export: CsvExport,
+    pub oem: OemExport,
+}
+
+/// State of the routing comparison started from the UI.
+#[derive(Resource)]
+pub struct RoutingComparison {
+    pub steps: usize,
+    /// time between two snapshots (s)
+    pub step: f64,
+    pub rx: Option<oneshot::Receiver<ComparisonReport>>,
+    pub report: Option<ComparisonReport>,
+    pub contact_plan: ContactPlanConfig,
+}
+
+impl Default for RoutingComparison {
+    fn default() -> Self {
+        Self {
+            steps: 20,
+            step: 30.0,
+            rx: None,
+            report: None,
+            contact_plan: ContactPlanConfig::default(),
+        }
+    }
+}
+
+/// Settings of the exports in the Export window.
+#[derive(Default, Resource)]
+pub struct ExportSettings {
+    pub czml: CzmlExport,
+    pub geo: GeoExport,
+    #[cfg(feature = "columnar")]
+    pub columnar: crate::export::columnar::ColumnarExport,
 }
 
 /// UI-related data stored in JSON format.
@@ -58,6 +98,8 @@ pub fn show_data(
     c: Res<CursorPosition>,
     mut cam: Query<(&mut OrthographicProjection, &mut Transform)>,
     rt: Res<celestrak::Runtime>,
+    clock: Res<celestrak::SimClock>,
+    satinfo: Res<SatInfo>,
     sats: Query<(
         Entity,
         &SGP4Constants,
@@ -66,8 +108,10 @@ pub fn show_data(
         &TEMEVelocity,
         &LatLonAlt,
         &Name,
+        &TleQuality,
     )>,
     mut vis: Query<&mut Visibility, With<SatID>>,
+    mut handover: Query<(&Name, &mut Handover, &mut Antennas, &HandoverStats), With<GroundStationID>>,
 ) {
     show_menu(&mut egui_context, &mut uidata, &mut cam);
     show_config_ui(
@@ -76,6 +120,7 @@ pub fn show_data(
         &mut gscfg,
         &mut cccfg,
         &mut uidata,
+        &mut handover,
     );
     show_satellite_data(
         &mut egui_context,
@@ -86,6 +131,8 @@ pub fn show_data(
         &mut vis,
         &mut query,
         &rt,
+        &clock,
+        &satinfo,
     );
 }
 
@@ -96,6 +143,7 @@ fn show_config_ui(
     gscfg: &mut ResMut<GSConfigs>,
     cccfg: &mut ResMut<ClearColor>,
     uidata: &mut ResMut<UIData>,
+    handover: &mut Query<(&Name, &mut Handover, &mut Antennas, &HandoverStats), With<GroundStationID>>,
 ) {
     let mut opened = uidata
         .0
@@ -103,7 +151,7 @@ fn show_config_ui(
         .unwrap_or(&false.into())
         .as_bool()
         .unwrap();
-    config_ui(egui_context, satcfg, gscfg, cccfg, &mut opened);
+    config_ui(egui_context, satcfg, gscfg, cccfg, handover, &mut opened);
     uidata.0["Config"] = opened.into();
 }
 
@@ -127,6 +175,21 @@ fn show_menu(
                 if satellite_data_open {
                     uidata.0["Satellite Data"] = satellite_data_open.into();
                 }
+                let link_latency_open = ui
+                    .menu_button("Link Latency", |_ui| {})
+                    .response
+                    .clicked();
+                if link_latency_open {
+                    uidata.0["Link Latency"] = link_latency_open.into();
+                }
+                let routing_open = ui.menu_button("Routing", |_ui| {}).response.clicked();
+                if routing_open {
+                    uidata.0["Routing"] = routing_open.into();
+                }
+                let export_open = ui.menu_button("Export", |_ui| {}).response.clicked();
+                if export_open {
+                    uidata.0["Export"] = export_open.into();
+                }
 
                 ui.menu_button("view", |ui| {
                     if ui.button("reset zoom").clicked() {
@@ -143,6 +206,493 @@ fn show_menu(
     });
 }
 
+/// Plots the latency history of a data link as time series, histogram or CDF.
+pub fn show_link_history(
+    mut egui_context: EguiContexts,
+    mut uidata: ResMut<UIData>,
+    links: Query<(
+        Entity,
+        &Name,
+        &DataLinkHistory,
+        Option<&KShortestPaths>,
+        Option<&Failover>,
+    )>,
+) {
+    use egui_plot::{Bar, BarChart, Legend, Line, Plot, PlotPoints};
+
+    let mut opened = uidata
+        .0
+        .get("Link Latency")
+        .unwrap_or(&false.into())
+        .as_bool()
+        .unwrap();
+    if !opened {
+        return;
+    }
+    let mut selected = uidata
+        .0
+        .get("link_plot_entity")
+        .and_then(|v| v.as_u64())
+        .map(Entity::from_bits)
+        .filter(|e| links.contains(*e))
+        .or_else(|| links.iter().next().map(|(e, ..)| e));
+    let mut view = uidata
+        .0
+        .get("link_plot_view")
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or("Latency".into());
+
+    egui::Window::new("Link Latency")
+        .open(&mut opened)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let text = selected
+                    .and_then(|e| links.get(e).ok())
+                    .map(|(_, n, ..)| n.to_string())
+                    .unwrap_or_default();
+                egui::ComboBox::from_label("Link")
+                    .selected_text(text)
+                    .show_ui(ui, |ui| {
+                        for (e, n, ..) in links.iter() {
+                            ui.selectable_value(&mut selected, Some(e), n.as_str());
+                        }
+                    });
+                for v in ["Latency", "Histogram", "CDF"] {
+                    ui.selectable_value(&mut view, v.to_string(), v);
+                }
+            });
+            let Some((_, _, history, k_paths, failover)) = selected.and_then(|e| links.get(e).ok())
+            else {
+                ui.label("no data link history");
+                return;
+            };
+            if let Some(k_paths) = k_paths {
+                let paths: Vec<_> = k_paths
+                    .0
+                    .iter()
+                    .map(|p| format!("{:.3} ms/{} hops", 1e3 * p.latency(), p.nodes.len() - 1))
+                    .collect();
+                ui.label(format!("k shortest paths: {}", paths.join(", ")));
+            }
+            if let Some(f) = failover {
+                ui.label(format!(
+                    "last failover: {:.3} ms (detection {:.3} ms, backup path {:.3} ms)",
+                    1e3 * f.latency,
+                    1e3 * f.detection,
+                    1e3 * f.backup_latency
+                ));
+            }
+            if let Some(s) = history.summary() {
+                ui.label(format!(
+                    "samples: {} min: {:.3} ms mean: {:.3} ms p95: {:.3} ms p99: {:.3} ms max: {:.3} ms",
+                    s.count,
+                    1e3 * s.min,
+                    1e3 * s.mean,
+                    1e3 * s.p95,
+                    1e3 * s.p99,
+                    1e3 * s.max
+                ));
+            }
+            let t0 = history.samples.front().map_or(0.0, |s| s.ts);
+
+            match view.as_str() {
+                "Histogram" => {
+                    let v: Vec<f64> = history.samples.iter().map(|s| 1e3 * s.latency as f64).collect();
+                    let (lo, hi) = v
+                        .iter()
+                        .fold((f64::MAX, f64::MIN), |(lo, hi), x| (lo.min(*x), hi.max(*x)));
+                    const BINS: usize = 50;
+                    let width = ((hi - lo) / BINS as f64).max(1e-6);
+                    let mut counts = [0usize; BINS];
+                    v.iter().for_each(|x| {
+                        counts[(((x - lo) / width) as usize).min(BINS - 1)] += 1;
+                    });
+                    let bars = counts
+                        .iter()
+                        .enumerate()
+                        .map(|(i, c)| Bar::new(lo + (i as f64 + 0.5) * width, *c as f64).width(width))
+                        .collect();
+                    Plot::new("link_latency_histogram")
+                        .x_axis_label("latency (ms)")
+                        .y_axis_label("samples")
+                        .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars)));
+                }
+                "CDF" => {
+                    let mut v: Vec<f64> = history.samples.iter().map(|s| 1e3 * s.latency as f64).collect();
+                    v.sort_by(|a, b| a.total_cmp(b));
+                    let n = v.len() as f64;
+                    let points: PlotPoints = v
+                        .iter()
+                        .enumerate()
+                        .map(|(i, x)| [*x, (i + 1) as f64 / n])
+                        .collect();
+                    Plot::new("link_latency_cdf")
+                        .x_axis_label("latency (ms)")
+                        .y_axis_label("P(X <= x)")
+                        .show(ui, |plot_ui| plot_ui.line(Line::new(points)));
+                }
+                _ => {
+                    let hops = history.samples.iter().map(|s| s.hops).max().unwrap_or(0);
+                    Plot::new("link_latency_series")
+                        .legend(Legend::default())
+                        .x_axis_label("time (s)")
+                        .y_axis_label("latency (ms)")
+                        .show(ui, |plot_ui| {
+                            let total: PlotPoints = history
+                                .samples
+                                .iter()
+                                .map(|s| [s.ts - t0, 1e3 * s.latency as f64])
+                                .collect();
+                            plot_ui.line(Line::new(total).name("total"));
+                            for hop in 0..hops {
+                                let points: PlotPoints = history
+                                    .samples
+                                    .iter()
+                                    .filter_map(|s| {
+                                        s.hop_latencies
+                                            .get(hop)
+                                            .map(|l| [s.ts - t0, 1e3 * *l as f64])
+                                    })
+                                    .collect();
+                                plot_ui.line(Line::new(points).name(format!("hop {}", hop + 1)));
+                            }
+                            let count: PlotPoints = history
+                                .samples
+                                .iter()
+                                .map(|s| [s.ts - t0, s.hops as f64])
+                                .collect();
+                            plot_ui.line(Line::new(count).name("hop count"));
+                        });
+                }
+            }
+        });
+    uidata.0["Link Latency"] = opened.into();
+    if let Some(e) = selected {
+        uidata.0["link_plot_entity"] = e.to_bits().into();
+    }
+    uidata.0["link_plot_view"] = view.into();
+}
+
+/// Selects the routing algorithm of the data links and runs batch comparisons.
+pub fn show_routing(
+    mut egui_context: EguiContexts,
+    mut uidata: ResMut<UIData>,
+    mut router: ResMut<Router>,
+    mut comparison: ResMut<RoutingComparison>,
+    rt: Res<celestrak::Runtime>,
+    topology: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    clock: Res<celestrak::SimClock>,
+    source: ScenarioSource,
+) {
+    if let Some(mut rx) = comparison.rx.take() {
+        match rx.try_recv() {
+            Ok(report) => comparison.report = Some(report),
+            Err(TryRecvError::Empty) => comparison.rx = Some(rx),
+            Err(_) => error!("routing comparison failed"),
+        }
+    }
+    let mut opened = uidata
+        .0
+        .get("Routing")
+        .unwrap_or(&false.into())
+        .as_bool()
+        .unwrap();
+    if !opened {
+        return;
+    }
+    egui::Window::new("Routing")
+        .open(&mut opened)
+        .show(egui_context.ctx_mut(), |ui| {
+            let mut kind = router.kind;
+            egui::ComboBox::from_label("Algorithm")
+                .selected_text(router.algorithm.name())
+                .show_ui(ui, |ui| {
+                    for k in RoutingKind::ALL {
+                        ui.selectable_value(&mut kind, k, k.build().name());
+                    }
+                });
+            if kind != router.kind {
+                *router = Router::new(kind);
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("steps:");
+                ui.add(egui::DragValue::new(&mut comparison.steps).range(1..=1000));
+                ui.label("step (s):");
+                ui.add(egui::DragValue::new(&mut comparison.step).range(1.0..=3600.0));
+            });
+            ui.add_enabled_ui(comparison.rx.is_none(), |ui| {
+                if ui.button("run comparison").clicked() {
+                    let (tx, rx) = oneshot::channel();
+                    comparison.rx = Some(rx);
+                    let scenario = source.build();
+                    let (steps, step) = (comparison.steps, comparison.step);
+                    let topology = topology.clone();
+                    let min_elevation = handover.min_elevation;
+                    let start = clock.now;
+                    rt.0.spawn_blocking(move || {
+                        let report = compare_algorithms(
+                            &scenario,
+                            &RoutingKind::ALL,
+                            start,
+                            steps,
+                            step,
+                            &topology,
+                            min_elevation,
+                        );
+                        let _ = tx.send(report);
+                    });
+                }
+            });
+            if comparison.rx.is_some() {
+                ui.spinner();
+            }
+            if let Some(report) = &comparison.report {
+                let mut table = Vec::new();
+                let _ = report.write_summary(&mut table);
+                ui.label(egui::RichText::new(String::from_utf8_lossy(&table)).monospace());
+                if ui.button("save report").clicked() {
+                    let stem = env::current_dir().unwrap().join("routing_comparison");
+                    match report.save(&stem) {
+                        Ok(_) => info!("routing comparison saved to {:?}", stem),
+                        Err(err) => error!("cannot save routing comparison: {}", err),
+                    }
+                }
+            }
+
+            ui.collapsing("Contact Plan", |ui| {
+                let cfg = &mut comparison.contact_plan;
+                ui.horizontal(|ui| {
+                    ui.label("duration (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.duration).range(1.0..=7.0 * 86400.0));
+                    ui.label("step (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.step).range(1.0..=3600.0));
+                    ui.label("elevation mask (deg):");
+                    ui.add(egui::DragValue::new(&mut cfg.min_elevation).range(0.0..=90.0));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("satellite filter:");
+                    ui.text_edit_singleline(&mut cfg.sat_filter);
+                    ui.checkbox(&mut cfg.include_isl, "inter-satellite contacts");
+                });
+                if ui.button("export contact plan").clicked() {
+                    let mut cfg = cfg.clone();
+                    cfg.start = clock.now;
+                    cfg.max_isl_range = topology.max_isl_range;
+                    cfg.isl_clearance = topology.isl_clearance;
+                    let scenario = source.build();
+                    rt.0.spawn(async move {
+                        let file = AsyncFileDialog::new()
+                            .add_filter("ION contact plan", &["txt"])
+                            .set_directory(env::current_dir().unwrap().as_path())
+                            .save_file()
+                            .await;
+                        let Some(file) = file else {
+                            return;
+                        };
+                        let stem = file.path().to_path_buf();
+                        let res = tokio::task::spawn_blocking(move || {
+                            generate_contact_plan(&scenario, &cfg).save(&stem)
+                        })
+                        .await;
+                        match res {
+                            Ok(Ok(_)) => info!("contact plan saved to {:?}", file.path()),
+                            Ok(Err(err)) => error!("cannot save contact plan: {}", err),
+                            Err(err) => error!("contact plan generation failed: {}", err),
+                        }
+                    });
+                }
+            });
+        });
+    uidata.0["Routing"] = opened.into();
+}
+
+/// Warning bar shown while the TLE data could not be loaded or updated.
+pub fn show_tle_status(
+    mut egui_context: EguiContexts,
+    status: Res<TleStatus>,
+    satinfo: Res<SatInfo>,
+    mut query: ResMut<QueryConfig>,
+) {
+    let Some(warning) = &status.warning else {
+        return;
+    };
+    egui::TopBottomPanel::bottom("TLE Status").show(egui_context.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.colored_label(egui::Color32::YELLOW, format!("⚠ {}", warning));
+            match status.updated {
+                Some(t) => ui.label(format!("last update {}", t.format("%Y-%m-%d %H:%M"))),
+                None => ui.label(format!("{} satellites from the cache", satinfo.sats.len())),
+            };
+            if ui.button("retry").clicked() {
+                let duration = query.timer.duration();
+                query.timer.set_elapsed(duration);
+            }
+        });
+    });
+}
+
+/// Asks for a file with the given extension and runs `save` on a blocking thread.
+fn save_with_dialog<F>(
+    rt: &Runtime,
+    what: &'static str,
+    extensions: &'static [&'static str],
+    save: F,
+) where
+    F: FnOnce(&std::path::Path) -> std::io::Result<()> + Send + 'static,
+{
+    rt.0.spawn(async move {
+        let file = AsyncFileDialog::new()
+            .add_filter(what, extensions)
+            .set_directory(env::current_dir().unwrap().as_path())
+            .save_file()
+            .await;
+        let Some(file) = file else {
+            return;
+        };
+        let path = file.path().to_path_buf();
+        let res = tokio::task::spawn_blocking(move || save(&path)).await;
+        match res {
+            Ok(Ok(())) => info!("{} saved to {:?}", what, file.path()),
+            Ok(Err(err)) => error!("cannot save {:?}: {}", file.path(), err),
+            Err(err) => error!("{} export failed: {}", what, err),
+        }
+    });
+}
+
+/// Window with the exports of the whole scenario over an interval.
+pub fn show_export(
+    mut egui_context: EguiContexts,
+    mut uidata: ResMut<UIData>,
+    mut settings: ResMut<ExportSettings>,
+    rt: Res<celestrak::Runtime>,
+    clock: Res<celestrak::SimClock>,
+    router: Res<Router>,
+    topology: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    source: ScenarioSource,
+) {
+    let mut opened = uidata
+        .0
+        .get("Export")
+        .unwrap_or(&false.into())
+        .as_bool()
+        .unwrap();
+    if !opened {
+        return;
+    }
+    egui::Window::new("Export")
+        .open(&mut opened)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(format!(
+                "from {} with {} routing",
+                clock.now.format("%Y-%m-%d %H:%M:%S"),
+                router.algorithm.name()
+            ));
+            ui.collapsing("CZML (Cesium)", |ui| {
+                let cfg = &mut settings.czml;
+                ui.horizontal(|ui| {
+                    ui.label("duration (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.duration).range(1.0..=7.0 * 86400.0));
+                    ui.label("step (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.step).range(1.0..=3600.0));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("satellite filter:");
+                    ui.text_edit_singleline(&mut cfg.sat_filter);
+                    ui.checkbox(&mut cfg.links, "data links");
+                });
+                if ui.button("export CZML").clicked() {
+                    let cfg = cfg.clone();
+                    let (start, kind, topology) = (clock.now, router.kind, topology.clone());
+                    let min_elevation = handover.min_elevation;
+                    let scenario = source.build();
+                    save_with_dialog(&rt, "CZML", &["czml"], move |path| {
+                        cfg.save(path, &scenario, start, kind, &topology, min_elevation)
+                    });
+                }
+            });
+            ui.collapsing("GeoJSON / KML", |ui| {
+                let cfg = &mut settings.geo;
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut cfg.format, GeoFormat::GeoJson, "GeoJSON");
+                    ui.radio_value(&mut cfg.format, GeoFormat::Kml, "KML");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("satellite filter:");
+                    ui.text_edit_singleline(&mut cfg.sat_filter);
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut cfg.satellites, "satellites");
+                    ui.checkbox(&mut cfg.ground_stations, "ground stations");
+                    ui.checkbox(&mut cfg.links, "data links");
+                    ui.checkbox(&mut cfg.footprints, "footprints");
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut cfg.ground_tracks, "ground tracks");
+                    ui.label("duration (s):");
+                    ui.add(
+                        egui::DragValue::new(&mut cfg.track_duration).range(1.0..=7.0 * 86400.0),
+                    );
+                    ui.label("step (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.track_step).range(1.0..=3600.0));
+                });
+                if ui.button("export").clicked() {
+                    let cfg = cfg.clone();
+                    let (start, kind, topology) = (clock.now, router.kind, topology.clone());
+                    let min_elevation = handover.min_elevation;
+                    let scenario = source.build();
+                    let (what, extensions): (_, &'static [&'static str]) = match cfg.format {
+                        GeoFormat::GeoJson => ("GeoJSON", &["geojson", "json"]),
+                        GeoFormat::Kml => ("KML", &["kml"]),
+                    };
+                    save_with_dialog(&rt, what, extensions, move |path| {
+                        cfg.save(path, &scenario, start, kind, &topology, min_elevation)
+                    });
+                }
+            });
+            #[cfg(feature = "columnar")]
+            ui.collapsing("Arrow / Parquet", |ui| {
+                use crate::export::columnar::ColumnarFormat;
+                let cfg = &mut settings.columnar;
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut cfg.format, ColumnarFormat::Parquet, "Parquet");
+                    ui.radio_value(&mut cfg.format, ColumnarFormat::ArrowIpc, "Arrow IPC");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("duration (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.duration).range(1.0..=30.0 * 86400.0));
+                    ui.label("step (s):");
+                    ui.add(egui::DragValue::new(&mut cfg.step).range(1.0..=3600.0));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("satellite filter:");
+                    ui.text_edit_singleline(&mut cfg.sat_filter);
+                    ui.checkbox(&mut cfg.links, "link statistics");
+                });
+                if ui.button("export").clicked() {
+                    let cfg = cfg.clone();
+                    let (start, kind, topology) = (clock.now, router.kind, topology.clone());
+                    let min_elevation = handover.min_elevation;
+                    let scenario = source.build();
+                    let (what, extensions): (_, &'static [&'static str]) = match cfg.format {
+                        ColumnarFormat::Parquet => ("Parquet", &["parquet"]),
+                        ColumnarFormat::ArrowIpc => ("Arrow IPC", &["arrow", "feather"]),
+                    };
+                    save_with_dialog(&rt, what, extensions, move |path| {
+                        let rows =
+                            cfg.save(path, &scenario, start, kind, &topology, min_elevation)?;
+                        info!("{} rows written", rows);
+                        Ok(())
+                    });
+                }
+            });
+        });
+    uidata.0["Export"] = opened.into();
+}
+
 /// Displays satellite data and provides controls to search, filter, and manage visibility of satellites.
 fn show_satellite_data(
     egui_context: &mut EguiContexts,
@@ -157,10 +707,13 @@ fn show_satellite_data(
         &TEMEVelocity,
         &LatLonAlt,
         &Name,
+        &TleQuality,
     )>,
     vis: &mut Query<&mut Visibility, With<SatID>>,
     query: &mut ResMut<QueryConfig>,
     rt: &Res<celestrak::Runtime>,
+    clock: &Res<celestrak::SimClock>,
+    satinfo: &Res<SatInfo>,
 ) {
     let mut opened = uidata
         .0
@@ -182,54 +735,160 @@ fn show_satellite_data(
                 query.timer.reset();
             }
             #[cfg(not(target_arch = "wasm32"))]
-            handle_export(ui, satcfg, rt);
+            handle_export(ui, satcfg, rt, clock, satinfo, sats);
+            #[cfg(not(target_arch = "wasm32"))]
+            handle_oem_export(ui, satcfg, rt, clock, satinfo, sats);
             create_table(ui, satcfg.table_data.iter());
         });
     uidata.0["Satellite Data"] = opened.into();
 }
 
-/// Handles the export functionality by providing an option to save data to a CSV file asynchronously.
-fn handle_export(ui: &mut Ui, satcfg: &mut SatConfigs, rt: &Res<Runtime>) {
+/// CSV export of the listed satellites, at the current simulation time or over an interval.
+fn handle_export(
+    ui: &mut Ui,
+    satcfg: &mut SatConfigs,
+    rt: &Res<Runtime>,
+    clock: &Res<celestrak::SimClock>,
+    satinfo: &Res<SatInfo>,
+    sats: &Query<(
+        Entity,
+        &SGP4Constants,
+        &SatID,
+        &TEMEPos,
+        &TEMEVelocity,
+        &LatLonAlt,
+        &Name,
+        &TleQuality,
+    )>,
+) {
+    ui.collapsing("CSV Export", |ui| {
+        let cfg = &mut satcfg.export;
+        ui.horizontal_wrapped(|ui| {
+            for field in CsvField::ALL {
+                let mut on = cfg.fields.contains(&field);
+                if ui.checkbox(&mut on, field.label()).changed() {
+                    if on {
+                        cfg.fields.push(field);
+                        cfg.fields.sort_by_key(|f| CsvField::ALL.iter().position(|a| a == f));
+                    } else {
+                        cfg.fields.retain(|f| *f != field);
+                    }
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("duration (s):");
+            ui.add(egui::DragValue::new(&mut cfg.duration).range(0.0..=7.0 * 86400.0));
+            ui.label("step (s):");
+            ui.add(egui::DragValue::new(&mut cfg.step).range(1.0..=86400.0));
+            ui.label("decimals:");
+            ui.add(egui::DragValue::new(&mut cfg.precision).range(0..=12));
+        });
+        ui.horizontal(|ui| {
+            ui.label("units:");
+            ui.radio_value(&mut cfg.units, UnitStyle::None, "none");
+            ui.radio_value(&mut cfg.units, UnitStyle::Header, "in header");
+            ui.radio_value(&mut cfg.units, UnitStyle::Row, "unit row");
+        });
+    });
     if ui.button("export").clicked() {
-        let (tx, rx) = oneshot::channel();
-        satcfg.rx = Some(rx);
-        let _ = rt.0.spawn(async move {
+        let export = satcfg.export.clone();
+        let start = clock.now;
+        let selected: Vec<ScenarioSat> = satcfg
+            .visible
+            .iter()
+            .filter_map(|e| {
+                let (_, _, id, ..) = sats.get(*e).ok()?;
+                ScenarioSat::from_elements(*e, satinfo.sats.get(&id.0)?)
+            })
+            .collect();
+        rt.0.spawn(async move {
             let file = AsyncFileDialog::new()
                 .add_filter("csv", &["csv"])
                 .set_directory(env::current_dir().unwrap().as_path())
                 .save_file()
                 .await;
-            let _ = tx.send(file);
+            let Some(file) = file else {
+                return;
+            };
+            let path = file.path().to_path_buf();
+            let res =
+                tokio::task::spawn_blocking(move || export.save(&path, &selected, start)).await;
+            match res {
+                Ok(Ok(rows)) => info!("{} rows saved to {:?}", rows, file.path()),
+                Ok(Err(err)) => error!("cannot save {:?}: {}", file.path(), err),
+                Err(err) => error!("CSV export failed: {}", err),
+            }
         });
     }
-    if let Some(mut rx) = satcfg.rx.take() {
-        match rx.try_recv() {
-            Ok(f) => {
-                if let Some(filename) = f {
-                    let ts = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs_f64();
-                    use std::io::Write;
-                    let mut f = std::fs::File::create(filename.path()).expect("create failed");
-
-                    for i in &satcfg.table_data {
-                        f.write(ts.to_string().as_bytes()).unwrap();
-                        f.write(",".as_bytes()).unwrap();
-                        for j in i {
-                            f.write(j.as_bytes()).unwrap();
-                            f.write(",".as_bytes()).unwrap();
-                        }
-                        f.write("\n".as_bytes()).unwrap();
-                    }
-                }
-            }
-            Err(TryRecvError::Empty) => {
-                satcfg.rx = Some(rx);
-            }
-            _ => {}
+}
+
+/// CCSDS OEM ephemeris of the listed satellites, propagated from their SGP4 constants.
+fn handle_oem_export(
+    ui: &mut Ui,
+    satcfg: &mut SatConfigs,
+    rt: &Res<Runtime>,
+    clock: &Res<celestrak::SimClock>,
+    satinfo: &Res<SatInfo>,
+    sats: &Query<(
+        Entity,
+        &SGP4Constants,
+        &SatID,
+        &TEMEPos,
+        &TEMEVelocity,
+        &LatLonAlt,
+        &Name,
+        &TleQuality,
+    )>,
+) {
+    ui.collapsing("OEM Export", |ui| {
+        let cfg = &mut satcfg.oem;
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut cfg.format, OemFormat::Kvn, "KVN");
+            ui.radio_value(&mut cfg.format, OemFormat::Xml, "XML");
+            ui.label("frame:");
+            ui.radio_value(&mut cfg.frame, OemFrame::Teme, "TEME");
+            ui.radio_value(&mut cfg.frame, OemFrame::Itrf, "ITRF");
+        });
+        ui.horizontal(|ui| {
+            ui.label("span (s):");
+            ui.add(egui::DragValue::new(&mut cfg.span).range(0.0..=30.0 * 86400.0));
+            ui.label("step (s):");
+            ui.add(egui::DragValue::new(&mut cfg.step).range(1.0..=86400.0));
+        });
+        if ui.button("export OEM").clicked() {
+            let export = cfg.clone();
+            let start = clock.now;
+            let objects: Vec<OemObject> = satcfg
+                .visible
+                .iter()
+                .filter_map(|e| {
+                    let (entity, constants, id, .., name, _) = sats.get(*e).ok()?;
+                    let elements = satinfo.sats.get(&id.0)?;
+                    Some(OemObject {
+                        sat: ScenarioSat {
+                            entity,
+                            norad_id: id.0,
+                            name: name.to_string(),
+                            epoch: elements.datetime,
+                            constants: constants.0.clone(),
+                            inclination: elements.inclination,
+                        },
+                        object_id: elements.international_designator.clone(),
+                    })
+                })
+                .collect();
+            let (what, extensions): (_, &'static [&'static str]) = match export.format {
+                OemFormat::Kvn => ("OEM", &["oem", "txt"]),
+                OemFormat::Xml => ("OEM XML", &["xml"]),
+            };
+            save_with_dialog(rt, what, extensions, move |path| {
+                let segments = export.save(path, &objects, start)?;
+                info!("{} OEM segments written", segments);
+                Ok(())
+            });
         }
-    }
+    });
 }
 
 /// Manages the search box functionality, allowing users to filter satellites based on name.
@@ -245,6 +904,7 @@ fn handle_search_box(
         &TEMEVelocity,
         &LatLonAlt,
         &Name,
+        &TleQuality,
     )>,
 ) {
     ui.label("Search Box:");
@@ -262,15 +922,15 @@ fn handle_search_box(
     satcfg.visible.clear();
     satcfg.table_data = sats
         .iter()
-        .filter(|(e, _, _, _, _, _, name)| {
+        .filter(|(e, _, _, _, _, _, name, _)| {
             let vis = name.contains(&text);
             if vis {
                 satcfg.visible.push(e.clone());
             }
             vis
         })
-        .map(|(e, elements, id, pos, vel, lla, name)| {
-            format_satellite_data(e, elements, id, pos, vel, lla, name)
+        .map(|(e, elements, id, pos, vel, lla, name, quality)| {
+            format_satellite_data(e, elements, id, pos, vel, lla, name, quality)
         })
         .collect();
 }
@@ -284,7 +944,8 @@ fn format_satellite_data(
     vel: &TEMEVelocity,
     lla: &LatLonAlt,
     name: &Name,
-) -> [String; 7] {
+    quality: &TleQuality,
+) -> [String; 8] {
     let orbit: Orbit =
         serde_json::from_value(serde_json::to_value(&elements.0).unwrap()["orbit_0"].clone())
             .unwrap();
@@ -296,6 +957,7 @@ fn format_satellite_data(
         format!("{:.2},{:.2},{:.2}", vel.0[0], vel.0[1], vel.0[2]),
         format!("{:.2},{:.2},{:.2}", lla.0 .0, lla.0 .1, lla.0 .2),
         orbit.inclination.to_degrees().to_string(),
+        format!("{:.1} d {}", quality.age, quality.flags()),
     ]
 }
 
@@ -320,6 +982,7 @@ fn config_ui(
     satcfg: &mut ResMut<SatConfigs>,
     gscfg: &mut ResMut<GSConfigs>,
     cccfg: &mut ResMut<ClearColor>,
+    handover: &mut Query<(&Name, &mut Handover, &mut Antennas, &HandoverStats), With<GroundStationID>>,
     opened: &mut bool,
 ) {
     fn edit_color(ui: &mut egui::Ui, label: &str, color: &mut Color) {
@@ -344,12 +1007,44 @@ fn config_ui(
             edit_color(ui, "Satellite Color:", &mut satcfg.sat_color);
             edit_color(ui, "Ground Station Color:", &mut gscfg.color);
             edit_color(ui, "Clear Color:", &mut cccfg.0);
+
+            ui.separator();
+            ui.label("Handover Policy:");
+            handover.iter_mut().for_each(|(name, mut policy, mut antennas, stats)| {
+                ui.horizontal(|ui| {
+                    ui.label(name.as_str().replace('\n', " "));
+                    egui::ComboBox::from_id_salt(name.as_str())
+                        .selected_text(policy.0.name())
+                        .show_ui(ui, |ui| {
+                            for kind in HandoverPolicyKind::ALL {
+                                let p = kind.build();
+                                let selected = p.name() == policy.0.name();
+                                if ui.selectable_label(selected, p.name()).clicked() && !selected {
+                                    *policy = Handover(p);
+                                }
+                            }
+                        });
+                    if let Some(HandoverPolicyKind::Hysteresis { mut threshold }) = policy.0.kind() {
+                        ui.label("threshold:");
+                        let drag = egui::DragValue::new(&mut threshold).range(0.0..=90.0).suffix("°");
+                        if ui.add(drag).changed() {
+                            *policy = Handover::from(HandoverPolicyKind::Hysteresis { threshold });
+                        }
+                    }
+                    ui.label("antennas:");
+                    ui.add(egui::DragValue::new(&mut antennas.0).range(1..=8));
+                    ui.label(format!(
+                        "handovers: {} interruption: {:.1} s (total {:.1} s)",
+                        stats.count, stats.last_interruption, stats.total_interruption
+                    ));
+                });
+            });
         });
 }
 
 /// Generates a table for displaying satellite data.
 /// Generates a table for displaying satellite data with specified column headers.
-fn create_table<'a, T: ExactSizeIterator + Iterator<Item = &'a [String; 7]>>(
+fn create_table<'a, T: ExactSizeIterator + Iterator<Item = &'a [String; 8]>>(
     ui: &mut egui::Ui,
     mut iter: T,
 ) {
@@ -361,7 +1056,8 @@ fn create_table<'a, T: ExactSizeIterator + Iterator<Item = &'a [String; 7]>>(
         "TEME Coord", 
         "TEME Velocity", 
         "Latitude,Longitude,Altitude", 
-        "Inclination"
+        "Inclination",
+        "TLE age, flags"
     ];
 
     egui_extras::TableBuilder::new(ui)
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::{LatLonAlt, PropagationSet, SatID, SimClock},
+    datalink::{BackupShape, DataLink, DataLinkStats, GSDataLink, InDataLink},
+    groundstation::{Antennas, GroundStationBundle, GroundStationID, NearestSat},
+    handover::{Handover, HandoverPolicyKind},
+    routing::{Router, RoutingKind},
+};
+
+/// Commands accepted from external controllers.
+///
+/// Encoded as a map with a `type` field naming the command, e.g.
+/// `{"type": "remove_link", "name": "link 1"}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    AddGroundStation {
+        id: u64,
+        name: String,
+        /// (deg)
+        lat: f64,
+        lon: f64,
+        /// (km)
+        #[serde(default)]
+        alt: f64,
+        antennas: Option<usize>,
+        handover: Option<HandoverPolicyKind>,
+    },
+    RemoveGroundStation {
+        id: u64,
+    },
+    /// Data link between two ground stations given by their IDs.
+    AddLink {
+        name: String,
+        from: u64,
+        to: u64,
+    },
+    RemoveLink {
+        name: String,
+    },
+    SetClock {
+        /// unix timestamp (s)
+        time: Option<f64>,
+        /// simulation seconds per wall clock second
+        rate: Option<f64>,
+        paused: Option<bool>,
+    },
+    /// Changes the routing algorithm and/or the handover policy of one
+    /// station, or of all stations if `station` is not given. The hysteresis
+    /// policy takes its threshold, e.g. `{"hysteresis": {"threshold": 30}}`.
+    SetRoutingPolicy {
+        algorithm: Option<RoutingKind>,
+        handover: Option<HandoverPolicyKind>,
+        station: Option<u64>,
+    },
+    RequestSnapshot {
+        #[serde(default)]
+        satellites: bool,
+    },
+}
+
+/// A command together with the ID the sender uses to match the acknowledgement.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandMsg {
+    #[serde(default)]
+    pub id: u64,
+    pub command: Command,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct RemoteCommand(pub CommandMsg);
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GroundStationState {
+    pub id: u64,
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    /// NORAD ID of the access satellite
+    pub access: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LinkState {
+    pub name: String,
+    pub from: u64,
+    pub to: u64,
+    /// end to end latency (s)
+    pub latency: f32,
+    /// (m)
+    pub distance: f32,
+    pub hops: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SatelliteState {
+    pub norad_id: u64,
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+}
+
+/// State of the simulation returned by `request_snapshot`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Snapshot {
+    /// simulation time as unix timestamp (s)
+    pub time: f64,
+    pub rate: f64,
+    pub paused: bool,
+    pub routing: String,
+    pub ground_stations: Vec<GroundStationState>,
+    pub links: Vec<LinkState>,
+    pub satellites: Vec<SatelliteState>,
+}
+
+/// Reply to a [`CommandMsg`] with the same `id`.
+#[derive(Event, Clone, Debug, Default, Serialize)]
+pub struct CommandAck {
+    pub id: u64,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub snapshot: Option<Snapshot>,
+}
+
+impl CommandAck {
+    pub fn ok(id: u64) -> Self {
+        Self {
+            id,
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(id: u64, error: impl Into<String>) -> Self {
+        Self {
+            id,
+            ok: false,
+            error: Some(error.into()),
+            snapshot: None,
+        }
+    }
+}
+
+fn despawn_link(
+    cmd: &mut Commands,
+    entity: Entity,
+    path: Option<&DataLink>,
+    backup: Option<&BackupShape>,
+) {
+    for edge in path.map(|p| p.0.as_slice()).unwrap_or_default() {
+        for node in [edge.0 .0, edge.0 .1] {
+            if let Some(mut e) = cmd.get_entity(node) {
+                e.remove::<InDataLink>();
+            }
+        }
+    }
+    if let Some(shape) = backup {
+        if let Some(e) = cmd.get_entity(shape.0) {
+            e.despawn_recursive();
+        }
+    }
+    cmd.entity(entity).despawn_recursive();
+}
+
+/**
+Applies remote commands and answers each of them with a [`CommandAck`].
+Stations added earlier in the same frame can already be used by `add_link`,
+and links added earlier in the frame count as existing.
+*/
+pub fn apply_commands(
+    mut cmd: Commands,
+    mut events: EventReader<RemoteCommand>,
+    mut acks: EventWriter<CommandAck>,
+    mut clock: ResMut<SimClock>,
+    mut router: ResMut<Router>,
+    mut stations: Query<(
+        Entity,
+        &GroundStationID,
+        &Name,
+        &LatLonAlt,
+        Option<&mut Handover>,
+        Option<&NearestSat>,
+    )>,
+    links: Query<(
+        Entity,
+        &Name,
+        &GSDataLink,
+        Option<&DataLink>,
+        Option<&DataLinkStats>,
+        Option<&BackupShape>,
+    )>,
+    sats: Query<(&SatID, &Name, &LatLonAlt)>,
+) {
+    let mut added: HashMap<u64, Entity> = HashMap::new();
+    let mut added_links: HashMap<String, (Entity, Entity, Entity)> = HashMap::new();
+    for RemoteCommand(msg) in events.read() {
+        let station = |id: u64| {
+            stations
+                .iter()
+                .find(|s| s.1 .0 == id)
+                .map(|s| s.0)
+                .or_else(|| added.get(&id).copied())
+        };
+        let ack = match &msg.command {
+            Command::AddGroundStation {
+                id,
+                name,
+                lat,
+                lon,
+                alt,
+                antennas,
+                handover,
+            } => {
+                if station(*id).is_some() {
+                    CommandAck::error(msg.id, format!("ground station {} exists", id))
+                } else if !(lat.abs() <= 90.0) || !lon.is_finite() || !alt.is_finite() {
+                    CommandAck::error(
+                        msg.id,
+                        format!("invalid position {}, {}, {}", lat, lon, alt),
+                    )
+                } else if handover.is_some_and(|kind| !kind.is_valid()) {
+                    CommandAck::error(
+                        msg.id,
+                        format!("invalid handover policy {:?}", handover.unwrap()),
+                    )
+                } else {
+                    let mut e = cmd.spawn(GroundStationBundle {
+                        id: GroundStationID(*id),
+                        pos: LatLonAlt((*lat, *lon, *alt)),
+                    });
+                    e.insert(Name::new(name.clone()));
+                    if let Some(n) = antennas {
+                        e.insert(Antennas(*n));
+                    }
+                    if let Some(kind) = handover {
+                        e.insert(Handover::from(*kind));
+                    }
+                    added.insert(*id, e.id());
+                    CommandAck::ok(msg.id)
+                }
+            }
+            Command::RemoveGroundStation { id } => match station(*id) {
+                Some(entity) => {
+                    links
+                        .iter()
+                        .filter(|l| l.2 .0 .0 == entity || l.2 .0 .1 == entity)
+                        .for_each(|(e, _, _, path, _, backup)| {
+                            despawn_link(&mut cmd, e, path, backup)
+                        });
+                    added_links.retain(|_, (e, a, b)| {
+                        let attached = *a == entity || *b == entity;
+                        if attached {
+                            cmd.entity(*e).despawn_recursive();
+                        }
+                        !attached
+                    });
+                    cmd.entity(entity).despawn_recursive();
+                    added.remove(id);
+                    CommandAck::ok(msg.id)
+                }
+                None => CommandAck::error(msg.id, format!("no ground station {}", id)),
+            },
+            Command::AddLink { name, from, to } => match (station(*from), station(*to)) {
+                _ if added_links.contains_key(name)
+                    || links.iter().any(|l| l.1.as_str() == name) =>
+                {
+                    CommandAck::error(msg.id, format!("link {} exists", name))
+                }
+                (Some(a), Some(b)) if a != b => {
+                    let e = cmd
+                        .spawn(GSDataLink((a, b)))
+                        .insert(Name::new(name.clone()))
+                        .id();
+                    added_links.insert(name.clone(), (e, a, b));
+                    CommandAck::ok(msg.id)
+                }
+                (Some(_), Some(_)) => {
+                    CommandAck::error(msg.id, "a link needs two different stations")
+                }
+                (None, _) => CommandAck::error(msg.id, format!("no ground station {}", from)),
+                (_, None) => CommandAck::error(msg.id, format!("no ground station {}", to)),
+            },
+            Command::RemoveLink { name } => match links.iter().find(|l| l.1.as_str() == name) {
+                Some((e, _, _, path, _, backup)) => {
+                    despawn_link(&mut cmd, e, path, backup);
+                    CommandAck::ok(msg.id)
+                }
+                None => match added_links.remove(name) {
+                    Some((e, _, _)) => {
+                        cmd.entity(e).despawn_recursive();
+                        CommandAck::ok(msg.id)
+                    }
+                    None => CommandAck::error(msg.id, format!("no link {}", name)),
+                },
+            },
+            Command::SetClock { time, rate, paused } => {
+                let t = time.map(|t| {
+                    DateTime::from_timestamp_micros((t * 1e6) as i64).filter(|_| t.is_finite())
+                });
+                if let Some(None) = t {
+                    CommandAck::error(msg.id, format!("invalid time {}", time.unwrap()))
+                } else if rate.is_some_and(|r| !r.is_finite()) {
+                    CommandAck::error(msg.id, "invalid rate")
+                } else {
+                    if let Some(Some(t)) = t {
+                        clock.set(t);
+                    }
+                    if let Some(rate) = rate {
+                        clock.set_rate(*rate);
+                    }
+                    if let Some(paused) = paused {
+                        clock.set_paused(*paused);
+                    }
+                    CommandAck::ok(msg.id)
+                }
+            }
+            Command::SetRoutingPolicy {
+                algorithm,
+                handover,
+                station: id,
+            } => {
+                let target = id.map(station);
+                if let Some(None) = target {
+                    CommandAck::error(msg.id, format!("no ground station {}", id.unwrap()))
+                } else if handover.is_some_and(|kind| !kind.is_valid()) {
+                    CommandAck::error(
+                        msg.id,
+                        format!("invalid handover policy {:?}", handover.unwrap()),
+                    )
+                } else {
+                    if let Some(kind) = algorithm {
+                        if router.kind != *kind {
+                            *router = Router::new(*kind);
+                        }
+                    }
+                    if let Some(kind) = handover {
+                        let target = target.flatten();
+                        stations.iter_mut().for_each(|(e, _, _, _, policy, _)| {
+                            if target.is_some_and(|t| t != e) {
+                                return;
+                            }
+                            match policy {
+                                Some(mut policy) => *policy = Handover::from(*kind),
+                                None => {
+                                    cmd.entity(e).insert(Handover::from(*kind));
+                                }
+                            }
+                        });
+                        for e in added.values() {
+                            if target.map_or(true, |t| t == *e) {
+                                cmd.entity(*e).insert(Handover::from(*kind));
+                            }
+                        }
+                    }
+                    CommandAck::ok(msg.id)
+                }
+            }
+            Command::RequestSnapshot { satellites } => {
+                let norad = |e: Entity| sats.get(e).ok().map(|s| s.0 .0);
+                let gs_id = |e: Entity| stations.get(e).map(|s| s.1 .0).unwrap_or_default();
+                let mut snapshot = Snapshot {
+                    time: clock.now.timestamp_micros() as f64 / 1e6,
+                    rate: clock.rate,
+                    paused: clock.paused,
+                    routing: router.algorithm.name().to_string(),
+                    ..Default::default()
+                };
+                snapshot.ground_stations = stations
+                    .iter()
+                    .map(|(_, id, name, lla, _, nearest)| GroundStationState {
+                        id: id.0,
+                        name: name.to_string(),
+                        lat: lla.0 .0,
+                        lon: lla.0 .1,
+                        alt: lla.0 .2,
+                        access: nearest.and_then(|n| norad(n.eid)),
+                    })
+                    .collect();
+                snapshot.links = links
+                    .iter()
+                    .map(|(_, name, gs, _, stats, _)| LinkState {
+                        name: name.to_string(),
+                        from: gs_id(gs.0 .0),
+                        to: gs_id(gs.0 .1),
+                        latency: stats.map(|s| s.latencies.iter().sum()).unwrap_or_default(),
+                        distance: stats.map(|s| s.distance.iter().sum()).unwrap_or_default(),
+                        hops: stats.map(|s| s.latencies.len()).unwrap_or_default(),
+                    })
+                    .collect();
+                if *satellites {
+                    snapshot.satellites = sats
+                        .iter()
+                        .map(|(id, name, lla)| SatelliteState {
+                            norad_id: id.0,
+                            name: name.to_string(),
+                            lat: lla.0 .0,
+                            lon: lla.0 .1,
+                            alt: lla.0 .2,
+                        })
+                        .collect();
+                }
+                CommandAck {
+                    snapshot: Some(snapshot),
+                    ..CommandAck::ok(msg.id)
+                }
+            }
+        };
+        if let Some(err) = &ack.error {
+            warn!("command {} failed: {}", msg.id, err);
+        }
+        acks.send(ack);
+    }
+}
+
+/// Applies [`RemoteCommand`]s, used by the external interfaces.
+#[derive(Default)]
+pub struct CommandPlugin;
+
+impl Plugin for CommandPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RemoteCommand>();
+        app.add_event::<CommandAck>();
+        // stations, links and clock changes take effect before the satellites are propagated
+        app.add_systems(Update, apply_commands.before(PropagationSet));
+    }
+}
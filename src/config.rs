@@ -0,0 +1,117 @@
+use std::{fmt, path::PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Default location of the configuration file.
+pub const CONFIG_FILE: &str = "./rustsat.json";
+
+/**
+Settings of the optional interfaces. Read from `rustsat.json` (or the file
+given with `--config <file>`) and then overridden by command line arguments
+of the form `--section.key value`, e.g. `--zmq.publish_rate 30`. Values are
+parsed as JSON and taken as plain strings if that fails.
+*/
+#[derive(Resource, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub tle: crate::tle_provider::TleProviderConfig,
+    pub udp: crate::udp_output::UdpConfig,
+    pub netem: crate::netem::NetemConfig,
+    pub session: crate::session::SessionConfig,
+    pub tle_history: crate::tle_history::TleHistoryConfig,
+    pub tle_quality: crate::tle_quality::TleQualityConfig,
+    pub bench: crate::bench::BenchConfig,
+    #[cfg(feature = "zmq_comm")]
+    pub zmq: crate::zmq_comm::ZmqConfig,
+    #[cfg(feature = "http_server")]
+    pub http: crate::http_server::HttpConfig,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: crate::mqtt::MqttConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(String),
+    Argument(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {:?}: {}", path, err),
+            ConfigError::Parse(err) => write!(f, "invalid configuration: {}", err),
+            ConfigError::Argument(arg) => write!(f, "invalid argument {}", arg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Sets `value` at a dotted `path`, creating the intermediate objects.
+fn set_path(root: &mut Value, path: &str, value: Value) -> Result<(), ConfigError> {
+    let mut node = root;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        let Value::Object(map) = node else {
+            return Err(ConfigError::Argument(format!("--{}: {} is not a section", path, key)));
+        };
+        if keys.peek().is_none() {
+            map.insert(key.to_string(), value);
+            return Ok(());
+        }
+        node = map
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+    Err(ConfigError::Argument(format!("--{}", path)))
+}
+
+impl AppConfig {
+    pub fn from_args() -> Result<Self, ConfigError> {
+        Self::load(std::env::args().skip(1))
+    }
+
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut file = None;
+        let mut overrides = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--") else {
+                return Err(ConfigError::Argument(arg));
+            };
+            let (key, value) = match key.split_once('=') {
+                Some((k, v)) => (k.to_string(), v.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| ConfigError::Argument(format!("{} needs a value", arg)))?;
+                    (key.to_string(), value)
+                }
+            };
+            if key == "config" {
+                file = Some(PathBuf::from(value));
+            } else {
+                overrides.push((key, value));
+            }
+        }
+
+        let mut root = match &file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                serde_json::from_str(&text).map_err(|e| ConfigError::Parse(e.to_string()))?
+            }
+            None => match std::fs::read_to_string(CONFIG_FILE) {
+                Ok(text) => serde_json::from_str(&text).map_err(|e| ConfigError::Parse(e.to_string()))?,
+                Err(_) => Value::Object(Default::default()),
+            },
+        };
+        for (key, value) in overrides {
+            let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+            set_path(&mut root, &key, value)?;
+        }
+        serde_json::from_value(root).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+}
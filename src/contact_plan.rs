@@ -0,0 +1,270 @@
+use std::{collections::HashMap, io::Write};
+
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    topology::{Scenario, LIGHT_SPEED},
+    util::geometry,
+};
+
+/// Parameters of a contact plan.
+#[derive(Clone, Debug)]
+pub struct ContactPlanConfig {
+    pub start: DateTime<Utc>,
+    /// length of the plan (s)
+    pub duration: f64,
+    /// sampling step of the visibility model (s)
+    pub step: f64,
+    /// elevation mask of the ground stations (deg)
+    pub min_elevation: f64,
+    /// maximum inter-satellite link range (m)
+    pub max_isl_range: f64,
+    /// minimum height of an inter-satellite link above the surface (m)
+    pub isl_clearance: f64,
+    /// include satellite to satellite contacts, O(n²) per step
+    pub include_isl: bool,
+    /// only satellites whose name contains this string, all if empty
+    pub sat_filter: String,
+    /// data rate written to the ION contacts (bytes/s)
+    pub data_rate: u64,
+    /// ground station node numbers are `gs_node_base + GroundStationID`,
+    /// satellites use their NORAD ID
+    pub gs_node_base: u64,
+}
+
+impl Default for ContactPlanConfig {
+    fn default() -> Self {
+        Self {
+            start: Utc::now(),
+            duration: 3600.0,
+            step: 10.0,
+            min_elevation: 25.0,
+            max_isl_range: 5_000_000.0,
+            isl_clearance: 80_000.0,
+            include_isl: true,
+            sat_filter: String::new(),
+            data_rate: 12_500_000,
+            gs_node_base: 1_000_000_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum NodeKind {
+    GroundStation,
+    Satellite,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ContactNode {
+    pub node: u64,
+    pub name: String,
+    pub kind: NodeKind,
+}
+
+/// A time window in which two nodes can communicate. Contacts are symmetric,
+/// `from` is always the smaller node number.
+#[derive(Clone, Debug, Serialize)]
+pub struct ContactWindow {
+    pub from: u64,
+    pub to: u64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// (m)
+    pub min_range: f64,
+    pub max_range: f64,
+    pub mean_range: f64,
+}
+
+impl ContactWindow {
+    pub fn duration(&self) -> f64 {
+        (self.end - self.start).num_milliseconds() as f64 / 1000.0
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ContactPlan {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub step: f64,
+    pub data_rate: u64,
+    pub nodes: Vec<ContactNode>,
+    pub contacts: Vec<ContactWindow>,
+}
+
+struct OpenWindow {
+    start: DateTime<Utc>,
+    last: DateTime<Utc>,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+}
+
+impl OpenWindow {
+    fn close(self, (from, to): (u64, u64), end: DateTime<Utc>) -> ContactWindow {
+        ContactWindow {
+            from,
+            to,
+            start: self.start,
+            end,
+            min_range: self.min,
+            max_range: self.max,
+            mean_range: self.sum / self.count as f64,
+        }
+    }
+}
+
+/**
+Samples the visibility model of a scenario and merges consecutive samples into
+contact windows. A window ends at the first sample the pair is no longer in
+contact, so windows are accurate to one `step`.
+*/
+pub fn generate_contact_plan(scenario: &Scenario, cfg: &ContactPlanConfig) -> ContactPlan {
+    let sats: Vec<_> = scenario
+        .sats
+        .iter()
+        .filter(|s| cfg.sat_filter.is_empty() || s.name.contains(&cfg.sat_filter))
+        .collect();
+    let sat_node: HashMap<Entity, u64> = sats.iter().map(|s| (s.entity, s.norad_id)).collect();
+    let mut nodes: Vec<ContactNode> = scenario
+        .ground
+        .iter()
+        .map(|gs| ContactNode {
+            node: cfg.gs_node_base + gs.id,
+            name: gs.name.replace('\n', " "),
+            kind: NodeKind::GroundStation,
+        })
+        .collect();
+    nodes.extend(sats.iter().map(|s| ContactNode {
+        node: s.norad_id,
+        name: s.name.clone(),
+        kind: NodeKind::Satellite,
+    }));
+
+    let ground: Vec<_> = scenario
+        .ground
+        .iter()
+        .map(|gs| {
+            (
+                cfg.gs_node_base + gs.id,
+                geometry::geodetic_to_ecef(gs.lla.0, gs.lla.1, gs.lla.2),
+            )
+        })
+        .collect();
+
+    let steps = (cfg.duration / cfg.step).ceil() as usize + 1;
+    let mut open: HashMap<(u64, u64), OpenWindow> = HashMap::new();
+    let mut contacts = Vec::new();
+    let max_sq = cfg.max_isl_range.powi(2);
+    let at = |k: usize| cfg.start + chrono::Duration::milliseconds((k as f64 * cfg.step * 1000.0) as i64);
+    for k in 0..steps {
+        let t = at(k);
+        let positions: Vec<(u64, [f64; 3])> = scenario
+            .sat_positions(&t)
+            .into_iter()
+            .filter_map(|(e, p)| sat_node.get(&e).map(|n| (*n, p)))
+            .collect();
+
+        let mut present: Vec<((u64, u64), f64)> = Vec::new();
+        for (g, gp) in &ground {
+            for (s, sp) in &positions {
+                if geometry::elevation(*gp, *sp) >= cfg.min_elevation {
+                    let d = ((gp[0] - sp[0]).powi(2) + (gp[1] - sp[1]).powi(2) + (gp[2] - sp[2]).powi(2)).sqrt();
+                    present.push(((*g.min(s), *g.max(s)), d));
+                }
+            }
+        }
+        if cfg.include_isl {
+            for i in 0..positions.len() {
+                for j in (i + 1)..positions.len() {
+                    let ((a, pa), (b, pb)) = (&positions[i], &positions[j]);
+                    let d_sq = (pa[0] - pb[0]).powi(2) + (pa[1] - pb[1]).powi(2) + (pa[2] - pb[2]).powi(2);
+                    if d_sq <= max_sq && geometry::line_of_sight(*pa, *pb, cfg.isl_clearance) {
+                        present.push(((*a.min(b), *a.max(b)), d_sq.sqrt()));
+                    }
+                }
+            }
+        }
+
+        let mut seen = std::collections::HashSet::with_capacity(present.len());
+        for (key, d) in present {
+            seen.insert(key);
+            let w = open.entry(key).or_insert(OpenWindow {
+                start: t,
+                last: t,
+                min: f64::MAX,
+                max: f64::MIN,
+                sum: 0.0,
+                count: 0,
+            });
+            w.last = t;
+            w.min = w.min.min(d);
+            w.max = w.max.max(d);
+            w.sum += d;
+            w.count += 1;
+        }
+        let closed: Vec<_> = open.keys().filter(|k| !seen.contains(k)).copied().collect();
+        for key in closed {
+            let w = open.remove(&key).unwrap();
+            contacts.push(w.close(key, t));
+        }
+    }
+    for (key, w) in open {
+        let end = w.last;
+        contacts.push(w.close(key, end));
+    }
+    contacts.sort_by(|a, b| a.start.cmp(&b.start).then(a.from.cmp(&b.from)).then(a.to.cmp(&b.to)));
+
+    ContactPlan {
+        start: cfg.start,
+        end: at(steps - 1),
+        step: cfg.step,
+        data_rate: cfg.data_rate,
+        nodes,
+        contacts,
+    }
+}
+
+fn ion_time(t: &DateTime<Utc>) -> String {
+    t.format("%Y/%m/%d-%H:%M:%S").to_string()
+}
+
+impl ContactPlan {
+    /**
+    Writes the plan as ION `ionrc` commands. ION contacts are directional,
+    so every window is written in both directions. Ranges are one way light
+    time in whole seconds, rounded up as ION expects.
+    */
+    pub fn write_ion<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "# contact plan generated by RustSat")?;
+        writeln!(w, "# {} to {}", self.start.to_rfc3339(), self.end.to_rfc3339())?;
+        for n in &self.nodes {
+            writeln!(w, "# node {} {:?} {}", n.node, n.kind, n.name)?;
+        }
+        for c in &self.contacts {
+            let (s, e) = (ion_time(&c.start), ion_time(&c.end));
+            let owlt = (c.max_range / LIGHT_SPEED).ceil() as u64;
+            for (a, b) in [(c.from, c.to), (c.to, c.from)] {
+                writeln!(w, "a contact {} {} {} {} {}", s, e, a, b, self.data_rate)?;
+                writeln!(w, "a range {} {} {} {} {}", s, e, a, b, owlt)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(w, self)
+    }
+
+    /// Writes `<stem>.txt` in ION format and `<stem>.json`.
+    pub fn save(&self, stem: &std::path::Path) -> std::io::Result<()> {
+        let mut f = std::fs::File::create(stem.with_extension("txt"))?;
+        self.write_ion(&mut f)?;
+        let mut f = std::fs::File::create(stem.with_extension("json"))?;
+        self.write_json(&mut f)?;
+        Ok(())
+    }
+}
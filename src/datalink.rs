@@ -1,15 +1,21 @@
 use bevy::{
-    color::palettes::css::GREEN, prelude::*, render::view::NoFrustumCulling,
-    time::common_conditions::on_real_timer,
+    color::palettes::css::{GREEN, ORANGE}, prelude::*, render::view::NoFrustumCulling,
 };
 use bevy_prototype_lyon::prelude::*;
-use std::time::Duration;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
 use crate::{
-    celestrak::{LatLonAlt, SatID, TEMEPos},
+    celestrak::{LatLonAlt, PropagationSet, Runtime, SatID, SimClock, TEMEPos},
     groundstation::{GroundStationID, NearestSat},
+    handover::{AccessSats, HandoverConfig},
     render_satellite::{SatRenderStage, WorldCoord},
-    util::distance,
+    routing::{ContactGraph, LiveContacts, Router, RoutingContext, RoutingKind},
+    session::{Recorder, Replay},
+    topology::{
+        build_sat_graph, GraphPath, SatGraph, Scenario, ScenarioSource, TopologyConfig, LIGHT_SPEED,
+    },
+    util::{distance, geometry},
 };
 
 #[derive(Component)]
@@ -24,13 +30,261 @@ pub struct DataLinkLatency(pub f64);
 #[component(storage = "SparseSet")]
 pub struct InDataLink(pub Entity);
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DataEdge(pub (Entity, Entity));
 
+/// Disjoint backup path of a [`GSDataLink`], used when the primary breaks.
+#[derive(Component)]
+pub struct BackupDataLink(pub Vec<DataEdge>);
+
+/// The k shortest paths of a [`GSDataLink`] found at the last rebuild.
+#[derive(Component, Default)]
+pub struct KShortestPaths(pub Vec<GraphPath>);
+
+/// Failover of a link from its primary to the backup path, all values in seconds.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Failover {
+    pub detection: f64,
+    pub backup_latency: f64,
+    pub latency: f64,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct FailoverEvent {
+    pub link: Entity,
+    pub failover: Failover,
+}
+
+/// Entity drawing the backup path of a link.
+#[derive(Component)]
+pub struct BackupShape(pub Entity);
+
 #[derive(Component, Default)]
 pub struct DataLinkStats {
     pub latencies: Vec<f32>,
     pub distance: Vec<f32>,
 }
+
+/// One recorded state of a data link.
+#[derive(Clone, Debug)]
+pub struct LinkSample {
+    /// simulation time as unix timestamp (s)
+    pub ts: f64,
+    /// end to end latency (s)
+    pub latency: f32,
+    pub hop_latencies: Vec<f32>,
+    pub hops: usize,
+}
+
+/// Ring buffer of the past [`DataLinkStats`] of a link.
+#[derive(Component)]
+pub struct DataLinkHistory {
+    pub samples: VecDeque<LinkSample>,
+    pub capacity: usize,
+}
+
+impl DataLinkHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, sample: LinkSample) {
+        while self.samples.len() >= self.capacity.max(1) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Summary of the end to end latency over the buffered samples.
+    pub fn summary(&self) -> Option<LatencySummary> {
+        LatencySummary::from_latencies(self.samples.iter().map(|s| s.latency as f64))
+    }
+}
+
+/// min/mean/percentiles of a latency series (s).
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct LatencySummary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub count: usize,
+}
+
+impl LatencySummary {
+    pub fn from_latencies<I: Iterator<Item = f64>>(iter: I) -> Option<Self> {
+        let mut v: Vec<f64> = iter.collect();
+        if v.is_empty() {
+            return None;
+        }
+        v.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f64| v[((p * (v.len() - 1) as f64).round() as usize).min(v.len() - 1)];
+        Some(Self {
+            min: v[0],
+            max: v[v.len() - 1],
+            mean: v.iter().sum::<f64>() / v.len() as f64,
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            count: v.len(),
+        })
+    }
+}
+
+#[derive(Resource)]
+/// Resource holding the size of the per link history buffers.
+pub struct LinkHistoryConfig {
+    pub capacity: usize,
+}
+
+impl Default for LinkHistoryConfig {
+    fn default() -> Self {
+        // ten minutes at 60 fps
+        Self { capacity: 36000 }
+    }
+}
+/// Converts a node sequence to the edges of a data link.
+pub fn path_edges(nodes: &[Entity]) -> Vec<DataEdge> {
+    nodes.windows(2).map(|w| DataEdge((w[0], w[1]))).collect()
+}
+
+/// Nodes of a path from the first ground station to the last, the inverse of [`path_edges`].
+pub fn path_nodes(edges: &[DataEdge]) -> Vec<Entity> {
+    edges
+        .first()
+        .map(|first| {
+            std::iter::once(first.0 .0)
+                .chain(edges.iter().map(|e| e.0 .1))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Routes of a ground station pair: primary, disjoint backup and the k shortest paths.
+pub struct LinkRoutes {
+    pub primary: Vec<DataEdge>,
+    pub backup: Option<GraphPath>,
+    pub k_paths: Vec<GraphPath>,
+}
+
+/**
+Routes a ground station pair with the active [`Router`]; the backup and the
+k shortest paths always come from the current satellite graph.
+Falls back to the direct access satellite hop when no route is found,
+e.g. before the first graph is built.
+*/
+pub fn route_gslink(
+    a: Entity,
+    b: Entity,
+    ctx: &RoutingContext,
+    router: &mut Router,
+    q2: &Query<(&GroundStationID, &NearestSat)>,
+) -> Option<LinkRoutes> {
+    if let Some(route) = router.algorithm.route(ctx, a, b) {
+        return Some(LinkRoutes {
+            primary: path_edges(&route.path.nodes),
+            backup: ctx
+                .graph
+                .disjoint_backup(&route.path, ctx.topology.disjoint),
+            k_paths: ctx.graph.k_shortest_paths(a, b, ctx.topology.k_paths),
+        });
+    }
+    let res = q2.get(a).ok()?;
+    let res2 = q2.get(b).ok()?;
+
+    let mut dlink: Vec<_> = Vec::new();
+    dlink.push(DataEdge((a, res.1.eid)));
+    dlink.push(DataEdge((res.1.eid, res2.1.eid)));
+
+    dlink.push(DataEdge((res2.1.eid, b)));
+    Some(LinkRoutes {
+        primary: dlink,
+        backup: None,
+        k_paths: Vec::new(),
+    })
+}
+
+fn insert_routes(cmd: &mut Commands, entity: Entity, routes: &LinkRoutes) {
+    match &routes.backup {
+        Some(backup) => {
+            cmd.entity(entity)
+                .insert(BackupDataLink(path_edges(&backup.nodes)));
+        }
+        None => {
+            cmd.entity(entity).remove::<BackupDataLink>();
+        }
+    }
+    cmd.entity(entity)
+        .insert(KShortestPaths(routes.k_paths.clone()));
+}
+
+/// Scenario for algorithms that propagate the constellation themselves.
+/// Contact graph routing gets its graph from [`LiveContacts`] instead.
+fn live_scenario(router: &Router, source: &ScenarioSource) -> Option<Scenario> {
+    (router.algorithm.needs_scenario() && router.algorithm.contact_plan().is_none())
+        .then(|| source.build())
+}
+
+/// Waits for the pending contact graph prediction and makes it the current graph.
+fn take_contacts(rt: &Runtime, contacts: &mut LiveContacts) {
+    let Some((plan, task)) = contacts.pending.take() else {
+        return;
+    };
+    match rt.0.block_on(task) {
+        Ok(graph) => {
+            contacts.graph = Some(Arc::new(graph));
+            contacts.plan = Some(plan);
+        }
+        Err(err) => error!("contact graph prediction failed! {}", err),
+    }
+}
+
+/// Predicts the contact graph of the active [`Router`] on the runtime when
+/// the current one is stale, and takes the new graph once it is ready.
+#[allow(clippy::too_many_arguments)]
+pub fn refresh_contacts(
+    rt: Res<Runtime>,
+    router: Res<Router>,
+    clock: Res<SimClock>,
+    cfg: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    source: ScenarioSource,
+    mut contacts: ResMut<LiveContacts>,
+    recorder: Option<Res<Recorder>>,
+    replay: Option<Res<Replay>>,
+) {
+    let Some(plan) = router.algorithm.contact_plan() else {
+        if contacts.graph.is_some() || contacts.pending.is_some() {
+            *contacts = LiveContacts::default();
+        }
+        return;
+    };
+    // sessions must not depend on the prediction time
+    let wait = recorder.is_some() || replay.is_some();
+    if contacts
+        .pending
+        .as_ref()
+        .is_some_and(|(_, task)| wait || task.is_finished())
+    {
+        take_contacts(&rt, &mut contacts);
+    }
+    if contacts.pending.is_some() || !contacts.stale(clock.now, plan) {
+        return;
+    }
+    let scenario = source.build();
+    let (start, topology, min_elevation) = (clock.now, cfg.clone(), handover.min_elevation);
+    let task = rt.0.spawn_blocking(move || {
+        ContactGraph::predict(&scenario, start, plan.0, plan.1, &topology, min_elevation)
+    });
+    contacts.pending = Some((plan, task));
+    if wait {
+        take_contacts(&rt, &mut contacts);
+    }
+}
+
 /**
 This function is used to establish data links
 the realisitic datalink should be established
@@ -39,26 +293,35 @@ via LEO satellite network.
 */
 pub fn init_gslinks(
     mut cmd: Commands,
+    graph: Res<SatGraph>,
+    cfg: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    mut router: ResMut<Router>,
+    clock: Res<SimClock>,
+    source: ScenarioSource,
+    contacts: Res<LiveContacts>,
     q: Query<(Entity, &GSDataLink), Without<DataLink>>,
     q2: Query<(&GroundStationID, &NearestSat)>,
 ) {
+    if q.is_empty() {
+        return;
+    }
+    let scenario = live_scenario(&router, &source);
+    let ctx = RoutingContext {
+        graph: &graph,
+        time: clock.now,
+        topology: &cfg,
+        min_elevation: handover.min_elevation,
+        scenario: scenario.as_ref(),
+        contacts: contacts.graph.as_deref(),
+    };
     q.iter().for_each(|(entity, v)| {
         let (a, b) = v.0;
-        let res = q2.get(a);
-        let res2 = q2.get(b);
-        if res.is_err() || res2.is_err() {
+        let Some(routes) = route_gslink(a, b, &ctx, &mut router, &q2) else {
             return;
-        }
-        let res = res.unwrap();
-        let res2 = res2.unwrap();
-
-        let mut dlink: Vec<_> = Vec::new();
-        dlink.push(DataEdge((a, res.1.eid)));
-        dlink.push(DataEdge((res.1.eid, res2.1.eid)));
-
-        dlink.push(DataEdge((res2.1.eid, b)));
-
-        cmd.entity(entity).insert(DataLink(dlink));
+        };
+        insert_routes(&mut cmd, entity, &routes);
+        cmd.entity(entity).insert(DataLink(routes.primary));
     });
 }
 
@@ -70,28 +333,127 @@ via LEO satellite network.
 */
 pub fn rebuild_gslinks(
     mut cmd: Commands,
-    mut q: Query<(&GSDataLink, &mut DataLink)>,
+    graph: Res<SatGraph>,
+    cfg: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    mut router: ResMut<Router>,
+    clock: Res<SimClock>,
+    source: ScenarioSource,
+    contacts: Res<LiveContacts>,
+    mut q: Query<(Entity, &GSDataLink, &mut DataLink)>,
     q2: Query<(&GroundStationID, &NearestSat)>,
 ) {
-    q.iter_mut().for_each(|(v, mut link)| {
+    let scenario = live_scenario(&router, &source);
+    let ctx = RoutingContext {
+        graph: &graph,
+        time: clock.now,
+        topology: &cfg,
+        min_elevation: handover.min_elevation,
+        scenario: scenario.as_ref(),
+        contacts: contacts.graph.as_deref(),
+    };
+    q.iter_mut().for_each(|(entity, v, mut link)| {
         let (a, b) = v.0;
-        let res = q2.get(a);
-        let res2 = q2.get(b);
-        if res.is_err() || res2.is_err() {
+        let Some(routes) = route_gslink(a, b, &ctx, &mut router, &q2) else {
             return;
-        }
-        let res = res.unwrap();
-        let res2 = res2.unwrap();
+        };
         for i in &link.0 {
             cmd.entity(i.0 .0).remove::<InDataLink>();
             cmd.entity(i.0 .1).remove::<InDataLink>();
         }
-        let mut dlink: Vec<_> = Vec::new();
-        dlink.push(DataEdge((a, res.1.eid)));
-        dlink.push(DataEdge((res.1.eid, res2.1.eid)));
+        insert_routes(&mut cmd, entity, &routes);
+        *link = DataLink(routes.primary);
+    });
+}
+
+/// Returns false if an edge of the path can no longer carry traffic: a ground
+/// station lost the access satellite or two satellites moved out of range.
+fn edge_alive(
+    edge: &DataEdge,
+    cfg: &TopologyConfig,
+    sats: &Query<&LatLonAlt, With<SatID>>,
+    gs: &Query<(Option<&AccessSats>, Option<&NearestSat>), With<GroundStationID>>,
+) -> bool {
+    let (a, b) = edge.0;
+    let linked = |g: Entity, s: Entity| match gs.get(g) {
+        Ok((Some(access), _)) => access.links.iter().any(|l| l.eid == s && l.is_active()),
+        Ok((None, Some(n))) => n.eid == s,
+        _ => false,
+    };
+    match (sats.get(a), sats.get(b)) {
+        (Ok(la), Ok(lb)) => {
+            let pa = geometry::geodetic_to_ecef(la.0 .0, la.0 .1, 1000.0 * la.0 .2);
+            let pb = geometry::geodetic_to_ecef(lb.0 .0, lb.0 .1, 1000.0 * lb.0 .2);
+            let d = ((pa[0] - pb[0]).powi(2) + (pa[1] - pb[1]).powi(2) + (pa[2] - pb[2]).powi(2)).sqrt();
+            d <= cfg.max_isl_range && geometry::line_of_sight(pa, pb, cfg.isl_clearance)
+        }
+        (Err(_), Ok(_)) => linked(a, b),
+        (Ok(_), Err(_)) => linked(b, a),
+        _ => false,
+    }
+}
 
-        dlink.push(DataEdge((res2.1.eid, b)));
-        *link = DataLink(dlink);
+fn path_latency(
+    edges: &[DataEdge],
+    sats: &Query<&LatLonAlt, With<SatID>>,
+    gs: &Query<&LatLonAlt, With<GroundStationID>>,
+) -> f64 {
+    let pos = |e: Entity| {
+        sats.get(e)
+            .or_else(|_| gs.get(e))
+            .ok()
+            .map(|llt| geometry::geodetic_to_ecef(llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2))
+    };
+    edges
+        .iter()
+        .filter_map(|edge| {
+            let (a, b) = (pos(edge.0 .0)?, pos(edge.0 .1)?);
+            Some(((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt())
+        })
+        .sum::<f64>()
+        / LIGHT_SPEED
+}
+
+/**
+Switches a data link to its backup as soon as an edge of the primary breaks.
+The failover latency is the detection time plus the one way delay of the
+backup path, i.e. the time until the first packet arrives over the backup.
+*/
+pub fn failover_gslinks(
+    mut cmd: Commands,
+    cfg: Res<TopologyConfig>,
+    mut events: EventWriter<FailoverEvent>,
+    mut q: Query<(Entity, &mut DataLink, &BackupDataLink)>,
+    sats: Query<&LatLonAlt, With<SatID>>,
+    gs: Query<(Option<&AccessSats>, Option<&NearestSat>), With<GroundStationID>>,
+    gs_pos: Query<&LatLonAlt, With<GroundStationID>>,
+) {
+    q.iter_mut().for_each(|(entity, mut link, backup)| {
+        if link.0.iter().all(|e| edge_alive(e, &cfg, &sats, &gs)) {
+            return;
+        }
+        if !backup.0.iter().all(|e| edge_alive(e, &cfg, &sats, &gs)) {
+            warn!("primary and backup path of {:?} are broken", entity);
+            cmd.entity(entity).remove::<BackupDataLink>();
+            return;
+        }
+        let backup_latency = path_latency(&backup.0, &sats, &gs_pos);
+        let failover = Failover {
+            detection: cfg.detection_time,
+            backup_latency,
+            latency: cfg.detection_time + backup_latency,
+        };
+        for i in &link.0 {
+            cmd.entity(i.0 .0).remove::<InDataLink>();
+            cmd.entity(i.0 .1).remove::<InDataLink>();
+        }
+        *link = DataLink(backup.0.clone());
+        cmd.entity(entity).remove::<BackupDataLink>();
+        cmd.entity(entity).insert(failover);
+        events.send(FailoverEvent {
+            link: entity,
+            failover,
+        });
     });
 }
 
@@ -157,7 +519,6 @@ pub fn compute_latency(
             }
             sum += dis;
             data.distance.push(dis as f32);
-            const LIGHT_SPEED: f64 = 299792458.0;
             data.latencies.push((dis / LIGHT_SPEED) as f32);
         }
         if sum > 0.0 {
@@ -167,6 +528,39 @@ pub fn compute_latency(
     });
 }
 
+/**
+Appends the latest link statistics to the history of each data link.
+*/
+pub fn record_history(
+    mut cmd: Commands,
+    cfg: Res<LinkHistoryConfig>,
+    clock: Res<SimClock>,
+    mut q: Query<(Entity, &DataLinkStats, Option<&mut DataLinkHistory>), Changed<DataLinkStats>>,
+) {
+    let ts = clock.now.timestamp_micros() as f64 / 1e6;
+    q.iter_mut().for_each(|(entity, stats, history)| {
+        let sample = LinkSample {
+            ts,
+            latency: stats.latencies.iter().sum(),
+            hop_latencies: stats.latencies.clone(),
+            hops: stats.latencies.len(),
+        };
+        match history {
+            Some(mut history) => {
+                if history.capacity != cfg.capacity {
+                    history.capacity = cfg.capacity;
+                }
+                history.push(sample);
+            }
+            None => {
+                let mut history = DataLinkHistory::new(cfg.capacity);
+                history.push(sample);
+                cmd.entity(entity).insert(history);
+            }
+        }
+    });
+}
+
 /**
 Add a shape to this datalink.
 */
@@ -241,27 +635,137 @@ pub fn update_data_link(
         // ));
     });
 }
+
+/**
+Draw the backup path of a data link with a thinner orange stroke.
+*/
+pub fn update_backup_shape(
+    mut commands: Commands,
+    links: Query<(Entity, Option<&BackupDataLink>, Option<&BackupShape>), With<GSDataLink>>,
+    mut shapes: Query<&mut Path, Without<GSDataLink>>,
+    points: Query<&WorldCoord>,
+) {
+    links.iter().for_each(|(entity, backup, shape)| {
+        let mut path_builder = PathBuilder::new();
+        for i in backup.map(|b| b.0.as_slice()).unwrap_or_default() {
+            let (a, b) = i.0;
+            let (Ok(spos), Ok(pos)) = (points.get(a), points.get(b)) else {
+                return;
+            };
+            path_builder.move_to(spos.0);
+            path_builder.line_to(pos.0);
+        }
+        let line = path_builder.build();
+        match shape {
+            Some(shape) => {
+                if let Ok(mut path) = shapes.get_mut(shape.0) {
+                    *path = line;
+                }
+            }
+            None if backup.is_some() => {
+                let mut t = Transform::default();
+                t.translation.z = 0.9f32;
+                let shape = ShapeBundle {
+                    path: line,
+                    spatial: SpatialBundle::from_transform(t),
+                    ..Default::default()
+                };
+                let e = commands
+                    .spawn((NoFrustumCulling, shape, Stroke::new(ORANGE, 0.05)))
+                    .id();
+                commands.entity(entity).insert(BackupShape(e));
+            }
+            None => {}
+        }
+    });
+}
+
+#[derive(Resource)]
+/// Resource deciding when the satellite graph and the link routes are rebuilt.
+pub struct RebuildSchedule {
+    /// real time between two rebuilds
+    pub interval: Duration,
+    /// overrides the interval for the current frame, set by session replay
+    pub forced: Option<bool>,
+}
+
+impl Default for RebuildSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs_f32(10.0),
+            forced: None,
+        }
+    }
+}
+
+/// Run condition of the periodic rebuild.
+pub fn rebuild_due(
+    schedule: Res<RebuildSchedule>,
+    time: Res<Time<Real>>,
+    mut last: Local<Option<Duration>>,
+) -> bool {
+    if let Some(due) = schedule.forced {
+        return due;
+    }
+    let now = time.elapsed();
+    let last = last.get_or_insert(now);
+    if now - *last >= schedule.interval {
+        *last = now;
+        true
+    } else {
+        false
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
 pub enum LinkRenderStage {
     RenderUpdate,
 }
-pub struct DatalinkPlugin;
+#[derive(Default)]
+pub struct DatalinkPlugin {
+    /// algorithm computing the primary path of the data links
+    pub routing: RoutingKind,
+}
 impl Plugin for DatalinkPlugin {
     fn build(&self, app: &mut App) {
+        app.insert_resource(Router::new(self.routing));
+        app.init_resource::<TopologyConfig>();
+        app.init_resource::<SatGraph>();
+        app.add_event::<FailoverEvent>();
         app.add_systems(
             PostUpdate,
-            (init_gslinks, init_links, init_data_link, update_data_link)
+            (
+                init_gslinks,
+                init_links,
+                init_data_link,
+                update_data_link,
+                update_backup_shape,
+            )
                 .in_set(LinkRenderStage::RenderUpdate)
                 .chain(),
         );
 
         app.configure_sets(
             Update,
-            LinkRenderStage::RenderUpdate.after(SatRenderStage::SatRenderUpdate),
+            LinkRenderStage::RenderUpdate
+                .after(SatRenderStage::SatRenderUpdate)
+                .after(PropagationSet),
+        );
+        app.init_resource::<RebuildSchedule>();
+        app.init_resource::<LiveContacts>();
+        app.add_systems(
+            Update,
+            refresh_contacts
+                .after(PropagationSet)
+                .before(build_sat_graph),
         );
         app.add_systems(
             Update,
-            rebuild_gslinks.run_if(on_real_timer(Duration::from_secs_f32(10.0))),
+            (build_sat_graph, rebuild_gslinks)
+                .chain()
+                .after(PropagationSet)
+                .before(LinkRenderStage::RenderUpdate)
+                .run_if(rebuild_due),
         );
         // .with_system(
         //     rebuild_gslinks
@@ -271,7 +775,11 @@ impl Plugin for DatalinkPlugin {
 
         app.add_systems(
             Update,
-            compute_latency.in_set(LinkRenderStage::RenderUpdate),
+            (failover_gslinks, compute_latency)
+                .chain()
+                .in_set(LinkRenderStage::RenderUpdate),
         );
+        app.init_resource::<LinkHistoryConfig>();
+        app.add_systems(PostUpdate, record_history);
     }
 }
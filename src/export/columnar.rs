@@ -0,0 +1,323 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{
+        ArrayBuilder, ArrayRef, BooleanBuilder, Float64Builder, ListBuilder, StringBuilder,
+        TimestampMillisecondArray, UInt32Builder, UInt64Builder,
+    },
+    datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    ipc::writer::FileWriter,
+    record_batch::RecordBatch,
+};
+use chrono::{DateTime, Utc};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+
+use super::{route_links, sample_times};
+use crate::{
+    celestrak::{propagate_sat_at, teme_to_lla},
+    routing::RoutingKind,
+    topology::{Scenario, TopologyConfig},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    /// Arrow IPC file (Feather v2), one record batch per sample time
+    ArrowIpc,
+    /// Parquet, one row group per sample time
+    #[default]
+    Parquet,
+}
+
+impl ColumnarFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ColumnarFormat::ArrowIpc => "arrow",
+            ColumnarFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Settings of the columnar export of satellite states and link statistics.
+#[derive(Clone, Debug)]
+pub struct ColumnarExport {
+    pub format: ColumnarFormat,
+    /// length of the exported interval from the start time (s)
+    pub duration: f64,
+    /// time between two samples (s)
+    pub step: f64,
+    /// satellites whose name contains this string, all if empty
+    pub sat_filter: String,
+    /// also write the link statistics next to the satellite file
+    pub links: bool,
+}
+
+impl Default for ColumnarExport {
+    fn default() -> Self {
+        Self {
+            format: ColumnarFormat::Parquet,
+            duration: 86400.0,
+            step: 60.0,
+            sat_filter: String::new(),
+            links: true,
+        }
+    }
+}
+
+fn io_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::other(err)
+}
+
+fn time_field() -> Field {
+    Field::new(
+        "time",
+        DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+        false,
+    )
+}
+
+/// Schema of the satellite state table, positions and velocities in TEME.
+pub fn satellite_schema() -> SchemaRef {
+    let f64_field = |name| Field::new(name, DataType::Float64, false);
+    Arc::new(Schema::new(vec![
+        time_field(),
+        Field::new("norad_id", DataType::UInt64, false),
+        Field::new("name", DataType::Utf8, false),
+        f64_field("teme_x_km"),
+        f64_field("teme_y_km"),
+        f64_field("teme_z_km"),
+        f64_field("teme_vx_km_s"),
+        f64_field("teme_vy_km_s"),
+        f64_field("teme_vz_km_s"),
+        f64_field("latitude_deg"),
+        f64_field("longitude_deg"),
+        f64_field("altitude_km"),
+    ]))
+}
+
+/// Schema of the link statistics table, the path columns are null while a
+/// link is down.
+pub fn link_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        time_field(),
+        Field::new("link", DataType::Utf8, false),
+        Field::new("connected", DataType::Boolean, false),
+        Field::new("latency_ms", DataType::Float64, true),
+        Field::new("distance_km", DataType::Float64, true),
+        Field::new("hops", DataType::UInt32, true),
+        Field::new(
+            "norad_ids",
+            DataType::List(Arc::new(Field::new("item", DataType::UInt64, true))),
+            true,
+        ),
+    ]))
+}
+
+/// Writes record batches to an Arrow IPC or Parquet file.
+enum BatchWriter {
+    Ipc(FileWriter<BufWriter<File>>),
+    Parquet(ArrowWriter<File>),
+}
+
+impl BatchWriter {
+    fn create(path: &Path, format: ColumnarFormat, schema: SchemaRef) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            ColumnarFormat::ArrowIpc => BatchWriter::Ipc(
+                FileWriter::try_new(BufWriter::new(file), &schema).map_err(io_error)?,
+            ),
+            ColumnarFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                BatchWriter::Parquet(
+                    ArrowWriter::try_new(file, schema, Some(props)).map_err(io_error)?,
+                )
+            }
+        })
+    }
+
+    /// Writes one sample time, in Parquet the batch is closed as its own row group.
+    fn write(&mut self, batch: &RecordBatch) -> io::Result<()> {
+        match self {
+            BatchWriter::Ipc(w) => w.write(batch).map_err(io_error),
+            BatchWriter::Parquet(w) => {
+                w.write(batch).map_err(io_error)?;
+                w.flush().map_err(io_error)
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            BatchWriter::Ipc(mut w) => w.finish().map_err(io_error),
+            BatchWriter::Parquet(w) => w.close().map(|_| ()).map_err(io_error),
+        }
+    }
+}
+
+/// `states.parquet` -> `states_links.parquet`
+pub fn links_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{}_links", stem);
+    if let Some(ext) = path.extension() {
+        name = format!("{}.{}", name, ext.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+impl ColumnarExport {
+    /**
+    Writes the satellite states sampled with SGP4 to `path`, one batch per
+    sample time so the file can be streamed and filtered by time. Satellites
+    that diverge at a sample are skipped for that sample. Returns the number
+    of rows.
+    */
+    pub fn write_satellites(
+        &self,
+        path: &Path,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+    ) -> io::Result<usize> {
+        let sats: Vec<_> = scenario
+            .sats
+            .iter()
+            .filter(|s| self.sat_filter.is_empty() || s.name.contains(&self.sat_filter))
+            .collect();
+        let schema = satellite_schema();
+        let mut writer = BatchWriter::create(path, self.format, schema.clone())?;
+        let mut rows = 0;
+        for t in sample_times(start, self.duration, self.step) {
+            let mut norad = UInt64Builder::with_capacity(sats.len());
+            let mut name = StringBuilder::new();
+            let mut columns: Vec<Float64Builder> = (0..9)
+                .map(|_| Float64Builder::with_capacity(sats.len()))
+                .collect();
+            for sat in &sats {
+                let Ok((pos, vel)) = propagate_sat_at(&sat.epoch, &sat.constants, &t) else {
+                    continue;
+                };
+                let (lat, lon, alt) = teme_to_lla(&pos.0, &t);
+                norad.append_value(sat.norad_id);
+                name.append_value(&sat.name);
+                let values = [
+                    pos.0[0], pos.0[1], pos.0[2], vel.0[0], vel.0[1], vel.0[2], lat, lon, alt,
+                ];
+                for (column, v) in columns.iter_mut().zip(values) {
+                    column.append_value(v);
+                }
+            }
+            let n = norad.len();
+            let mut arrays: Vec<ArrayRef> = vec![
+                Arc::new(
+                    TimestampMillisecondArray::from(vec![t.timestamp_millis(); n])
+                        .with_timezone("UTC"),
+                ),
+                Arc::new(norad.finish()),
+                Arc::new(name.finish()),
+            ];
+            arrays.extend(columns.iter_mut().map(|c| Arc::new(c.finish()) as ArrayRef));
+            let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(io_error)?;
+            writer.write(&batch)?;
+            rows += n;
+        }
+        writer.finish()?;
+        Ok(rows)
+    }
+
+    /// Writes the route of every scenario link at each sample time to `path`.
+    pub fn write_links(
+        &self,
+        path: &Path,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> io::Result<usize> {
+        let times = sample_times(start, self.duration, self.step);
+        let tracks = route_links(scenario, routing, &times, topology, min_elevation);
+        let norad: std::collections::HashMap<_, _> = scenario
+            .sats
+            .iter()
+            .map(|s| (s.entity, s.norad_id))
+            .collect();
+        let schema = link_schema();
+        let mut writer = BatchWriter::create(path, self.format, schema.clone())?;
+        let mut rows = 0;
+        for (i, t) in times.iter().enumerate() {
+            let mut link = StringBuilder::new();
+            let mut connected = BooleanBuilder::with_capacity(tracks.len());
+            let mut latency = Float64Builder::with_capacity(tracks.len());
+            let mut distance = Float64Builder::with_capacity(tracks.len());
+            let mut hops = UInt32Builder::with_capacity(tracks.len());
+            let mut ids = ListBuilder::new(UInt64Builder::new());
+            for track in &tracks {
+                link.append_value(&track.name);
+                let route = track.routes[i].as_ref();
+                connected.append_value(route.is_some());
+                latency.append_option(route.map(|r| 1e3 * r.delay));
+                distance.append_option(route.map(|r| r.path.cost / 1000.0));
+                hops.append_option(route.map(|r| r.path.nodes.len().saturating_sub(1) as u32));
+                match route {
+                    Some(r) => {
+                        for node in &r.path.nodes {
+                            if let Some(id) = norad.get(node) {
+                                ids.values().append_value(*id);
+                            }
+                        }
+                        ids.append(true);
+                    }
+                    None => ids.append(false),
+                }
+            }
+            let n = tracks.len();
+            let arrays: Vec<ArrayRef> = vec![
+                Arc::new(
+                    TimestampMillisecondArray::from(vec![t.timestamp_millis(); n])
+                        .with_timezone("UTC"),
+                ),
+                Arc::new(link.finish()),
+                Arc::new(connected.finish()),
+                Arc::new(latency.finish()),
+                Arc::new(distance.finish()),
+                Arc::new(hops.finish()),
+                Arc::new(ids.finish()),
+            ];
+            let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(io_error)?;
+            writer.write(&batch)?;
+            rows += n;
+        }
+        writer.finish()?;
+        Ok(rows)
+    }
+
+    /// Writes the satellite states to `path` and, with `links`, the link
+    /// statistics to [`links_path`]. Returns the number of rows of both files.
+    pub fn save(
+        &self,
+        path: &Path,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> io::Result<usize> {
+        let mut rows = self.write_satellites(path, scenario, start)?;
+        if self.links && !scenario.links.is_empty() {
+            rows += self.write_links(
+                &links_path(path),
+                scenario,
+                start,
+                routing,
+                topology,
+                min_elevation,
+            )?;
+        }
+        Ok(rows)
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Utc};
+
+use super::{iso, sample_times};
+use crate::{
+    celestrak::{propagate_sat_at, teme_to_lla},
+    topology::ScenarioSat,
+};
+
+/// A column group of the satellite CSV export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsvField {
+    /// ISO 8601 sample time
+    Time,
+    /// sample time as unix timestamp
+    Timestamp,
+    EntityId,
+    NoradId,
+    Name,
+    /// three columns
+    TemePosition,
+    /// three columns
+    TemeVelocity,
+    Latitude,
+    Longitude,
+    Altitude,
+    Inclination,
+}
+
+impl CsvField {
+    pub const ALL: [CsvField; 11] = [
+        CsvField::Time,
+        CsvField::Timestamp,
+        CsvField::EntityId,
+        CsvField::NoradId,
+        CsvField::Name,
+        CsvField::TemePosition,
+        CsvField::TemeVelocity,
+        CsvField::Latitude,
+        CsvField::Longitude,
+        CsvField::Altitude,
+        CsvField::Inclination,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            CsvField::Time => "time",
+            CsvField::Timestamp => "timestamp",
+            CsvField::EntityId => "entity ID",
+            CsvField::NoradId => "NORAD ID",
+            CsvField::Name => "name",
+            CsvField::TemePosition => "TEME position",
+            CsvField::TemeVelocity => "TEME velocity",
+            CsvField::Latitude => "latitude",
+            CsvField::Longitude => "longitude",
+            CsvField::Altitude => "altitude",
+            CsvField::Inclination => "inclination",
+        }
+    }
+
+    /// Column names, the coordinate names match the older exports read by the
+    /// scripts in `Starlink/`.
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            CsvField::Time => &["Time"],
+            CsvField::Timestamp => &["Timestamp"],
+            CsvField::EntityId => &["EntityID"],
+            CsvField::NoradId => &["NoradID"],
+            CsvField::Name => &["Name"],
+            CsvField::TemePosition => &["TemeCoord1", "TemeCoord2", "TemeCoord3"],
+            CsvField::TemeVelocity => &["TemeVel1", "TemeVel2", "TemeVel3"],
+            CsvField::Latitude => &["Latitude"],
+            CsvField::Longitude => &["Longitude"],
+            CsvField::Altitude => &["Altitude"],
+            CsvField::Inclination => &["Inclination"],
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            CsvField::Time => "UTC",
+            CsvField::Timestamp => "s",
+            CsvField::TemePosition | CsvField::Altitude => "km",
+            CsvField::TemeVelocity => "km/s",
+            CsvField::Latitude | CsvField::Longitude | CsvField::Inclination => "deg",
+            CsvField::EntityId | CsvField::NoradId | CsvField::Name => "",
+        }
+    }
+}
+
+/// Where the units of the columns are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnitStyle {
+    /// plain column names, readable by the existing scripts
+    #[default]
+    None,
+    /// `Altitude [km]`
+    Header,
+    /// a second header row with the units
+    Row,
+}
+
+/// Settings of the satellite CSV export.
+#[derive(Clone, Debug)]
+pub struct CsvExport {
+    pub fields: Vec<CsvField>,
+    /// length of the exported interval from the start time (s), 0 for a single sample
+    pub duration: f64,
+    /// time between two samples (s)
+    pub step: f64,
+    pub units: UnitStyle,
+    /// decimals of the floating point columns
+    pub precision: usize,
+}
+
+impl Default for CsvExport {
+    fn default() -> Self {
+        Self {
+            fields: vec![
+                CsvField::EntityId,
+                CsvField::NoradId,
+                CsvField::Name,
+                CsvField::TemePosition,
+                CsvField::TemeVelocity,
+                CsvField::Latitude,
+                CsvField::Longitude,
+                CsvField::Altitude,
+            ],
+            duration: 0.0,
+            step: 60.0,
+            units: UnitStyle::None,
+            precision: 6,
+        }
+    }
+}
+
+/// Quotes a field if it contains a separator, a quote or a line break.
+pub(crate) fn escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl CsvExport {
+    fn write_header<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let names: Vec<String> = self
+            .fields
+            .iter()
+            .flat_map(|f| {
+                f.columns().iter().map(move |c| match self.units {
+                    UnitStyle::Header if !f.unit().is_empty() => format!("{} [{}]", c, f.unit()),
+                    _ => c.to_string(),
+                })
+            })
+            .collect();
+        writeln!(w, "{}", names.join(","))?;
+        if self.units == UnitStyle::Row {
+            let units: Vec<&str> = self
+                .fields
+                .iter()
+                .flat_map(|f| f.columns().iter().map(|_| f.unit()))
+                .collect();
+            writeln!(w, "{}", units.join(","))?;
+        }
+        Ok(())
+    }
+
+    /**
+    Writes one row per satellite and sample time, starting at `start`. The
+    satellites are propagated with SGP4 at every sample, satellites that
+    diverge at a sample are skipped for that sample. Returns the number of rows.
+    */
+    pub fn write<W: Write>(
+        &self,
+        w: &mut W,
+        sats: &[ScenarioSat],
+        start: DateTime<Utc>,
+    ) -> io::Result<usize> {
+        self.write_header(w)?;
+        let p = self.precision;
+        let mut rows = 0;
+        for t in sample_times(start, self.duration, self.step) {
+            for sat in sats {
+                let Ok((pos, vel)) = propagate_sat_at(&sat.epoch, &sat.constants, &t) else {
+                    continue;
+                };
+                let lla = teme_to_lla(&pos.0, &t);
+                let mut cells: Vec<String> = Vec::new();
+                for field in &self.fields {
+                    match field {
+                        CsvField::Time => cells.push(iso(&t)),
+                        CsvField::Timestamp => {
+                            cells.push(format!("{:.3}", t.timestamp_millis() as f64 / 1e3))
+                        }
+                        CsvField::EntityId => cells.push(sat.entity.index().to_string()),
+                        CsvField::NoradId => cells.push(sat.norad_id.to_string()),
+                        CsvField::Name => cells.push(escape(&sat.name)),
+                        CsvField::TemePosition => {
+                            cells.extend(pos.0.iter().map(|v| format!("{:.*}", p, v)))
+                        }
+                        CsvField::TemeVelocity => {
+                            cells.extend(vel.0.iter().map(|v| format!("{:.*}", p, v)))
+                        }
+                        CsvField::Latitude => cells.push(format!("{:.*}", p, lla.0)),
+                        CsvField::Longitude => cells.push(format!("{:.*}", p, lla.1)),
+                        CsvField::Altitude => cells.push(format!("{:.*}", p, lla.2)),
+                        CsvField::Inclination => cells.push(format!("{:.*}", p, sat.inclination)),
+                    }
+                }
+                writeln!(w, "{}", cells.join(","))?;
+                rows += 1;
+            }
+        }
+        Ok(rows)
+    }
+
+    pub fn save(
+        &self,
+        path: &std::path::Path,
+        sats: &[ScenarioSat],
+        start: DateTime<Utc>,
+    ) -> io::Result<usize> {
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        let rows = self.write(&mut w, sats, start)?;
+        w.flush()?;
+        Ok(rows)
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use super::{iso, route_links, sample_times, LinkTrack};
+use crate::{
+    routing::RoutingKind,
+    topology::{Scenario, TopologyConfig},
+};
+
+/// Settings of the CZML export.
+#[derive(Clone, Debug)]
+pub struct CzmlExport {
+    /// length of the exported interval (s)
+    pub duration: f64,
+    /// time between two position samples (s)
+    pub step: f64,
+    /// only satellites whose name contains this string, all if empty.
+    /// Satellites on a link path are always exported.
+    pub sat_filter: String,
+    pub links: bool,
+}
+
+impl Default for CzmlExport {
+    fn default() -> Self {
+        Self {
+            duration: 3600.0,
+            step: 60.0,
+            sat_filter: String::new(),
+            links: true,
+        }
+    }
+}
+
+fn rgba(c: [u8; 4]) -> Value {
+    json!({ "rgba": c })
+}
+
+fn interval(a: &DateTime<Utc>, b: &DateTime<Utc>) -> String {
+    format!("{}/{}", iso(a), iso(b))
+}
+
+/// Seconds from `epoch`, the time tag of CZML samples.
+fn offset(epoch: &DateTime<Utc>, t: &DateTime<Utc>) -> f64 {
+    (*t - *epoch).num_milliseconds() as f64 / 1e3
+}
+
+/// Link packets: a parent with the sampled latency and hop count, and one
+/// polyline per path with the interval in which it is used. The polylines
+/// reference the position of their nodes, so they follow the satellites.
+fn link_packets(
+    track: &LinkTrack,
+    times: &[DateTime<Utc>],
+    node_id: &HashMap<Entity, String>,
+) -> Vec<Value> {
+    let (start, end) = (times[0], times[times.len() - 1]);
+    let parent = format!("link/{}", track.name);
+    let mut latency = Vec::new();
+    let mut hops = Vec::new();
+    for (t, route) in times.iter().zip(&track.routes) {
+        if let Some(route) = route {
+            latency.extend([offset(&start, t), 1e3 * route.delay]);
+            hops.extend([offset(&start, t), route.path.nodes.len().saturating_sub(1) as f64]);
+        }
+    }
+    let mut packets = vec![json!({
+        "id": parent,
+        "name": track.name,
+        "properties": {
+            "latency_ms": { "epoch": iso(&start), "number": latency },
+            "hops": { "epoch": iso(&start), "number": hops },
+        },
+    })];
+
+    let mut i = 0;
+    while i < times.len() {
+        let Some(route) = &track.routes[i] else {
+            i += 1;
+            continue;
+        };
+        let mut j = i + 1;
+        while j < times.len()
+            && track.routes[j]
+                .as_ref()
+                .is_some_and(|r| r.path.nodes == route.path.nodes)
+        {
+            j += 1;
+        }
+        let until = times.get(j).copied().unwrap_or(end);
+        let refs: Vec<String> = route
+            .path
+            .nodes
+            .iter()
+            .filter_map(|n| node_id.get(n).map(|id| format!("{}#position", id)))
+            .collect();
+        packets.push(json!({
+            "id": format!("{}/{}", parent, packets.len()),
+            "parent": parent,
+            "name": track.name,
+            "availability": interval(&times[i], &until),
+            "description": format!(
+                "{} hops, {:.2} ms at {}",
+                route.path.nodes.len().saturating_sub(1),
+                1e3 * route.delay,
+                iso(&times[i])
+            ),
+            "polyline": {
+                "positions": { "references": refs },
+                "width": 2,
+                "arcType": "NONE",
+                "material": { "solidColor": { "color": rgba([0, 255, 0, 255]) } },
+            },
+        }));
+        i = j;
+    }
+    packets
+}
+
+impl CzmlExport {
+    /**
+    Builds the CZML document: satellite positions sampled with SGP4 in the
+    Earth fixed frame, ground stations, and when `links` is set the data link
+    paths computed with `routing` at every sample.
+    */
+    pub fn document(
+        &self,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> Vec<Value> {
+        let times = sample_times(start, self.duration, self.step);
+        let end = times[times.len() - 1];
+        let tracks = if self.links {
+            route_links(scenario, routing, &times, topology, min_elevation)
+        } else {
+            Vec::new()
+        };
+
+        let mut node_id: HashMap<Entity, String> = HashMap::new();
+        node_id.extend(scenario.ground.iter().map(|g| (g.entity, format!("gs/{}", g.id))));
+        node_id.extend(scenario.sats.iter().map(|s| (s.entity, format!("sat/{}", s.norad_id))));
+        let on_path: HashSet<Entity> = tracks
+            .iter()
+            .flat_map(|t| t.routes.iter().flatten())
+            .flat_map(|r| r.path.nodes.iter().copied())
+            .collect();
+        let exported: HashSet<Entity> = scenario
+            .sats
+            .iter()
+            .filter(|s| {
+                self.sat_filter.is_empty()
+                    || s.name.contains(&self.sat_filter)
+                    || on_path.contains(&s.entity)
+            })
+            .map(|s| s.entity)
+            .collect();
+
+        let mut cartesian: HashMap<Entity, Vec<f64>> = HashMap::new();
+        for t in &times {
+            for (e, p) in scenario.sat_positions(t) {
+                if exported.contains(&e) {
+                    cartesian
+                        .entry(e)
+                        .or_default()
+                        .extend([offset(&start, t), p[0], p[1], p[2]]);
+                }
+            }
+        }
+
+        let mut packets = vec![json!({
+            "id": "document",
+            "name": "RustSat",
+            "version": "1.0",
+            "clock": {
+                "interval": interval(&start, &end),
+                "currentTime": iso(&start),
+                "multiplier": 60,
+                "range": "LOOP_STOP",
+                "step": "SYSTEM_CLOCK_MULTIPLIER",
+            },
+        })];
+        for gs in &scenario.ground {
+            let name = gs.name.replace('\n', " ");
+            packets.push(json!({
+                "id": node_id[&gs.entity],
+                "name": name,
+                "properties": { "id": gs.id, "antennas": gs.antennas },
+                "position": { "cartographicDegrees": [gs.lla.1, gs.lla.0, gs.lla.2] },
+                "point": { "pixelSize": 8, "color": rgba([255, 255, 0, 255]) },
+                "label": {
+                    "text": name,
+                    "font": "11pt sans-serif",
+                    "pixelOffset": { "cartesian2": [0, 16] },
+                },
+            }));
+        }
+        for sat in scenario.sats.iter().filter(|s| exported.contains(&s.entity)) {
+            let Some(samples) = cartesian.remove(&sat.entity) else {
+                continue;
+            };
+            packets.push(json!({
+                "id": node_id[&sat.entity],
+                "name": sat.name,
+                "availability": interval(&start, &end),
+                "properties": { "norad_id": sat.norad_id },
+                "position": {
+                    "epoch": iso(&start),
+                    "referenceFrame": "FIXED",
+                    "interpolationAlgorithm": "LAGRANGE",
+                    "interpolationDegree": 5,
+                    "cartesian": samples,
+                },
+                "point": { "pixelSize": 3, "color": rgba([0, 255, 202, 255]) },
+            }));
+        }
+        for track in &tracks {
+            packets.extend(link_packets(track, &times, &node_id));
+        }
+        packets
+    }
+
+    pub fn save(
+        &self,
+        path: &std::path::Path,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> io::Result<()> {
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        let doc = self.document(scenario, start, routing, topology, min_elevation);
+        serde_json::to_writer(&mut w, &doc)?;
+        w.flush()
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use map_3d::EARTH_RADIUS;
+use serde_json::{json, Map, Value};
+
+use super::{iso, route_links, sample_times};
+use crate::{
+    celestrak::{propagate_sat_at, teme_to_lla},
+    routing::RoutingKind,
+    topology::{Scenario, ScenarioSat, TopologyConfig},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GeoFormat {
+    #[default]
+    GeoJson,
+    Kml,
+}
+
+/// Settings of the GeoJSON and KML export.
+#[derive(Clone, Debug)]
+pub struct GeoExport {
+    pub format: GeoFormat,
+    /// satellites whose name contains this string, all if empty. Used for the
+    /// positions, ground tracks and footprints.
+    pub sat_filter: String,
+    pub satellites: bool,
+    pub ground_stations: bool,
+    pub links: bool,
+    pub ground_tracks: bool,
+    /// length of the ground tracks from the start time (s)
+    pub track_duration: f64,
+    /// (s)
+    pub track_step: f64,
+    pub footprints: bool,
+    /// points on a footprint boundary
+    pub footprint_points: usize,
+}
+
+impl Default for GeoExport {
+    fn default() -> Self {
+        Self {
+            format: GeoFormat::GeoJson,
+            sat_filter: String::new(),
+            satellites: true,
+            ground_stations: true,
+            links: true,
+            ground_tracks: false,
+            track_duration: 5400.0,
+            track_step: 30.0,
+            footprints: false,
+            footprint_points: 72,
+        }
+    }
+}
+
+/// (lon deg, lat deg, alt m), the GeoJSON axis order.
+pub type Position = [f64; 3];
+
+pub enum Geometry {
+    Point(Position),
+    MultiLineString(Vec<Vec<Position>>),
+    /// outer rings only, (lon, lat)
+    MultiPolygon(Vec<Vec<[f64; 2]>>),
+}
+
+pub struct GeoFeature {
+    pub name: String,
+    pub geometry: Geometry,
+    /// includes `kind`: satellite, ground_station, link, ground_track or footprint
+    pub properties: Map<String, Value>,
+}
+
+/// Linear interpolation of the latitude and altitude where `a`-`b` meets `lon`.
+fn crossing(a: &Position, b: &Position, lon: f64) -> Position {
+    let t = if b[0] == a[0] {
+        0.0
+    } else {
+        (lon - a[0]) / (b[0] - a[0])
+    };
+    [lon, a[1] + t * (b[1] - a[1]), a[2] + t * (b[2] - a[2])]
+}
+
+/**
+Splits a line where it crosses the antimeridian. Two consecutive points more
+than 180° of longitude apart are taken to go the short way round, the line
+ends at ±180° and continues from ∓180°.
+*/
+pub fn split_antimeridian(points: &[Position]) -> Vec<Vec<Position>> {
+    let mut parts: Vec<Vec<Position>> = Vec::new();
+    let mut current: Vec<Position> = Vec::new();
+    for p in points {
+        if let Some(last) = current.last().copied() {
+            let d = p[0] - last[0];
+            if d.abs() > 180.0 {
+                // the edge in unwrapped longitude, then its crossing of ±180
+                let side = if d > 0.0 { -180.0 } else { 180.0 };
+                let unwrapped = [p[0] - 2.0 * side, p[1], p[2]];
+                let c = crossing(&last, &unwrapped, side);
+                current.push(c);
+                parts.push(std::mem::take(&mut current));
+                current.push([-side, c[1], c[2]]);
+            }
+        }
+        current.push(*p);
+    }
+    if current.len() > 1 {
+        parts.push(current);
+    }
+    parts.retain(|p| p.len() > 1);
+    parts
+}
+
+/// Sutherland-Hodgman clipping of a ring to `lon >= min` and `lon <= max`.
+fn clip_ring(ring: &[[f64; 2]], min: f64, max: f64) -> Vec<[f64; 2]> {
+    let clip = |ring: Vec<[f64; 2]>, inside: &dyn Fn(f64) -> bool, edge: f64| {
+        let mut out = Vec::new();
+        for (i, p) in ring.iter().enumerate() {
+            let q = ring[(i + 1) % ring.len()];
+            let cut = |a: [f64; 2], b: [f64; 2]| {
+                let c = crossing(&[a[0], a[1], 0.0], &[b[0], b[1], 0.0], edge);
+                [c[0], c[1]]
+            };
+            match (inside(p[0]), inside(q[0])) {
+                (true, true) => out.push(q),
+                (true, false) => out.push(cut(*p, q)),
+                (false, true) => {
+                    out.push(cut(*p, q));
+                    out.push(q);
+                }
+                (false, false) => {}
+            }
+        }
+        out
+    };
+    let ring = clip(ring.to_vec(), &|lon| lon >= min, min);
+    if ring.is_empty() {
+        return ring;
+    }
+    clip(ring, &|lon| lon <= max, max)
+}
+
+fn close(mut ring: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+    if let (Some(first), Some(last)) = (ring.first().copied(), ring.last()) {
+        if first != *last {
+            ring.push(first);
+        }
+    }
+    ring
+}
+
+/**
+Coverage area of a satellite at (lat, lon deg, alt km) for a minimum
+elevation (deg), on a spherical Earth. The boundary is split at the
+antimeridian, and a footprint containing a pole is closed along the pole.
+*/
+pub fn footprint(lat: f64, lon: f64, alt: f64, min_elevation: f64, n: usize) -> Vec<Vec<[f64; 2]>> {
+    let r = EARTH_RADIUS / 1000.0;
+    let eps = min_elevation.to_radians();
+    // earth central angle of the coverage circle
+    let lambda = ((r / (r + alt)) * eps.cos()).acos() - eps;
+    let (phi0, lam0) = (lat.to_radians(), lon.to_radians());
+    let mut ring: Vec<[f64; 2]> = (0..n.max(8))
+        .map(|i| {
+            let bearing = std::f64::consts::TAU * i as f64 / n.max(8) as f64;
+            let phi =
+                (phi0.sin() * lambda.cos() + phi0.cos() * lambda.sin() * bearing.cos()).asin();
+            let lam = lam0
+                + (bearing.sin() * lambda.sin() * phi0.cos())
+                    .atan2(lambda.cos() - phi0.sin() * phi.sin());
+            [lam.to_degrees(), phi.to_degrees()]
+        })
+        .collect();
+    // unwrap the longitudes so the ring is continuous
+    for i in 1..ring.len() {
+        let d = ring[i][0] - ring[i - 1][0];
+        ring[i][0] -= 360.0 * (d / 360.0).round();
+    }
+    // longitude covered by one turn along the boundary, ±360 around a pole
+    let (first, last) = (ring[0][0], ring[ring.len() - 1][0]);
+    let closing = (first - last) - 360.0 * ((first - last) / 360.0).round();
+    if (last - first + closing).abs() > 180.0 {
+        // the circle winds around a pole, each longitude meets the boundary once
+        let pole = if lat >= 0.0 { 90.0 } else { -90.0 };
+        let mut boundary: Vec<[f64; 2]> = ring
+            .iter()
+            .map(|p| [(p[0] + 180.0).rem_euclid(360.0) - 180.0, p[1]])
+            .collect();
+        boundary.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        let (first, last) = (boundary[0], boundary[boundary.len() - 1]);
+        // latitude at ±180 between the last and the first point
+        let span = first[0] + 360.0 - last[0];
+        let t = if span > 0.0 {
+            (180.0 - last[0]) / span
+        } else {
+            0.0
+        };
+        let edge = last[1] + t * (first[1] - last[1]);
+        let mut polygon = vec![[-180.0, pole], [-180.0, edge]];
+        polygon.extend(boundary);
+        polygon.extend([[180.0, edge], [180.0, pole]]);
+        return vec![close(polygon)];
+    }
+    [-360.0, 0.0, 360.0]
+        .into_iter()
+        .map(|shift| {
+            let shifted: Vec<[f64; 2]> = ring.iter().map(|p| [p[0] + shift, p[1]]).collect();
+            clip_ring(&shifted, -180.0, 180.0)
+        })
+        .filter(|r| r.len() >= 3)
+        .map(close)
+        .collect()
+}
+
+fn properties(kind: &str, values: Value) -> Map<String, Value> {
+    let mut map = Map::new();
+    map.insert("kind".into(), kind.into());
+    if let Value::Object(values) = values {
+        map.extend(values);
+    }
+    map
+}
+
+/// (lat deg, lon deg, alt km) of a satellite at `t`.
+fn sat_lla(sat: &ScenarioSat, t: &DateTime<Utc>) -> Option<(f64, f64, f64)> {
+    let (pos, _) = propagate_sat_at(&sat.epoch, &sat.constants, t).ok()?;
+    Some(teme_to_lla(&pos.0, t))
+}
+
+impl GeoExport {
+    /// Features of the scenario at `start`, the link paths are computed with `routing`.
+    pub fn features(
+        &self,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> Vec<GeoFeature> {
+        let mut features = Vec::new();
+        let sats: Vec<&ScenarioSat> = scenario
+            .sats
+            .iter()
+            .filter(|s| self.sat_filter.is_empty() || s.name.contains(&self.sat_filter))
+            .collect();
+
+        if self.ground_stations {
+            for gs in &scenario.ground {
+                features.push(GeoFeature {
+                    name: gs.name.replace('\n', " "),
+                    geometry: Geometry::Point([gs.lla.1, gs.lla.0, gs.lla.2]),
+                    properties: properties(
+                        "ground_station",
+                        json!({ "id": gs.id, "antennas": gs.antennas }),
+                    ),
+                });
+            }
+        }
+        for sat in &sats {
+            let Some((lat, lon, alt)) = sat_lla(sat, &start) else {
+                continue;
+            };
+            let common = json!({
+                "norad_id": sat.norad_id,
+                "satellite": sat.name,
+                "altitude_km": alt,
+                "time": iso(&start),
+            });
+            if self.satellites {
+                features.push(GeoFeature {
+                    name: sat.name.clone(),
+                    geometry: Geometry::Point([lon, lat, 1000.0 * alt]),
+                    properties: properties("satellite", common.clone()),
+                });
+            }
+            if self.footprints {
+                let mut p = properties("footprint", common.clone());
+                p.insert("min_elevation".into(), min_elevation.into());
+                features.push(GeoFeature {
+                    name: format!("{} footprint", sat.name),
+                    geometry: Geometry::MultiPolygon(footprint(
+                        lat,
+                        lon,
+                        alt,
+                        min_elevation,
+                        self.footprint_points,
+                    )),
+                    properties: p,
+                });
+            }
+            if self.ground_tracks {
+                let times = sample_times(start, self.track_duration, self.track_step);
+                let track: Vec<Position> = times
+                    .iter()
+                    .filter_map(|t| sat_lla(sat, t))
+                    .map(|(lat, lon, alt)| [lon, lat, 1000.0 * alt])
+                    .collect();
+                let mut p = properties("ground_track", common);
+                p.insert("end".into(), iso(&times[times.len() - 1]).into());
+                p.insert("step".into(), self.track_step.into());
+                features.push(GeoFeature {
+                    name: format!("{} ground track", sat.name),
+                    geometry: Geometry::MultiLineString(split_antimeridian(&track)),
+                    properties: p,
+                });
+            }
+        }
+        if self.links {
+            let mut position: HashMap<Entity, Position> = scenario
+                .ground
+                .iter()
+                .map(|g| (g.entity, [g.lla.1, g.lla.0, g.lla.2]))
+                .collect();
+            let mut norad: HashMap<Entity, u64> = HashMap::new();
+            for track in route_links(scenario, routing, &[start], topology, min_elevation) {
+                let Some(route) = &track.routes[0] else {
+                    continue;
+                };
+                for node in &route.path.nodes {
+                    if position.contains_key(node) {
+                        continue;
+                    }
+                    if let Some(sat) = scenario.sats.iter().find(|s| s.entity == *node) {
+                        if let Some((lat, lon, alt)) = sat_lla(sat, &start) {
+                            position.insert(*node, [lon, lat, 1000.0 * alt]);
+                            norad.insert(*node, sat.norad_id);
+                        }
+                    }
+                }
+                let line: Vec<Position> = route
+                    .path
+                    .nodes
+                    .iter()
+                    .filter_map(|n| position.get(n).copied())
+                    .collect();
+                let ids: Vec<u64> = route
+                    .path
+                    .nodes
+                    .iter()
+                    .filter_map(|n| norad.get(n).copied())
+                    .collect();
+                features.push(GeoFeature {
+                    name: track.name.clone(),
+                    geometry: Geometry::MultiLineString(split_antimeridian(&line)),
+                    properties: properties(
+                        "link",
+                        json!({
+                            "link": track.name,
+                            "latency_ms": 1e3 * route.delay,
+                            "hops": route.path.nodes.len().saturating_sub(1),
+                            "norad_ids": ids,
+                            "time": iso(&start),
+                        }),
+                    ),
+                });
+            }
+        }
+        features
+    }
+
+    pub fn save(
+        &self,
+        path: &std::path::Path,
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        routing: RoutingKind,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> io::Result<()> {
+        let features = self.features(scenario, start, routing, topology, min_elevation);
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        match self.format {
+            GeoFormat::GeoJson => serde_json::to_writer(&mut w, &to_geojson(&features))?,
+            GeoFormat::Kml => write_kml(&mut w, &features)?,
+        }
+        w.flush()
+    }
+}
+
+/// GeoJSON (RFC 7946) feature collection.
+pub fn to_geojson(features: &[GeoFeature]) -> Value {
+    let features: Vec<Value> = features
+        .iter()
+        .map(|f| {
+            let geometry = match &f.geometry {
+                Geometry::Point(p) => json!({ "type": "Point", "coordinates": p }),
+                Geometry::MultiLineString(lines) => {
+                    json!({ "type": "MultiLineString", "coordinates": lines })
+                }
+                Geometry::MultiPolygon(rings) => json!({
+                    "type": "MultiPolygon",
+                    "coordinates": rings.iter().map(|r| vec![r]).collect::<Vec<_>>(),
+                }),
+            };
+            let mut properties = f.properties.clone();
+            properties.insert("name".into(), f.name.clone().into());
+            json!({ "type": "Feature", "geometry": geometry, "properties": properties })
+        })
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn kml_coordinates(points: impl Iterator<Item = Position>) -> String {
+    points
+        .map(|p| format!("{},{},{}", p[0], p[1], p[2]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// KML 2.2 document with one placemark per feature, styled by kind.
+pub fn write_kml<W: Write>(w: &mut W, features: &[GeoFeature]) -> io::Result<()> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        w,
+        r#"<kml xmlns="http://www.opengis.net/kml/2.2"><Document><name>RustSat</name>"#
+    )?;
+    for (id, line, poly) in [
+        ("satellite", "ffcaff00", "00000000"),
+        ("ground_station", "ff00ffff", "00000000"),
+        ("link", "ff00ff00", "00000000"),
+        ("ground_track", "ffcaff00", "00000000"),
+        ("footprint", "ffcaff00", "4dcaff00"),
+    ] {
+        writeln!(
+            w,
+            "<Style id=\"{}\"><IconStyle><color>{}</color></IconStyle><LineStyle><color>{}</color><width>2</width></LineStyle><PolyStyle><color>{}</color></PolyStyle></Style>",
+            id, line, line, poly
+        )?;
+    }
+    for f in features {
+        let kind = f
+            .properties
+            .get("kind")
+            .and_then(|k| k.as_str())
+            .unwrap_or("");
+        writeln!(
+            w,
+            "<Placemark><name>{}</name><styleUrl>#{}</styleUrl>",
+            xml_escape(&f.name),
+            kind
+        )?;
+        write!(w, "<ExtendedData>")?;
+        for (k, v) in &f.properties {
+            let v = match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            write!(
+                w,
+                "<Data name=\"{}\"><value>{}</value></Data>",
+                xml_escape(k),
+                xml_escape(&v)
+            )?;
+        }
+        writeln!(w, "</ExtendedData>")?;
+        match &f.geometry {
+            Geometry::Point(p) => writeln!(
+                w,
+                "<Point><altitudeMode>absolute</altitudeMode><coordinates>{}</coordinates></Point>",
+                kml_coordinates(std::iter::once(*p))
+            )?,
+            Geometry::MultiLineString(lines) => {
+                write!(w, "<MultiGeometry>")?;
+                for line in lines {
+                    write!(
+                        w,
+                        "<LineString><altitudeMode>absolute</altitudeMode><coordinates>{}</coordinates></LineString>",
+                        kml_coordinates(line.iter().copied())
+                    )?;
+                }
+                writeln!(w, "</MultiGeometry>")?;
+            }
+            Geometry::MultiPolygon(rings) => {
+                write!(w, "<MultiGeometry>")?;
+                for ring in rings {
+                    write!(
+                        w,
+                        "<Polygon><outerBoundaryIs><LinearRing><coordinates>{}</coordinates></LinearRing></outerBoundaryIs></Polygon>",
+                        kml_coordinates(ring.iter().map(|p| [p[0], p[1], 0.0]))
+                    )?;
+                }
+                writeln!(w, "</MultiGeometry>")?;
+            }
+        }
+        writeln!(w, "</Placemark>")?;
+    }
+    writeln!(w, "</Document></kml>")
+}
@@ -0,0 +1,100 @@
+#[cfg(feature = "columnar")]
+pub mod columnar;
+pub mod csv;
+pub mod czml;
+pub mod geo;
+pub mod oem;
+
+use bevy::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{
+    routing::{ContactGraph, Route, RoutingContext, RoutingKind},
+    topology::{SatGraph, Scenario, TopologyConfig},
+};
+
+/// Sample times from `start` over `duration` seconds every `step` seconds,
+/// the end is always included. A zero duration gives the start only.
+pub fn sample_times(start: DateTime<Utc>, duration: f64, step: f64) -> Vec<DateTime<Utc>> {
+    let at = |s: f64| start + chrono::Duration::microseconds((s * 1e6) as i64);
+    if duration <= 0.0 || step <= 0.0 {
+        return vec![start];
+    }
+    let n = (duration / step).floor() as usize;
+    let mut times: Vec<_> = (0..=n).map(|i| at(i as f64 * step)).collect();
+    if (n as f64 * step) < duration {
+        times.push(at(duration));
+    }
+    times
+}
+
+/// ISO 8601 time with milliseconds, as used by the text exports.
+pub fn iso(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Route of a scenario link at every sample time.
+pub struct LinkTrack {
+    pub name: String,
+    pub from: Entity,
+    pub to: Entity,
+    /// one per sample time, `None` while the link has no path
+    pub routes: Vec<Option<Route>>,
+}
+
+/**
+Routes every link of the scenario at the sample times with the given
+algorithm, on topology snapshots rebuilt at each time. Snapshots are only kept
+in memory for contact graph routing, which needs the whole series.
+*/
+pub fn route_links(
+    scenario: &Scenario,
+    kind: RoutingKind,
+    times: &[DateTime<Utc>],
+    topology: &TopologyConfig,
+    min_elevation: f64,
+) -> Vec<LinkTrack> {
+    let mut tracks: Vec<LinkTrack> = scenario
+        .links
+        .iter()
+        .map(|(name, from, to)| LinkTrack {
+            name: name.clone(),
+            from: *from,
+            to: *to,
+            routes: Vec::with_capacity(times.len()),
+        })
+        .collect();
+    let mut algorithm = kind.build();
+    let mut route_all = |t: DateTime<Utc>, graph: &SatGraph, contacts: Option<&ContactGraph>| {
+        let ctx = RoutingContext {
+            graph,
+            time: t,
+            topology,
+            min_elevation,
+            scenario: Some(scenario),
+            contacts,
+        };
+        for track in tracks.iter_mut() {
+            track.routes.push(algorithm.route(&ctx, track.from, track.to));
+        }
+    };
+    if kind == RoutingKind::ContactGraph {
+        let step = times
+            .windows(2)
+            .next()
+            .map_or(0.0, |w| (w[1] - w[0]).num_milliseconds() as f64 / 1e3);
+        let snapshots: Vec<_> = times
+            .iter()
+            .map(|t| (*t, scenario.snapshot(t, topology, min_elevation)))
+            .collect();
+        let contacts = ContactGraph::from_snapshots(&snapshots, step);
+        for (t, graph) in &snapshots {
+            route_all(*t, graph, Some(&contacts));
+        }
+    } else {
+        for t in times {
+            route_all(*t, &scenario.snapshot(t, topology, min_elevation), None);
+        }
+    }
+    tracks
+}
@@ -0,0 +1,273 @@
+use std::io::{self, Write};
+
+use chrono::{DateTime, Utc};
+
+use super::sample_times;
+use crate::{celestrak::propagate_sat_at, topology::ScenarioSat};
+
+/// Earth rotation rate (rad/s)
+const EARTH_ROTATION: f64 = 7.292115146706979e-5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OemFormat {
+    /// keyword = value notation
+    #[default]
+    Kvn,
+    Xml,
+}
+
+/// Reference frame of the exported states.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OemFrame {
+    /// the SGP4 output frame
+    #[default]
+    Teme,
+    /// Earth fixed, rotated from TEME by GMST. Polar motion is neglected.
+    Itrf,
+}
+
+impl OemFrame {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OemFrame::Teme => "TEME",
+            OemFrame::Itrf => "ITRF",
+        }
+    }
+}
+
+/// Settings of the CCSDS Orbit Ephemeris Message export.
+#[derive(Clone, Debug)]
+pub struct OemExport {
+    pub format: OemFormat,
+    pub frame: OemFrame,
+    /// length of the ephemeris from the start time (s)
+    pub span: f64,
+    /// time between two states (s)
+    pub step: f64,
+    pub originator: String,
+}
+
+impl Default for OemExport {
+    fn default() -> Self {
+        Self {
+            format: OemFormat::Kvn,
+            frame: OemFrame::Teme,
+            span: 86400.0,
+            step: 60.0,
+            originator: "RustSat".into(),
+        }
+    }
+}
+
+/// A satellite of the ephemeris, `object_id` is its international designator.
+pub struct OemObject {
+    pub sat: ScenarioSat,
+    pub object_id: Option<String>,
+}
+
+/// State vector (km, km/s) at an epoch.
+struct State {
+    epoch: DateTime<Utc>,
+    pos: [f64; 3],
+    vel: [f64; 3],
+}
+
+/// A metadata block and its states.
+struct Segment<'a> {
+    object: &'a OemObject,
+    states: Vec<State>,
+}
+
+/// Greenwich mean sidereal time (rad), IAU 1982 model with UTC as UT1.
+fn gmst(t: &DateTime<Utc>) -> f64 {
+    let jd = t.timestamp_micros() as f64 / 86400e6 + 2440587.5;
+    let c = (jd - 2451545.0) / 36525.0;
+    let seconds = 67310.54841 + (876600.0 * 3600.0 + 8640184.812866) * c + 0.093104 * c * c
+        - 6.2e-6 * c * c * c;
+    (seconds.rem_euclid(86400.0) / 240.0).to_radians()
+}
+
+/// TEME to Earth fixed, the velocity includes the rotation of the frame.
+fn teme_to_itrf(pos: [f64; 3], vel: [f64; 3], t: &DateTime<Utc>) -> ([f64; 3], [f64; 3]) {
+    let (s, c) = gmst(t).sin_cos();
+    let rotate = |v: [f64; 3]| [c * v[0] + s * v[1], -s * v[0] + c * v[1], v[2]];
+    let p = rotate(pos);
+    let v = rotate(vel);
+    (
+        p,
+        [
+            v[0] + EARTH_ROTATION * p[1],
+            v[1] - EARTH_ROTATION * p[0],
+            v[2],
+        ],
+    )
+}
+
+/// OEM epoch, UTC without a zone designator.
+fn epoch(t: &DateTime<Utc>) -> String {
+    t.format("%Y-%m-%dT%H:%M:%S%.3f").to_string()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl OemExport {
+    fn segments<'a>(&self, objects: &'a [OemObject], start: DateTime<Utc>) -> Vec<Segment<'a>> {
+        let times = sample_times(start, self.span, self.step);
+        objects
+            .iter()
+            .map(|object| {
+                let sat = &object.sat;
+                let states = times
+                    .iter()
+                    .filter_map(|t| {
+                        let (pos, vel) = propagate_sat_at(&sat.epoch, &sat.constants, t).ok()?;
+                        let (pos, vel) = match self.frame {
+                            OemFrame::Teme => (pos.0, vel.0),
+                            OemFrame::Itrf => teme_to_itrf(pos.0, vel.0, t),
+                        };
+                        Some(State { epoch: *t, pos, vel })
+                    })
+                    .collect();
+                Segment { object, states }
+            })
+            .filter(|s: &Segment| !s.states.is_empty())
+            .collect()
+    }
+
+    /// Metadata keywords in the order of CCSDS 502.0-B-2.
+    fn metadata(&self, segment: &Segment) -> Vec<(&'static str, String)> {
+        let object = segment.object;
+        let first = &segment.states[0];
+        let last = &segment.states[segment.states.len() - 1];
+        vec![
+            ("OBJECT_NAME", object.sat.name.clone()),
+            (
+                "OBJECT_ID",
+                object
+                    .object_id
+                    .clone()
+                    .unwrap_or_else(|| object.sat.norad_id.to_string()),
+            ),
+            ("CENTER_NAME", "EARTH".into()),
+            ("REF_FRAME", self.frame.name().into()),
+            ("TIME_SYSTEM", "UTC".into()),
+            ("START_TIME", epoch(&first.epoch)),
+            ("STOP_TIME", epoch(&last.epoch)),
+            ("INTERPOLATION", "LAGRANGE".into()),
+            ("INTERPOLATION_DEGREE", "7".into()),
+        ]
+    }
+
+    fn comment(&self, object: &OemObject) -> String {
+        format!(
+            "SGP4 propagation of the element set of NORAD {} at {}",
+            object.sat.norad_id,
+            object.sat.epoch.format("%Y-%m-%dT%H:%M:%S%.3f")
+        )
+    }
+
+    fn write_kvn<W: Write>(&self, w: &mut W, segments: &[Segment]) -> io::Result<()> {
+        writeln!(w, "CCSDS_OEM_VERS = 2.0")?;
+        writeln!(w, "CREATION_DATE = {}", epoch(&Utc::now()))?;
+        writeln!(w, "ORIGINATOR = {}", self.originator)?;
+        for segment in segments {
+            writeln!(w)?;
+            writeln!(w, "META_START")?;
+            for (key, value) in self.metadata(segment) {
+                writeln!(w, "{:<20} = {}", key, value)?;
+            }
+            writeln!(w, "META_STOP")?;
+            writeln!(w)?;
+            writeln!(w, "COMMENT {}", self.comment(segment.object))?;
+            for s in &segment.states {
+                writeln!(
+                    w,
+                    "{} {:.6} {:.6} {:.6} {:.9} {:.9} {:.9}",
+                    epoch(&s.epoch),
+                    s.pos[0],
+                    s.pos[1],
+                    s.pos[2],
+                    s.vel[0],
+                    s.vel[1],
+                    s.vel[2]
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_xml<W: Write>(&self, w: &mut W, segments: &[Segment]) -> io::Result<()> {
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(w, r#"<oem id="CCSDS_OEM_VERS" version="2.0">"#)?;
+        writeln!(w, "  <header>")?;
+        writeln!(w, "    <CREATION_DATE>{}</CREATION_DATE>", epoch(&Utc::now()))?;
+        writeln!(w, "    <ORIGINATOR>{}</ORIGINATOR>", xml_escape(&self.originator))?;
+        writeln!(w, "  </header>")?;
+        writeln!(w, "  <body>")?;
+        for segment in segments {
+            writeln!(w, "    <segment>")?;
+            writeln!(w, "      <metadata>")?;
+            for (key, value) in self.metadata(segment) {
+                writeln!(w, "        <{0}>{1}</{0}>", key, xml_escape(&value))?;
+            }
+            writeln!(w, "      </metadata>")?;
+            writeln!(w, "      <data>")?;
+            writeln!(
+                w,
+                "        <COMMENT>{}</COMMENT>",
+                xml_escape(&self.comment(segment.object))
+            )?;
+            for s in &segment.states {
+                writeln!(w, "        <stateVector>")?;
+                writeln!(w, "          <EPOCH>{}</EPOCH>", epoch(&s.epoch))?;
+                for (key, v) in ["X", "Y", "Z"].iter().zip(s.pos) {
+                    writeln!(w, "          <{0}>{1:.6}</{0}>", key, v)?;
+                }
+                for (key, v) in ["X_DOT", "Y_DOT", "Z_DOT"].iter().zip(s.vel) {
+                    writeln!(w, "          <{0}>{1:.9}</{0}>", key, v)?;
+                }
+                writeln!(w, "        </stateVector>")?;
+            }
+            writeln!(w, "      </data>")?;
+            writeln!(w, "    </segment>")?;
+        }
+        writeln!(w, "  </body>")?;
+        writeln!(w, "</oem>")
+    }
+
+    /**
+    Writes one OEM segment per satellite with the states from `start` over
+    `span`, propagated with SGP4 from the satellite's constants. Samples at
+    which a satellite diverges are left out, satellites without any state are
+    skipped. Returns the number of segments.
+    */
+    pub fn write<W: Write>(
+        &self,
+        w: &mut W,
+        objects: &[OemObject],
+        start: DateTime<Utc>,
+    ) -> io::Result<usize> {
+        let segments = self.segments(objects, start);
+        match self.format {
+            OemFormat::Kvn => self.write_kvn(w, &segments)?,
+            OemFormat::Xml => self.write_xml(w, &segments)?,
+        }
+        Ok(segments.len())
+    }
+
+    pub fn save(
+        &self,
+        path: &std::path::Path,
+        objects: &[OemObject],
+        start: DateTime<Utc>,
+    ) -> io::Result<usize> {
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        let segments = self.write(&mut w, objects, start)?;
+        w.flush()?;
+        Ok(segments)
+    }
+}
@@ -1,7 +1,7 @@
+use crate::handover::HandoverPlugin;
 use crate::render_satellite::{SatRenderStage, WorldCoord};
-use crate::util::*;
 
-use crate::celestrak::{LatLonAlt, SatID};
+use crate::celestrak::LatLonAlt;
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::*;
 #[derive(Component, Default)]
@@ -14,9 +14,21 @@ pub struct GSConfigs {
     pub visible: Vec<Entity>,
 }
 #[derive(Component)]
+/// Access satellite of a ground station, chosen by its handover policy.
 pub struct NearestSat {
     pub eid: Entity,
     pub distance: f64,
+    pub elevation: f64,
+}
+
+/// Number of antennas of a ground station, each one can track a satellite.
+#[derive(Component)]
+pub struct Antennas(pub usize);
+
+impl Default for Antennas {
+    fn default() -> Self {
+        Self(1)
+    }
 }
 
 #[derive(Bundle)]
@@ -35,32 +47,6 @@ pub struct GroundStationBundle {
 //         });
 //     });
 // }
-pub fn distance_update(
-    mut commands: Commands,
-    mut q: Query<(Entity, &GroundStationID, &LatLonAlt)>,
-    sats: Query<(Entity, &SatID, &LatLonAlt)>,
-) {
-    q.iter_mut().for_each(|(entity, _id, gs_llt)| {
-        let res = sats
-            .iter()
-            .map(|(e, _, llt)| {
-                let dis = distance::ground_space_distance(
-                    (gs_llt.0 .0, gs_llt.0 .1),
-                    (llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2),
-                );
-
-                NearestSat {
-                    eid: e,
-                    distance: dis,
-                }
-            })
-            .min_by(|x, y| x.distance.partial_cmp(&y.distance).unwrap());
-        if res.is_none() {
-            return;
-        }
-        commands.entity(entity).insert(res.unwrap());
-    });
-}
 pub fn print_gs(q: Query<(&GroundStationID, &Transform)>) {
     q.iter().for_each(|(_id, trans)| {
         info!("{}", trans.translation);
@@ -121,7 +107,7 @@ pub struct GSPlugin;
 impl Plugin for GSPlugin {
     fn build(&self, app: &mut App) {
         //app.add_system_to_stage(CoreStage::PreUpdate, distance_init);
-        app.add_systems(PostUpdate, distance_update);
+        app.add_plugins(HandoverPlugin);
         //app.add_systems(print_gs);
         app.add_systems(PreUpdate, shape_ground_station);
         app.add_systems(Update, color_update.in_set(SatRenderStage::SatRenderUpdate));
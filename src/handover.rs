@@ -0,0 +1,621 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::{
+        propagate_sat_at, teme_to_lla, LatLonAlt, SGP4Constants, SatID, SimClock, TLETimeStamp,
+    },
+    groundstation::{Antennas, GroundStationID, NearestSat},
+    util::geometry,
+};
+
+/// Geometry of a satellite as seen from one ground station.
+#[derive(Clone, Debug)]
+pub struct AccessCandidate {
+    pub eid: Entity,
+    /// slant range (m)
+    pub distance: f64,
+    /// elevation above the local horizon (deg)
+    pub elevation: f64,
+    /// seconds until the satellite drops below the elevation mask,
+    /// only filled when the policy asks for it
+    pub remaining_visibility: Option<f64>,
+    /// number of ground stations currently served by this satellite
+    pub load: usize,
+}
+
+/// Strategy used by a ground station to pick its access satellite.
+///
+/// `candidates` only contains satellites above the elevation mask of
+/// [`HandoverConfig`]; `current` is the serving satellite if it is still one of them.
+pub trait HandoverPolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The built-in policy with its parameters, `None` for custom policies.
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        None
+    }
+
+    /// Whether `remaining_visibility` must be predicted for the candidates.
+    /// Prediction propagates SGP4 forward, so only request it when needed.
+    fn needs_visibility(&self) -> bool {
+        false
+    }
+
+    fn select(
+        &self,
+        current: Option<&AccessCandidate>,
+        candidates: &[AccessCandidate],
+    ) -> Option<Entity>;
+}
+
+fn max_by_key<F: Fn(&AccessCandidate) -> f64>(
+    candidates: &[AccessCandidate],
+    key: F,
+) -> Option<Entity> {
+    candidates
+        .iter()
+        .max_by(|x, y| key(x).total_cmp(&key(y)))
+        .map(|c| c.eid)
+}
+
+/// Minimum slant range, the original behaviour.
+#[derive(Default)]
+pub struct NearestPolicy;
+
+impl HandoverPolicy for NearestPolicy {
+    fn name(&self) -> &'static str {
+        "Nearest"
+    }
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        Some(HandoverPolicyKind::Nearest)
+    }
+    fn select(&self, _: Option<&AccessCandidate>, candidates: &[AccessCandidate]) -> Option<Entity> {
+        max_by_key(candidates, |c| -c.distance)
+    }
+}
+
+/// Highest elevation angle.
+#[derive(Default)]
+pub struct HighestElevationPolicy;
+
+impl HandoverPolicy for HighestElevationPolicy {
+    fn name(&self) -> &'static str {
+        "Highest Elevation"
+    }
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        Some(HandoverPolicyKind::HighestElevation)
+    }
+    fn select(&self, _: Option<&AccessCandidate>, candidates: &[AccessCandidate]) -> Option<Entity> {
+        max_by_key(candidates, |c| c.elevation)
+    }
+}
+
+/// Longest remaining visibility, the satellite that sets last.
+#[derive(Default)]
+pub struct LongestVisibilityPolicy;
+
+impl HandoverPolicy for LongestVisibilityPolicy {
+    fn name(&self) -> &'static str {
+        "Longest Visibility"
+    }
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        Some(HandoverPolicyKind::LongestVisibility)
+    }
+    fn needs_visibility(&self) -> bool {
+        true
+    }
+    fn select(&self, _: Option<&AccessCandidate>, candidates: &[AccessCandidate]) -> Option<Entity> {
+        max_by_key(candidates, |c| c.remaining_visibility.unwrap_or(0.0))
+    }
+}
+
+/// Satellite serving the fewest ground stations, ties broken by slant range.
+#[derive(Default)]
+pub struct LeastLoadedPolicy;
+
+impl HandoverPolicy for LeastLoadedPolicy {
+    fn name(&self) -> &'static str {
+        "Least Loaded"
+    }
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        Some(HandoverPolicyKind::LeastLoaded)
+    }
+    fn select(&self, current: Option<&AccessCandidate>, candidates: &[AccessCandidate]) -> Option<Entity> {
+        candidates
+            .iter()
+            .map(|c| {
+                // the current satellite counts this station in its load already
+                let own = current.map_or(false, |cur| cur.eid == c.eid) as usize;
+                (c.load.saturating_sub(own), c)
+            })
+            .min_by(|(l1, c1), (l2, c2)| l1.cmp(l2).then(c1.distance.total_cmp(&c2.distance)))
+            .map(|(_, c)| c.eid)
+    }
+}
+
+/// Default elevation threshold of [`HysteresisPolicy`] (deg).
+pub const HYSTERESIS_THRESHOLD: f64 = 40.0;
+
+fn default_hysteresis_threshold() -> f64 {
+    HYSTERESIS_THRESHOLD
+}
+
+/// Stays on the serving satellite until its elevation drops below `threshold` (deg),
+/// then switches to the highest satellite.
+pub struct HysteresisPolicy {
+    pub threshold: f64,
+}
+
+impl Default for HysteresisPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: HYSTERESIS_THRESHOLD,
+        }
+    }
+}
+
+impl HandoverPolicy for HysteresisPolicy {
+    fn name(&self) -> &'static str {
+        "Hysteresis"
+    }
+    fn kind(&self) -> Option<HandoverPolicyKind> {
+        Some(HandoverPolicyKind::Hysteresis {
+            threshold: self.threshold,
+        })
+    }
+    fn select(&self, current: Option<&AccessCandidate>, candidates: &[AccessCandidate]) -> Option<Entity> {
+        match current {
+            Some(c) if c.elevation >= self.threshold => Some(c.eid),
+            _ => max_by_key(candidates, |c| c.elevation),
+        }
+    }
+}
+
+/// Built-in policies, used by the UI to switch policies at runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandoverPolicyKind {
+    Nearest,
+    HighestElevation,
+    LongestVisibility,
+    LeastLoaded,
+    /// `{"hysteresis": {"threshold": 40}}`, elevation threshold in deg
+    Hysteresis {
+        #[serde(default = "default_hysteresis_threshold")]
+        threshold: f64,
+    },
+}
+
+impl HandoverPolicyKind {
+    pub const ALL: [HandoverPolicyKind; 5] = [
+        HandoverPolicyKind::Nearest,
+        HandoverPolicyKind::HighestElevation,
+        HandoverPolicyKind::LongestVisibility,
+        HandoverPolicyKind::LeastLoaded,
+        HandoverPolicyKind::Hysteresis {
+            threshold: HYSTERESIS_THRESHOLD,
+        },
+    ];
+
+    /// Whether the parameters are usable, the hysteresis threshold has to be an elevation.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            HandoverPolicyKind::Hysteresis { threshold } => (-90.0..=90.0).contains(threshold),
+            _ => true,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn HandoverPolicy> {
+        match self {
+            HandoverPolicyKind::Nearest => Box::new(NearestPolicy),
+            HandoverPolicyKind::HighestElevation => Box::new(HighestElevationPolicy),
+            HandoverPolicyKind::LongestVisibility => Box::new(LongestVisibilityPolicy),
+            HandoverPolicyKind::LeastLoaded => Box::new(LeastLoadedPolicy),
+            HandoverPolicyKind::Hysteresis { threshold } => Box::new(HysteresisPolicy {
+                threshold: *threshold,
+            }),
+        }
+    }
+}
+
+/// Handover policy of a ground station. Stations spawned without one use [`NearestPolicy`].
+#[derive(Component)]
+pub struct Handover(pub Box<dyn HandoverPolicy>);
+
+impl Default for Handover {
+    fn default() -> Self {
+        Self(Box::new(NearestPolicy))
+    }
+}
+
+impl From<HandoverPolicyKind> for Handover {
+    fn from(kind: HandoverPolicyKind) -> Self {
+        Self(kind.build())
+    }
+}
+
+/// Number of handovers performed by a ground station.
+#[derive(Component, Default)]
+pub struct HandoverStats {
+    pub count: u64,
+    pub last: Option<DateTime<Utc>>,
+    /// service interruption of the last handover (s)
+    pub last_interruption: f64,
+    /// accumulated service interruption (s)
+    pub total_interruption: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessState {
+    /// antenna is pointing at the satellite, the link is usable at `ready_at`
+    Establishing { ready_at: DateTime<Utc> },
+    Active { since: DateTime<Utc> },
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLink {
+    pub eid: Entity,
+    pub state: AccessState,
+}
+
+impl AccessLink {
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, AccessState::Active { .. })
+    }
+}
+
+/**
+Satellites a ground station is connected to, at most one per antenna.
+`primary` carries the traffic and is mirrored into [`NearestSat`]; the other
+links are kept for make-before-break handovers.
+*/
+#[derive(Component, Default)]
+pub struct AccessSats {
+    pub links: Vec<AccessLink>,
+    pub primary: Option<Entity>,
+    /// start of the current outage, set when the primary is lost without an active backup
+    pub outage_since: Option<DateTime<Utc>>,
+}
+
+/// Predicted set times of the satellites visible from a ground station.
+#[derive(Component, Default)]
+pub struct PassCache(pub HashMap<Entity, DateTime<Utc>>);
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+/// Resource holding the visibility model shared by all handover policies.
+pub struct HandoverConfig {
+    /// elevation mask (deg)
+    pub min_elevation: f64,
+    /// how far ahead set times are searched (s)
+    pub prediction_horizon: f64,
+    /// propagation step of the set time search (s)
+    pub prediction_step: f64,
+    /// time needed by an antenna to acquire a new satellite (s)
+    pub acquisition_time: f64,
+}
+
+impl Default for HandoverConfig {
+    fn default() -> Self {
+        Self {
+            min_elevation: 0.0,
+            prediction_horizon: 30.0 * 60.0,
+            prediction_step: 10.0,
+            acquisition_time: 1.0,
+        }
+    }
+}
+
+#[derive(Event, Debug, Clone)]
+/// Sent when a ground station switches its access satellite.
+pub struct HandoverEvent {
+    pub gs: Entity,
+    pub from: Option<Entity>,
+    pub to: Entity,
+    /// time without any active access link (s), zero for make-before-break
+    pub interruption: f64,
+}
+
+/// Searches the time at which a satellite drops below the elevation mask.
+/// Returns the end of the horizon if it stays visible.
+pub fn predict_set_time(
+    gs: (f64, f64, f64),
+    ts: &TLETimeStamp,
+    constants: &SGP4Constants,
+    now: &DateTime<Utc>,
+    cfg: &HandoverConfig,
+) -> DateTime<Utc> {
+    let step = chrono::Duration::milliseconds((cfg.prediction_step * 1000.0) as i64);
+    let mut t = *now;
+    let mut elapsed = 0.0;
+    while elapsed < cfg.prediction_horizon {
+        t += step;
+        elapsed += cfg.prediction_step;
+        let Ok((pos, _)) = propagate_sat_at(&ts.0, &constants.0, &t) else {
+            break;
+        };
+        let lla = teme_to_lla(&pos.0, &t);
+        let (_, el, _) = geometry::look_angles(gs, (lla.0, lla.1, lla.2 * 1000.0));
+        if el < cfg.min_elevation {
+            break;
+        }
+    }
+    t
+}
+
+/// Attaches the handover bookkeeping to new ground stations.
+pub fn init_handover(
+    mut cmd: Commands,
+    q: Query<
+        (Entity, Has<Handover>, Has<Antennas>),
+        (With<GroundStationID>, Without<HandoverStats>),
+    >,
+) {
+    q.iter().for_each(|(e, has_policy, has_antennas)| {
+        cmd.entity(e).insert((
+            HandoverStats::default(),
+            PassCache::default(),
+            AccessSats::default(),
+        ));
+        if !has_policy {
+            cmd.entity(e).insert(Handover::default());
+        }
+        if !has_antennas {
+            cmd.entity(e).insert(Antennas::default());
+        }
+    });
+}
+
+/// Lists the satellites above the elevation mask of a ground station.
+pub fn access_candidates(
+    gs: (f64, f64, f64),
+    cfg: &HandoverConfig,
+    sats: &Query<(Entity, &LatLonAlt, Option<&SGP4Constants>, Option<&TLETimeStamp>), With<SatID>>,
+    load: &HashMap<Entity, usize>,
+) -> Vec<AccessCandidate> {
+    sats.iter()
+        .filter_map(|(e, llt, _, _)| {
+            let (_, el, range) =
+                geometry::look_angles(gs, (llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2));
+            (el >= cfg.min_elevation).then(|| AccessCandidate {
+                eid: e,
+                distance: range,
+                elevation: el,
+                remaining_visibility: None,
+                load: load.get(&e).copied().unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+fn fill_visibility(
+    gs: (f64, f64, f64),
+    candidates: &mut [AccessCandidate],
+    cache: &mut PassCache,
+    cfg: &HandoverConfig,
+    sats: &Query<(Entity, &LatLonAlt, Option<&SGP4Constants>, Option<&TLETimeStamp>), With<SatID>>,
+    now: &DateTime<Utc>,
+) {
+    for c in candidates.iter_mut() {
+        let set_time = match cache.0.get(&c.eid) {
+            Some(t) => *t,
+            None => {
+                let Ok((_, _, Some(constants), Some(ts))) = sats.get(c.eid) else {
+                    continue;
+                };
+                let t = predict_set_time(gs, ts, constants, now, cfg);
+                cache.0.insert(c.eid, t);
+                t
+            }
+        };
+        c.remaining_visibility = Some((set_time - *now).num_milliseconds() as f64 / 1000.0);
+    }
+}
+
+/// Runs the policy on the candidates that are not linked yet.
+fn select_unlinked(
+    policy: &dyn HandoverPolicy,
+    candidates: &[AccessCandidate],
+    access: &AccessSats,
+) -> Option<Entity> {
+    let free: Vec<_> = candidates
+        .iter()
+        .filter(|c| !access.links.iter().any(|l| l.eid == c.eid))
+        .cloned()
+        .collect();
+    policy.select(None, &free)
+}
+
+/**
+Selects the access satellites of every ground station with its handover policy.
+
+Each antenna tracks one satellite. A new satellite first has to be acquired
+(`HandoverConfig::acquisition_time`), meanwhile the old primary keeps carrying
+traffic, so stations with spare antennas hand over without interruption.
+Single antenna stations have to break the old link first and report the
+acquisition time as interruption.
+
+The primary is written to [`NearestSat`] so the data link code does not need
+to know which policy is used.
+*/
+pub fn select_access_sat(
+    mut commands: Commands,
+    cfg: Res<HandoverConfig>,
+    clock: Res<SimClock>,
+    mut events: EventWriter<HandoverEvent>,
+    mut q: Query<(
+        Entity,
+        &LatLonAlt,
+        &Handover,
+        &Antennas,
+        &mut AccessSats,
+        &mut PassCache,
+        &mut HandoverStats,
+    )>,
+    sats: Query<(Entity, &LatLonAlt, Option<&SGP4Constants>, Option<&TLETimeStamp>), With<SatID>>,
+) {
+    let now = clock.now;
+    let acquisition = chrono::Duration::milliseconds((cfg.acquisition_time * 1000.0) as i64);
+    let mut load: HashMap<Entity, usize> = HashMap::new();
+    q.iter().for_each(|(_, _, _, _, access, _, _)| {
+        if let Some(p) = access.primary {
+            *load.entry(p).or_default() += 1;
+        }
+    });
+
+    q.iter_mut().for_each(
+        |(entity, gs_llt, policy, antennas, mut access, mut cache, mut stats)| {
+            let gs = (gs_llt.0 .0, gs_llt.0 .1, 1000.0 * gs_llt.0 .2);
+            let antennas = antennas.0.max(1);
+            let mut candidates = access_candidates(gs, &cfg, &sats, &load);
+
+            cache.0.retain(|e, _| candidates.iter().any(|c| c.eid == *e));
+            if policy.0.needs_visibility() {
+                fill_visibility(gs, &mut candidates, &mut cache, &cfg, &sats, &now);
+            }
+            let access = &mut *access;
+            let previous = access.primary;
+
+            // drop satellites below the mask and finish acquisitions
+            access
+                .links
+                .retain(|l| candidates.iter().any(|c| c.eid == l.eid));
+            for l in access.links.iter_mut() {
+                if let AccessState::Establishing { ready_at } = l.state {
+                    if ready_at <= now {
+                        l.state = AccessState::Active { since: ready_at };
+                    }
+                }
+            }
+            let active: Vec<_> = candidates
+                .iter()
+                .filter(|c| access.links.iter().any(|l| l.eid == c.eid && l.is_active()))
+                .cloned()
+                .collect();
+            if access.primary.map_or(false, |p| !active.iter().any(|c| c.eid == p)) {
+                access.primary = policy.0.select(None, &active);
+            }
+            if access.primary.is_none() && access.outage_since.is_none() && previous.is_some() {
+                access.outage_since = Some(now);
+            }
+
+            let current = candidates.iter().find(|c| Some(c.eid) == access.primary);
+            if let Some(target) = policy.0.select(current, &candidates) {
+                let linked = access
+                    .links
+                    .iter()
+                    .find(|l| l.eid == target)
+                    .map(|l| l.is_active());
+                match linked {
+                    Some(true) => access.primary = Some(target),
+                    Some(false) => {}
+                    None => {
+                        if access.links.len() >= antennas {
+                            // all antennas busy, release the lowest non-primary link,
+                            // a single antenna has to give up the primary
+                            let victim = access
+                                .links
+                                .iter()
+                                .filter(|l| antennas == 1 || Some(l.eid) != access.primary)
+                                .min_by(|a, b| {
+                                    let el = |e: Entity| {
+                                        candidates
+                                            .iter()
+                                            .find(|c| c.eid == e)
+                                            .map_or(f64::MIN, |c| c.elevation)
+                                    };
+                                    el(a.eid).total_cmp(&el(b.eid))
+                                })
+                                .map(|l| l.eid);
+                            access.links.retain(|l| Some(l.eid) != victim);
+                            if victim.is_some() && victim == access.primary {
+                                access.primary = None;
+                                access.outage_since = Some(now);
+                            }
+                        }
+                        access.links.push(AccessLink {
+                            eid: target,
+                            state: AccessState::Establishing {
+                                ready_at: now + acquisition,
+                            },
+                        });
+                    }
+                }
+            }
+
+            // spare antennas track the next best satellites for dual connectivity
+            while access.links.len() < antennas {
+                let Some(next) = select_unlinked(policy.0.as_ref(), &candidates, access) else {
+                    break;
+                };
+                access.links.push(AccessLink {
+                    eid: next,
+                    state: AccessState::Establishing {
+                        ready_at: now + acquisition,
+                    },
+                });
+            }
+            if access.primary.is_none() {
+                let active: Vec<_> = candidates
+                    .iter()
+                    .filter(|c| access.links.iter().any(|l| l.eid == c.eid && l.is_active()))
+                    .cloned()
+                    .collect();
+                access.primary = policy.0.select(None, &active);
+            }
+
+            if access.primary != previous {
+                if let Some(prev) = previous {
+                    if let Some(l) = load.get_mut(&prev) {
+                        *l = l.saturating_sub(1);
+                    }
+                }
+                if let Some(p) = access.primary {
+                    *load.entry(p).or_default() += 1;
+                    let interruption = access
+                        .outage_since
+                        .take()
+                        .map_or(0.0, |t| (now - t).num_milliseconds() as f64 / 1000.0);
+                    if previous.is_some() || interruption > 0.0 {
+                        stats.count += 1;
+                        stats.last = Some(now);
+                        stats.last_interruption = interruption;
+                        stats.total_interruption += interruption;
+                    }
+                    events.send(HandoverEvent {
+                        gs: entity,
+                        from: previous,
+                        to: p,
+                        interruption,
+                    });
+                }
+            }
+
+            match access.primary.and_then(|p| candidates.iter().find(|c| c.eid == p)) {
+                Some(c) => {
+                    commands.entity(entity).insert(NearestSat {
+                        eid: c.eid,
+                        distance: c.distance,
+                        elevation: c.elevation,
+                    });
+                }
+                None => {
+                    commands.entity(entity).remove::<NearestSat>();
+                }
+            }
+        },
+    );
+}
+
+pub struct HandoverPlugin;
+
+impl Plugin for HandoverPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HandoverConfig>();
+        app.add_event::<HandoverEvent>();
+        app.add_systems(PostUpdate, (init_handover, select_access_sat).chain());
+    }
+}
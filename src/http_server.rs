@@ -0,0 +1,410 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use bevy::{prelude::*, time::common_conditions::on_real_timer};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tower_http::cors::CorsLayer;
+
+use crate::{
+    celestrak::{LatLonAlt, Runtime, SatID, SimClock},
+    command::{GroundStationState, SatelliteState},
+    datalink::{path_nodes, DataLink, DataLinkHistory, DataLinkStats, GSDataLink, LatencySummary},
+    groundstation::{GroundStationID, NearestSat},
+    handover::HandoverStats,
+    query::{
+        QueryError, QueryReply, QueryRequest, QueryWorld, RequestHeader, QUERY_SCHEMA_VERSION,
+    },
+};
+
+/// `http` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub address: String,
+    /// upper limit of the WebSocket messages per second, 0 to push every app update
+    pub stream_rate: f64,
+    /// include every satellite position in the WebSocket messages
+    pub stream_positions: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: "127.0.0.1:8080".into(),
+            stream_rate: 0.0,
+            stream_positions: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LinkLive {
+    pub name: String,
+    /// ground station IDs
+    pub from: u64,
+    pub to: u64,
+    /// end to end latency (s)
+    pub latency: f32,
+    pub latencies: Vec<f32>,
+    pub distance: Vec<f32>,
+    /// entity IDs of the path nodes
+    pub hops: Vec<u64>,
+    /// NORAD ID of every hop, null for ground stations
+    pub norad_ids: Vec<Option<u64>>,
+    /// (lat, lon) of every hop (deg), for drawing the path
+    pub path: Vec<(f64, f64)>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LinkStatistics {
+    pub name: String,
+    pub summary: Option<LatencySummary>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StationStatistics {
+    pub id: u64,
+    pub name: String,
+    pub handovers: u64,
+    /// accumulated service interruption (s)
+    pub interruption: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Statistics {
+    pub satellites: usize,
+    pub links: Vec<LinkStatistics>,
+    pub stations: Vec<StationStatistics>,
+}
+
+/// State served by the REST endpoints, refreshed with every WebSocket message.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LiveState {
+    /// simulation time as unix timestamp (s)
+    pub time: f64,
+    pub satellites: Vec<SatelliteState>,
+    pub ground_stations: Vec<GroundStationState>,
+    pub links: Vec<LinkLive>,
+    pub statistics: Statistics,
+}
+
+/// Message pushed to WebSocket clients.
+#[derive(Serialize)]
+struct Tick<'a> {
+    time: f64,
+    links: &'a [LinkLive],
+    ground_stations: &'a [GroundStationState],
+    /// (NORAD ID, lat, lon, alt km), empty unless `stream_positions` is set
+    satellites: Vec<(u64, f32, f32, f32)>,
+}
+
+type QueryJob = (QueryRequest, oneshot::Sender<QueryReply>);
+
+#[derive(Clone)]
+struct HttpState {
+    live: watch::Receiver<Arc<LiveState>>,
+    ticks: broadcast::Sender<Arc<str>>,
+    queries: mpsc::Sender<QueryJob>,
+}
+
+#[derive(Resource)]
+/// Resource connecting the HTTP server tasks with the ECS.
+pub struct HttpServer {
+    live: watch::Sender<Arc<LiveState>>,
+    ticks: broadcast::Sender<Arc<str>>,
+    queries: mpsc::Receiver<QueryJob>,
+    config: HttpConfig,
+}
+
+fn not_found(what: String) -> Response {
+    (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": what }))).into_response()
+}
+
+async fn index() -> Html<&'static str> {
+    Html(include_str!("../data/live.html"))
+}
+
+async fn satellites(State(state): State<HttpState>) -> Response {
+    Json(state.live.borrow().satellites.clone()).into_response()
+}
+
+async fn satellite(State(state): State<HttpState>, Path(norad_id): Path<u64>) -> Response {
+    let live = state.live.borrow().clone();
+    match live.satellites.iter().find(|s| s.norad_id == norad_id) {
+        Some(s) => Json(s.clone()).into_response(),
+        None => not_found(format!("no satellite with NORAD ID {}", norad_id)),
+    }
+}
+
+async fn ground_stations(State(state): State<HttpState>) -> Response {
+    Json(state.live.borrow().ground_stations.clone()).into_response()
+}
+
+async fn links(State(state): State<HttpState>) -> Response {
+    Json(state.live.borrow().links.clone()).into_response()
+}
+
+async fn link(State(state): State<HttpState>, Path(name): Path<String>) -> Response {
+    let live = state.live.borrow().clone();
+    match live.links.iter().find(|l| l.name == name) {
+        Some(l) => Json(l.clone()).into_response(),
+        None => not_found(format!("no link {}", name)),
+    }
+}
+
+async fn statistics(State(state): State<HttpState>) -> Response {
+    Json(state.live.borrow().statistics.clone()).into_response()
+}
+
+/// Decodes a JSON query like the ZMQ interface does, the header first so
+/// that other schema versions are rejected before the body is looked at.
+fn decode_query(body: &[u8]) -> Result<QueryRequest, QueryReply> {
+    let header: RequestHeader = serde_json::from_slice(body)
+        .map_err(|err| QueryReply::new(0, Err(QueryError::Malformed(err.to_string()))))?;
+    if header.version != QUERY_SCHEMA_VERSION {
+        return Err(QueryReply::new(
+            header.id,
+            Err(QueryError::UnsupportedVersion(header.version)),
+        ));
+    }
+    serde_json::from_slice(body)
+        .map_err(|err| QueryReply::new(header.id, Err(QueryError::Malformed(err.to_string()))))
+}
+
+fn query_response(reply: QueryReply) -> Response {
+    let status = match reply.error.as_ref().map(|e| e.code.as_str()) {
+        None => StatusCode::OK,
+        Some("busy") | Some("unavailable") => StatusCode::SERVICE_UNAVAILABLE,
+        Some(_) => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(reply)).into_response()
+}
+
+/// Forwards a query to the ECS, see [`crate::query`]. Every response carries
+/// a [`QueryReply`], also for bodies that cannot be decoded.
+async fn query(State(state): State<HttpState>, body: Bytes) -> Response {
+    let request = match decode_query(&body) {
+        Ok(request) => request,
+        Err(reply) => return query_response(reply),
+    };
+    let id = request.id;
+    let (tx, rx) = oneshot::channel();
+    if state.queries.send((request, tx)).await.is_err() {
+        return query_response(QueryReply::new(id, Err(QueryError::Unavailable)));
+    }
+    match tokio::time::timeout(Duration::from_secs(10), rx).await {
+        Ok(Ok(reply)) => query_response(reply),
+        _ => query_response(QueryReply::new(id, Err(QueryError::Unavailable))),
+    }
+}
+
+async fn websocket(ws: WebSocketUpgrade, State(state): State<HttpState>) -> Response {
+    ws.on_upgrade(move |socket| stream(socket, state))
+}
+
+/// Pushes every tick to the client. The socket is read as well, so pings are
+/// answered and a closed connection ends the stream right away.
+async fn stream(mut socket: WebSocket, state: HttpState) {
+    let mut ticks = state.ticks.subscribe();
+    loop {
+        tokio::select! {
+            tick = ticks.recv() => match tick {
+                Ok(msg) => {
+                    if socket.send(Message::Text(msg.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+                // slow clients skip ticks
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+fn start_server(rt: Res<Runtime>, mut cmd: Commands, config: Res<HttpServerConfig>) {
+    let config = config.0.clone();
+    let (live_tx, live_rx) = watch::channel(Arc::new(LiveState::default()));
+    let (ticks, _) = broadcast::channel(16);
+    let (query_tx, query_rx) = mpsc::channel(64);
+    let state = HttpState {
+        live: live_rx,
+        ticks: ticks.clone(),
+        queries: query_tx,
+    };
+    let app = axum::Router::new()
+        .route("/", get(index))
+        .route("/api/satellites", get(satellites))
+        .route("/api/satellites/:norad_id", get(satellite))
+        .route("/api/ground_stations", get(ground_stations))
+        .route("/api/links", get(links))
+        .route("/api/links/:name", get(link))
+        .route("/api/statistics", get(statistics))
+        .route("/api/query", post(query))
+        .route("/ws", get(websocket))
+        .with_state(state)
+        .layer(CorsLayer::permissive());
+    let address = config.address.clone();
+    rt.0.spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("cannot start HTTP server on {}: {}", address, err);
+                return;
+            }
+        };
+        info!("HTTP server listening on http://{}", address);
+        if let Err(err) = axum::serve(listener, app).await {
+            error!("HTTP server stopped: {}", err);
+        }
+    });
+    cmd.insert_resource(HttpServer {
+        live: live_tx,
+        ticks,
+        queries: query_rx,
+        config,
+    });
+}
+
+/// Publishes the current state to the REST endpoints and WebSocket clients.
+fn update_live_state(
+    server: Res<HttpServer>,
+    clock: Res<SimClock>,
+    sats: Query<(&SatID, &Name, &LatLonAlt)>,
+    stations: Query<(Entity, &GroundStationID, &Name, &LatLonAlt, Option<&NearestSat>, Option<&HandoverStats>)>,
+    links: Query<(&Name, &GSDataLink, Option<&DataLink>, Option<&DataLinkStats>, Option<&DataLinkHistory>)>,
+    lla: Query<&LatLonAlt>,
+) {
+    let norad = |e: Entity| sats.get(e).ok().map(|s| s.0 .0);
+    let gs_id = |e: Entity| stations.get(e).map(|s| s.1 .0).unwrap_or_default();
+    let mut live = LiveState {
+        time: clock.now.timestamp_micros() as f64 / 1e6,
+        ..Default::default()
+    };
+    live.satellites = sats
+        .iter()
+        .map(|(id, name, lla)| SatelliteState {
+            norad_id: id.0,
+            name: name.to_string(),
+            lat: lla.0 .0,
+            lon: lla.0 .1,
+            alt: lla.0 .2,
+        })
+        .collect();
+    live.ground_stations = stations
+        .iter()
+        .map(|(_, id, name, lla, nearest, _)| GroundStationState {
+            id: id.0,
+            name: name.to_string(),
+            lat: lla.0 .0,
+            lon: lla.0 .1,
+            alt: lla.0 .2,
+            access: nearest.and_then(|n| norad(n.eid)),
+        })
+        .collect();
+    for (name, gs, path, stats, history) in links.iter() {
+        let hops: Vec<Entity> = path.map(|p| path_nodes(&p.0)).unwrap_or_default();
+        live.links.push(LinkLive {
+            name: name.to_string(),
+            from: gs_id(gs.0 .0),
+            to: gs_id(gs.0 .1),
+            latency: stats.map(|s| s.latencies.iter().sum()).unwrap_or_default(),
+            latencies: stats.map(|s| s.latencies.clone()).unwrap_or_default(),
+            distance: stats.map(|s| s.distance.clone()).unwrap_or_default(),
+            norad_ids: hops.iter().map(|e| norad(*e)).collect(),
+            path: hops
+                .iter()
+                .filter_map(|e| lla.get(*e).ok().map(|l| (l.0 .0, l.0 .1)))
+                .collect(),
+            hops: hops.iter().map(|e| e.to_bits()).collect(),
+        });
+        live.statistics.links.push(LinkStatistics {
+            name: name.to_string(),
+            summary: history.and_then(|h| h.summary()),
+        });
+    }
+    live.statistics.satellites = live.satellites.len();
+    live.statistics.stations = stations
+        .iter()
+        .map(|(_, id, name, _, _, stats)| StationStatistics {
+            id: id.0,
+            name: name.to_string(),
+            handovers: stats.map_or(0, |s| s.count),
+            interruption: stats.map_or(0.0, |s| s.total_interruption),
+        })
+        .collect();
+
+    if server.ticks.receiver_count() > 0 {
+        let tick = Tick {
+            time: live.time,
+            links: &live.links,
+            ground_stations: &live.ground_stations,
+            satellites: if server.config.stream_positions {
+                live.satellites
+                    .iter()
+                    .map(|s| (s.norad_id, s.lat as f32, s.lon as f32, s.alt as f32))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        };
+        match serde_json::to_string(&tick) {
+            Ok(msg) => {
+                let _ = server.ticks.send(msg.into());
+            }
+            Err(err) => error!("cannot encode WebSocket message: {}", err),
+        }
+    }
+    server.live.send_replace(Arc::new(live));
+}
+
+fn serve_http_queries(mut server: ResMut<HttpServer>, mut world: QueryWorld) {
+    while let Ok((request, reply)) = server.queries.try_recv() {
+        let _ = reply.send(world.answer(&request));
+    }
+}
+
+#[derive(Resource)]
+struct HttpServerConfig(HttpConfig);
+
+/// Embedded HTTP server with REST endpoints under `/api`, a WebSocket stream
+/// on `/ws` and a live map on `/`.
+#[derive(Default)]
+pub struct HttpServerPlugin {
+    pub config: HttpConfig,
+}
+
+impl Plugin for HttpServerPlugin {
+    fn build(&self, app: &mut App) {
+        if !self.config.enabled {
+            return;
+        }
+        app.insert_resource(HttpServerConfig(self.config.clone()));
+        app.add_systems(Startup, start_server);
+        let update = update_live_state.run_if(resource_exists::<HttpServer>);
+        if self.config.stream_rate > 0.0 {
+            let period = Duration::from_secs_f64(1.0 / self.config.stream_rate);
+            app.add_systems(PostUpdate, update.run_if(on_real_timer(period)));
+        } else {
+            app.add_systems(PostUpdate, update);
+        }
+        app.add_systems(Update, serve_http_queries.run_if(resource_exists::<HttpServer>));
+    }
+}
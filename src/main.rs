@@ -22,11 +22,30 @@ use groundstation::{GSConfigs, GSPlugin, GroundStationBundle, GroundStationID};
 use sgp4::Orbit;
 
 use bevy_svg::prelude::*;
+pub mod bench;
 pub mod celestrak;
 mod cfg_ui;
-mod datalink;
+pub mod command;
+pub mod config;
+pub mod contact_plan;
+pub mod datalink;
+pub mod export;
 pub mod groundstation;
+pub mod handover;
+#[cfg(feature = "http_server")]
+pub mod http_server;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod netem;
+pub mod query;
 pub mod render_satellite;
+pub mod routing;
+pub mod session;
+pub mod tle_history;
+pub mod tle_provider;
+pub mod tle_quality;
+pub mod topology;
+pub mod udp_output;
 pub mod util;
 #[cfg(feature = "zmq_comm")]
 pub mod zmq_comm;
@@ -40,6 +59,14 @@ use cfg_ui::*;
 use render_satellite::*;
 
 fn main() {
+    let config = config::AppConfig::from_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    });
+    if !config.bench.satellites.is_empty() {
+        bench::run(&config.bench);
+        return;
+    }
     let mut app = App::new();
 
     app.insert_resource(ClearColor(Color::srgb_u8(0, 7, 13)));
@@ -67,18 +94,42 @@ fn main() {
         EguiPlugin,
         SatRenderPlugin,
         ShapePlugin,
-        DatalinkPlugin,
+        DatalinkPlugin::default(),
     ))
     .add_systems(Startup, setup);
 
-    app.add_plugins(SGP4Plugin);
+    app.add_plugins(SGP4Plugin {
+        config: config.tle.clone(),
+        ..default()
+    });
+    app.insert_resource(config.clone());
+    app.add_plugins(tle_quality::TleQualityPlugin {
+        config: config.tle_quality.clone(),
+    });
+    app.add_plugins(session::SessionPlugin {
+        config: config.session.clone(),
+    });
+    app.add_plugins(tle_history::TleHistoryPlugin {
+        config: config.tle_history.clone(),
+    });
+    app.add_plugins(udp_output::UdpOutputPlugin {
+        config: config.udp.clone(),
+    });
+    app.add_plugins(netem::NetemPlugin {
+        config: config.netem.clone(),
+    });
     #[cfg(feature = "zmq_comm")]
-    {
-        let mut zmq = zmq_comm::ZMQContext::default();
-        zmq.tx_address = "tcp://127.0.0.1:5551".into();
-        zmq.rx_address = "tcp://127.0.0.1:5552".into();
-        app.insert_resource(zmq);
-    }
+    app.add_plugins(zmq_comm::ZMQPlugin {
+        config: config.zmq.clone(),
+    });
+    #[cfg(feature = "http_server")]
+    app.add_plugins(http_server::HttpServerPlugin {
+        config: config.http.clone(),
+    });
+    #[cfg(feature = "mqtt")]
+    app.add_plugins(mqtt::MqttPlugin {
+        config: config.mqtt.clone(),
+    });
     app.insert_resource(UIData::default());
     app.add_systems(PreUpdate, retro_cam_input_handle.in_set(InputSet));
 
@@ -90,7 +141,19 @@ fn main() {
     //app.add_system_to_stage(CoreStage::PreUpdate, resize_map);
     app.add_systems(PreUpdate, get_cursor_coord);
     //app.add_systems(Update,check_vis);
-    app.add_systems(Update, show_data.in_set(EguiUISet));
+    app.init_resource::<RoutingComparison>();
+    app.init_resource::<ExportSettings>();
+    app.add_systems(
+        Update,
+        (
+            show_data,
+            show_link_history,
+            show_routing,
+            show_export,
+            show_tle_status,
+        )
+            .in_set(EguiUISet),
+    );
     app.configure_sets(Update, EguiUISet.after(EguiSet::InitContexts));
     // app.add_systems(test);
 
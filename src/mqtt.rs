@@ -0,0 +1,272 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, time::common_conditions::on_real_timer};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::{Runtime, SatID, SimClock},
+    datalink::DataLinkStats,
+    groundstation::GroundStationID,
+    handover::HandoverEvent,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// `mqtt` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// (s)
+    pub keep_alive: u64,
+    /// replaces `{prefix}` in the topic templates
+    pub prefix: String,
+    /// template with `{prefix}`, `{link}` and `{metric}` (latency, distance or hops)
+    pub link_topic: String,
+    /// template with `{prefix}` and `{station}`
+    pub handover_topic: String,
+    /// 0, 1 or 2
+    pub qos: u8,
+    /// keep the last value on the broker for new subscribers
+    pub retain: bool,
+    pub format: PayloadFormat,
+    /// link metrics per second
+    pub publish_rate: f64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: "127.0.0.1".into(),
+            port: 1883,
+            client_id: "rustsat".into(),
+            username: None,
+            password: None,
+            keep_alive: 30,
+            prefix: "rustsat".into(),
+            link_topic: "{prefix}/links/{link}/{metric}".into(),
+            handover_topic: "{prefix}/handover/{station}".into(),
+            qos: 0,
+            retain: true,
+            format: PayloadFormat::Json,
+            publish_rate: 1.0,
+        }
+    }
+}
+
+impl MqttConfig {
+    fn qos(&self) -> QoS {
+        match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// A single metric of a link.
+#[derive(Serialize)]
+pub struct MetricMsg<T> {
+    /// simulation time as unix timestamp (s)
+    pub ts: f64,
+    pub value: T,
+    /// per hop values, empty for the hop count
+    pub hops: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct HandoverMsg {
+    pub ts: f64,
+    pub station: u64,
+    /// NORAD IDs of the previous and new access satellite
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// (s)
+    pub interruption: f64,
+}
+
+/// Replaces the characters MQTT reserves in topic levels.
+fn topic_level(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '/' | '+' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Resource)]
+/// Resource holding the MQTT client, the connection is driven by a task on [`Runtime`].
+pub struct MqttPublisher {
+    client: AsyncClient,
+    config: MqttConfig,
+}
+
+impl MqttPublisher {
+    fn encode<T: Serialize>(&self, msg: &T) -> Option<Vec<u8>> {
+        let data = match self.config.format {
+            PayloadFormat::Json => serde_json::to_vec(msg).map_err(|e| e.to_string()),
+            PayloadFormat::Msgpack => rmp_serde::to_vec_named(msg).map_err(|e| e.to_string()),
+        };
+        data.map_err(|err| error!("cannot encode MQTT payload: {}", err)).ok()
+    }
+
+    fn publish<T: Serialize>(&self, topic: String, msg: &T) {
+        let Some(payload) = self.encode(msg) else {
+            return;
+        };
+        // fails only if the request queue is full, e.g. while the broker is unreachable
+        if let Err(err) = self
+            .client
+            .try_publish(topic, self.config.qos(), self.config.retain, payload)
+        {
+            debug!("MQTT message dropped: {}", err);
+        }
+    }
+
+    fn link_topic(&self, link: &str, metric: &str) -> String {
+        self.config
+            .link_topic
+            .replace("{prefix}", &self.config.prefix)
+            .replace("{link}", &topic_level(link))
+            .replace("{metric}", metric)
+    }
+}
+
+fn connect_mqtt(mut cmd: Commands, rt: Res<Runtime>, config: Res<MqttClientConfig>) {
+    let config = config.0.clone();
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive.max(5)));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 256);
+    let address = format!("{}:{}", config.host, config.port);
+    rt.0.spawn(async move {
+        let mut connected = false;
+        loop {
+            match eventloop.poll().await {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker {}", address);
+                    connected = true;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    // polling again reconnects
+                    if connected {
+                        warn!("MQTT connection to {} lost: {}", address, err);
+                    }
+                    connected = false;
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+            }
+        }
+    });
+    cmd.insert_resource(MqttPublisher { client, config });
+}
+
+fn publish_link_metrics(
+    mqtt: Res<MqttPublisher>,
+    clock: Res<SimClock>,
+    q: Query<(&Name, &DataLinkStats)>,
+) {
+    let ts = clock.now.timestamp_micros() as f64 / 1e6;
+    q.iter().for_each(|(name, stats)| {
+        mqtt.publish(
+            mqtt.link_topic(name, "latency"),
+            &MetricMsg {
+                ts,
+                value: stats.latencies.iter().sum::<f32>(),
+                hops: stats.latencies.clone(),
+            },
+        );
+        mqtt.publish(
+            mqtt.link_topic(name, "distance"),
+            &MetricMsg {
+                ts,
+                value: stats.distance.iter().sum::<f32>(),
+                hops: stats.distance.clone(),
+            },
+        );
+        mqtt.publish(
+            mqtt.link_topic(name, "hops"),
+            &MetricMsg {
+                ts,
+                value: stats.latencies.len(),
+                hops: Vec::new(),
+            },
+        );
+    });
+}
+
+fn publish_handovers(
+    mqtt: Res<MqttPublisher>,
+    clock: Res<SimClock>,
+    mut events: EventReader<HandoverEvent>,
+    stations: Query<(&GroundStationID, Option<&Name>)>,
+    sats: Query<&SatID>,
+) {
+    let ts = clock.now.timestamp_micros() as f64 / 1e6;
+    for ev in events.read() {
+        let Ok((id, name)) = stations.get(ev.gs) else {
+            continue;
+        };
+        let station = name.map_or_else(|| id.0.to_string(), |n| n.to_string());
+        let topic = mqtt
+            .config
+            .handover_topic
+            .replace("{prefix}", &mqtt.config.prefix)
+            .replace("{station}", &topic_level(&station));
+        mqtt.publish(
+            topic,
+            &HandoverMsg {
+                ts,
+                station: id.0,
+                from: ev.from.and_then(|e| sats.get(e).ok()).map(|s| s.0),
+                to: sats.get(ev.to).ok().map(|s| s.0),
+                interruption: ev.interruption,
+            },
+        );
+    }
+}
+
+#[derive(Resource)]
+struct MqttClientConfig(MqttConfig);
+
+/// Publishes link metrics and handover events to an MQTT broker.
+#[derive(Default)]
+pub struct MqttPlugin {
+    pub config: MqttConfig,
+}
+
+impl Plugin for MqttPlugin {
+    fn build(&self, app: &mut App) {
+        if !self.config.enabled {
+            return;
+        }
+        let period = Duration::from_secs_f64(1.0 / self.config.publish_rate.max(0.01));
+        app.insert_resource(MqttClientConfig(self.config.clone()));
+        app.add_systems(Startup, connect_mqtt);
+        app.add_systems(
+            PostUpdate,
+            (
+                publish_link_metrics.run_if(on_real_timer(period)),
+                publish_handovers,
+            )
+                .run_if(resource_exists::<MqttPublisher>),
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, watch, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    celestrak::Runtime,
+    datalink::{DataLinkStats, GSDataLink},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// A delay line between two local ports following one data link.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelayLineConfig {
+    /// name of the data link whose path drives the line
+    pub link: String,
+    pub protocol: Protocol,
+    /// address the application under test sends to
+    pub listen: String,
+    /// address the traffic is delivered to
+    pub forward: String,
+    /// standard deviation of the delay added per hop (s)
+    pub jitter_per_hop: f64,
+    /// loss probability per hop, UDP only
+    pub loss_per_hop: f64,
+    /// queueing and processing delay per hop (s)
+    pub processing_per_hop: f64,
+}
+
+impl Default for DelayLineConfig {
+    fn default() -> Self {
+        Self {
+            link: String::new(),
+            protocol: Protocol::Udp,
+            listen: "127.0.0.1:7000".into(),
+            forward: "127.0.0.1:7001".into(),
+            jitter_per_hop: 0.0005,
+            loss_per_hop: 0.0,
+            processing_per_hop: 0.0,
+        }
+    }
+}
+
+/// `netem` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetemConfig {
+    pub lines: Vec<DelayLineConfig>,
+    /// seed of the jitter and loss generators, equal seeds give equal sequences
+    pub seed: u64,
+}
+
+impl Default for NetemConfig {
+    fn default() -> Self {
+        Self {
+            lines: Vec::new(),
+            seed: 1,
+        }
+    }
+}
+
+/// Current state of the emulated path.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Condition {
+    pub up: bool,
+    /// one way delay (s)
+    pub delay: f64,
+    /// (s)
+    pub jitter: f64,
+    pub loss: f64,
+}
+
+impl Condition {
+    fn from_stats(stats: Option<&DataLinkStats>, cfg: &DelayLineConfig) -> Self {
+        let Some(stats) = stats.filter(|s| !s.latencies.is_empty()) else {
+            return Self::default();
+        };
+        let hops = stats.latencies.len() as f64;
+        Self {
+            up: true,
+            delay: stats.latencies.iter().map(|l| *l as f64).sum::<f64>() + cfg.processing_per_hop * hops,
+            jitter: cfg.jitter_per_hop * hops.sqrt(),
+            loss: 1.0 - (1.0 - cfg.loss_per_hop.clamp(0.0, 1.0)).powf(hops),
+        }
+    }
+}
+
+/// xorshift64*, enough for jitter and loss and reproducible from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal sample (Box-Muller).
+    fn normal(&mut self) -> f64 {
+        let u = self.uniform().max(f64::MIN_POSITIVE);
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    }
+}
+
+#[derive(Default)]
+pub struct LineCounters {
+    pub forwarded: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+/// Release times of one direction. Packets leave in arrival order, so
+/// jitter never reorders them.
+struct Shaper {
+    condition: watch::Receiver<Condition>,
+    rng: Rng,
+    last: Instant,
+    counters: Arc<LineCounters>,
+}
+
+impl Shaper {
+    fn new(condition: watch::Receiver<Condition>, seed: u64, counters: Arc<LineCounters>) -> Self {
+        Self {
+            condition,
+            rng: Rng::new(seed),
+            last: Instant::now(),
+            counters,
+        }
+    }
+
+    fn release(&mut self, c: &Condition) -> Instant {
+        let delay = (c.delay + c.jitter * self.rng.normal()).max(0.0);
+        self.last = self.last.max(Instant::now() + std::time::Duration::from_secs_f64(delay));
+        self.last
+    }
+
+    /// Release time of a datagram, `None` if it is lost.
+    fn datagram(&mut self) -> Option<Instant> {
+        let c = *self.condition.borrow();
+        if !c.up || self.rng.uniform() < c.loss {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.counters.forwarded.fetch_add(1, Ordering::Relaxed);
+        Some(self.release(&c))
+    }
+
+    /// Release time of stream data, which is never lost.
+    fn stream(&mut self) -> Instant {
+        let c = *self.condition.borrow();
+        self.counters.forwarded.fetch_add(1, Ordering::Relaxed);
+        self.release(&c)
+    }
+}
+
+type Queue = mpsc::UnboundedSender<(Instant, Vec<u8>)>;
+
+async fn run_udp(
+    cfg: DelayLineConfig,
+    condition: watch::Receiver<Condition>,
+    seed: u64,
+    counters: Arc<LineCounters>,
+) -> std::io::Result<()> {
+    let listen = Arc::new(UdpSocket::bind(&cfg.listen).await?);
+    let upstream = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    upstream.connect(&cfg.forward).await?;
+    let client: Arc<Mutex<Option<SocketAddr>>> = Arc::default();
+
+    let (to_forward, mut forward_rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let (to_client, mut client_rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    {
+        let upstream = upstream.clone();
+        tokio::spawn(async move {
+            while let Some((at, data)) = forward_rx.recv().await {
+                tokio::time::sleep_until(at).await;
+                let _ = upstream.send(&data).await;
+            }
+        });
+    }
+    {
+        let (listen, client) = (listen.clone(), client.clone());
+        tokio::spawn(async move {
+            while let Some((at, data)) = client_rx.recv().await {
+                tokio::time::sleep_until(at).await;
+                if let Some(addr) = *client.lock().await {
+                    let _ = listen.send_to(&data, addr).await;
+                }
+            }
+        });
+    }
+
+    let mut forward = Shaper::new(condition.clone(), seed, counters.clone());
+    let mut back = Shaper::new(condition, seed.rotate_left(32), counters);
+    let (mut a, mut b) = (vec![0u8; 65536], vec![0u8; 65536]);
+    let enqueue = |queue: &Queue, at: Option<Instant>, data: &[u8]| {
+        if let Some(at) = at {
+            let _ = queue.send((at, data.to_vec()));
+        }
+    };
+    loop {
+        tokio::select! {
+            r = listen.recv_from(&mut a) => {
+                let (n, from) = r?;
+                client.lock().await.replace(from);
+                enqueue(&to_forward, forward.datagram(), &a[..n]);
+            }
+            r = upstream.recv(&mut b) => {
+                // the target may not be listening yet
+                let Ok(n) = r else { continue };
+                enqueue(&to_client, back.datagram(), &b[..n]);
+            }
+        }
+    }
+}
+
+/// Copies one direction of a TCP connection through a shaper. While the link
+/// is down data is held back, like a TCP sender retransmitting into an outage.
+async fn pump<R, W>(mut r: R, mut w: W, mut shaper: Shaper)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let mut condition = shaper.condition.clone();
+    let writer = tokio::spawn(async move {
+        while let Some((at, data)) = rx.recv().await {
+            tokio::time::sleep_until(at).await;
+            if condition.wait_for(|c| c.up).await.is_err() || w.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = w.shutdown().await;
+    });
+    let mut buf = vec![0u8; 65536];
+    loop {
+        match r.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if tx.send((shaper.stream(), buf[..n].to_vec())).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    drop(tx);
+    let _ = writer.await;
+}
+
+async fn run_tcp(
+    cfg: DelayLineConfig,
+    condition: watch::Receiver<Condition>,
+    seed: u64,
+    counters: Arc<LineCounters>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&cfg.listen).await?;
+    let mut connection: u64 = 0;
+    loop {
+        let (inbound, peer) = listener.accept().await?;
+        let outbound = match TcpStream::connect(&cfg.forward).await {
+            Ok(s) => s,
+            Err(err) => {
+                warn!("delay line {}: cannot connect {} for {}: {}", cfg.link, cfg.forward, peer, err);
+                continue;
+            }
+        };
+        let _ = inbound.set_nodelay(true);
+        let _ = outbound.set_nodelay(true);
+        connection += 1;
+        let s = seed.wrapping_add(connection);
+        let (ir, iw) = inbound.into_split();
+        let (or, ow) = outbound.into_split();
+        tokio::spawn(pump(ir, ow, Shaper::new(condition.clone(), s, counters.clone())));
+        tokio::spawn(pump(or, iw, Shaper::new(condition.clone(), s.rotate_left(32), counters.clone())));
+    }
+}
+
+struct Line {
+    cfg: DelayLineConfig,
+    condition: watch::Sender<Condition>,
+    counters: Arc<LineCounters>,
+}
+
+#[derive(Resource)]
+/// Resource holding the running delay lines.
+pub struct Netem {
+    lines: Vec<Line>,
+}
+
+impl Netem {
+    /// (link, condition, forwarded, dropped) of every line.
+    pub fn status(&self) -> Vec<(String, Condition, u64, u64)> {
+        self.lines
+            .iter()
+            .map(|l| {
+                (
+                    l.cfg.link.clone(),
+                    *l.condition.borrow(),
+                    l.counters.forwarded.load(Ordering::Relaxed),
+                    l.counters.dropped.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+fn start_netem(mut cmd: Commands, rt: Res<Runtime>, config: Res<NetemSettings>) {
+    let mut lines = Vec::new();
+    for (i, cfg) in config.0.lines.iter().enumerate() {
+        let (tx, rx) = watch::channel(Condition::default());
+        let counters = Arc::new(LineCounters::default());
+        let seed = config.0.seed.wrapping_add(i as u64 * 0x1_0000);
+        let (line, c) = (cfg.clone(), counters.clone());
+        rt.0.spawn(async move {
+            info!(
+                "delay line {:?} {} -> {} following {}",
+                line.protocol, line.listen, line.forward, line.link
+            );
+            let result = match line.protocol {
+                Protocol::Udp => run_udp(line.clone(), rx, seed, c).await,
+                Protocol::Tcp => run_tcp(line.clone(), rx, seed, c).await,
+            };
+            if let Err(err) = result {
+                error!("delay line {} on {} stopped: {}", line.link, line.listen, err);
+            }
+        });
+        lines.push(Line {
+            cfg: cfg.clone(),
+            condition: tx,
+            counters,
+        });
+    }
+    cmd.insert_resource(Netem { lines });
+}
+
+/// Drives every delay line by the current path of its data link. A line
+/// whose link does not exist runs without delay and loss, which is logged
+/// once until the link appears.
+fn update_netem(
+    netem: Res<Netem>,
+    links: Query<(&Name, Option<&DataLinkStats>), With<GSDataLink>>,
+    mut missing: Local<HashSet<usize>>,
+) {
+    for (i, line) in netem.lines.iter().enumerate() {
+        let link = links.iter().find(|(n, _)| n.as_str() == line.cfg.link);
+        if link.is_some() {
+            missing.remove(&i);
+        } else if missing.insert(i) {
+            warn!(
+                "delay line on {}: no data link named {:?}",
+                line.cfg.listen, line.cfg.link
+            );
+        }
+        let stats = link.and_then(|(_, s)| s);
+        let c = Condition::from_stats(stats, &line.cfg);
+        line.condition.send_if_modified(|old| {
+            let changed = *old != c;
+            *old = c;
+            changed
+        });
+    }
+}
+
+#[derive(Resource)]
+struct NetemSettings(NetemConfig);
+
+/// Emulates the data links on real localhost traffic, see [`NetemConfig`].
+#[derive(Default)]
+pub struct NetemPlugin {
+    pub config: NetemConfig,
+}
+
+impl Plugin for NetemPlugin {
+    fn build(&self, app: &mut App) {
+        if self.config.lines.is_empty() {
+            return;
+        }
+        app.insert_resource(NetemSettings(self.config.clone()));
+        app.add_systems(Startup, start_netem);
+        app.add_systems(PostUpdate, update_netem.run_if(resource_exists::<Netem>));
+    }
+}
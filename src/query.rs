@@ -0,0 +1,656 @@
+use std::{collections::VecDeque, fmt, sync::Arc};
+
+use bevy::{core::FrameCount, ecs::system::SystemParam, prelude::*};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::{propagate_sat_at, teme_to_lla, SimClock},
+    handover::HandoverConfig,
+    routing::{LiveContacts, Router, RoutingAlgorithm, RoutingContext, RoutingKind},
+    topology::{SatGraph, Scenario, ScenarioSat, ScenarioSource, TopologyConfig, LIGHT_SPEED},
+    util::geometry,
+};
+
+/// Version of the query schema, requests with another version are rejected.
+pub const QUERY_SCHEMA_VERSION: u32 = 1;
+
+/// Snapshots kept for path queries with an explicit time.
+const SNAPSHOT_CACHE: usize = 8;
+/// Scenarios and snapshots built per frame for queries, further queries are
+/// answered with [`QueryError::Busy`] so clients cannot stall the frame.
+const BUILDS_PER_FRAME: usize = 2;
+
+/// Observer location, either an existing ground station or coordinates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Site {
+    Station(u64),
+    Coordinates {
+        /// (deg)
+        lat: f64,
+        lon: f64,
+        /// (km)
+        #[serde(default)]
+        alt: f64,
+    },
+}
+
+/// Queries answered on demand. Times are unix timestamps (s), the
+/// simulation clock is used when they are omitted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SimQuery {
+    SatelliteState {
+        norad_id: u64,
+        time: Option<f64>,
+    },
+    /// Route between two ground stations given by their IDs.
+    Path {
+        from: u64,
+        to: u64,
+        time: Option<f64>,
+    },
+    Visibility {
+        site: Site,
+        time: Option<f64>,
+        /// (deg), the handover elevation mask by default
+        min_elevation: Option<f64>,
+    },
+    Passes {
+        norad_id: u64,
+        site: Site,
+        start: Option<f64>,
+        /// (s)
+        #[serde(default = "default_pass_window")]
+        duration: f64,
+        /// (s)
+        #[serde(default = "default_pass_step")]
+        step: f64,
+        min_elevation: Option<f64>,
+    },
+}
+
+fn default_pass_window() -> f64 {
+    86400.0
+}
+
+fn default_pass_step() -> f64 {
+    30.0
+}
+
+/// Fields every request carries, decoded first so that requests of another
+/// schema version can be rejected before their body is looked at.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RequestHeader {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub id: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueryRequest {
+    pub version: u32,
+    #[serde(default)]
+    pub id: u64,
+    pub query: SimQuery,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryError {
+    UnsupportedVersion(u32),
+    Malformed(String),
+    UnknownSatellite(u64),
+    UnknownStation(u64),
+    Diverged(u64),
+    InvalidArgument(String),
+    NoPath { from: u64, to: u64 },
+    /// the build budget of the frame is used up
+    Busy,
+    /// the simulation did not answer, e.g. while shutting down
+    Unavailable,
+}
+
+impl QueryError {
+    /// Stable identifier of the error kind for clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            QueryError::UnsupportedVersion(_) => "unsupported_version",
+            QueryError::Malformed(_) => "malformed",
+            QueryError::UnknownSatellite(_) => "unknown_satellite",
+            QueryError::UnknownStation(_) => "unknown_station",
+            QueryError::Diverged(_) => "diverged",
+            QueryError::InvalidArgument(_) => "invalid_argument",
+            QueryError::NoPath { .. } => "no_path",
+            QueryError::Busy => "busy",
+            QueryError::Unavailable => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::UnsupportedVersion(v) => write!(
+                f,
+                "schema version {} is not supported, expected {}",
+                v, QUERY_SCHEMA_VERSION
+            ),
+            QueryError::Malformed(err) => write!(f, "malformed request: {}", err),
+            QueryError::UnknownSatellite(id) => write!(f, "no satellite with NORAD ID {}", id),
+            QueryError::UnknownStation(id) => write!(f, "no ground station {}", id),
+            QueryError::Diverged(id) => write!(f, "propagation of {} diverged", id),
+            QueryError::InvalidArgument(err) => write!(f, "{}", err),
+            QueryError::NoPath { from, to } => write!(f, "no path from {} to {}", from, to),
+            QueryError::Busy => write!(f, "too many snapshot queries, retry in the next frame"),
+            QueryError::Unavailable => write!(f, "the simulation is not answering queries"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorReply {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SatelliteStateReply {
+    pub norad_id: u64,
+    pub name: String,
+    pub time: f64,
+    /// TEME position (km)
+    pub position: [f64; 3],
+    /// TEME velocity (km/s)
+    pub velocity: [f64; 3],
+    /// (deg)
+    pub lat: f64,
+    pub lon: f64,
+    /// (km)
+    pub alt: f64,
+    /// age of the element set (s)
+    pub tle_age: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PathNode {
+    /// `ground_station` or `satellite`
+    pub kind: &'static str,
+    /// ground station ID or NORAD ID
+    pub id: u64,
+    pub entity: u64,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PathReply {
+    pub time: f64,
+    pub algorithm: String,
+    pub nodes: Vec<PathNode>,
+    /// propagation delay of the path (s)
+    pub latency: f64,
+    /// time until the first bit arrives, differs from `latency` for store-and-forward routing (s)
+    pub delay: f64,
+    /// sum of the hop lengths at `time` (m)
+    pub distance: f64,
+    pub hop_latencies: Vec<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VisibleSat {
+    pub norad_id: u64,
+    pub name: String,
+    /// (deg)
+    pub azimuth: f64,
+    pub elevation: f64,
+    /// (m)
+    pub range: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Pass {
+    /// acquisition and loss of signal (unix s)
+    pub aos: f64,
+    pub los: f64,
+    pub max_elevation: f64,
+    pub max_elevation_time: f64,
+    pub aos_azimuth: f64,
+    pub los_azimuth: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QueryResult {
+    SatelliteState(SatelliteStateReply),
+    Path(PathReply),
+    Visibility { time: f64, satellites: Vec<VisibleSat> },
+    Passes { norad_id: u64, passes: Vec<Pass> },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryReply {
+    pub version: u32,
+    pub id: u64,
+    pub ok: bool,
+    pub error: Option<ErrorReply>,
+    pub result: Option<QueryResult>,
+}
+
+impl QueryReply {
+    pub fn new(id: u64, result: Result<QueryResult, QueryError>) -> Self {
+        let (result, error) = match result {
+            Ok(r) => (Some(r), None),
+            Err(err) => (
+                None,
+                Some(ErrorReply {
+                    code: err.code().to_string(),
+                    message: err.to_string(),
+                }),
+            ),
+        };
+        Self {
+            version: QUERY_SCHEMA_VERSION,
+            id,
+            ok: error.is_none(),
+            error,
+            result,
+        }
+    }
+}
+
+fn unix(t: &DateTime<Utc>) -> f64 {
+    t.timestamp_micros() as f64 / 1e6
+}
+
+fn from_unix(t: f64) -> Result<DateTime<Utc>, QueryError> {
+    DateTime::from_timestamp_micros((t * 1e6) as i64)
+        .filter(|_| t.is_finite())
+        .ok_or_else(|| QueryError::InvalidArgument(format!("invalid time {}", t)))
+}
+
+/// (azimuth deg, elevation deg, range m) of a satellite seen from `site` (lat, lon, alt m).
+fn look_at(sat: &ScenarioSat, site: (f64, f64, f64), t: &DateTime<Utc>) -> Option<(f64, f64, f64)> {
+    let (pos, _) = propagate_sat_at(&sat.epoch, &sat.constants, t).ok()?;
+    let lla = teme_to_lla(&pos.0, t);
+    Some(geometry::look_angles(site, (lla.0, lla.1, lla.2 * 1000.0)))
+}
+
+/// Bisects the time between `a` (below the mask) and `b` (above) or the reverse.
+fn refine_crossing(
+    sat: &ScenarioSat,
+    site: (f64, f64, f64),
+    min_elevation: f64,
+    mut a: DateTime<Utc>,
+    mut b: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let above = |t: &DateTime<Utc>| look_at(sat, site, t).is_some_and(|l| l.1 >= min_elevation);
+    let a_above = above(&a);
+    while (b - a).num_milliseconds() > 500 {
+        let mid = a + (b - a) / 2;
+        if above(&mid) == a_above {
+            a = mid;
+        } else {
+            b = mid;
+        }
+    }
+    b
+}
+
+/// Passes of a satellite over a site, found with a fixed step and refined to half a second.
+pub fn predict_passes(
+    sat: &ScenarioSat,
+    site: (f64, f64, f64),
+    start: DateTime<Utc>,
+    duration: f64,
+    step: f64,
+    min_elevation: f64,
+) -> Vec<Pass> {
+    let dt = chrono::Duration::milliseconds((step * 1000.0) as i64);
+    let end = start + chrono::Duration::milliseconds((duration * 1000.0) as i64);
+    let mut passes = Vec::new();
+    let mut current: Option<Pass> = None;
+    let mut prev = start;
+    let mut t = start;
+    while t <= end {
+        let visible = look_at(sat, site, &t).filter(|l| l.1 >= min_elevation);
+        match (visible, current.is_some()) {
+            (Some((az, el, _)), false) => {
+                let aos = if t == start {
+                    t
+                } else {
+                    refine_crossing(sat, site, min_elevation, prev, t)
+                };
+                current = Some(Pass {
+                    aos: unix(&aos),
+                    los: unix(&t),
+                    max_elevation: el,
+                    max_elevation_time: unix(&t),
+                    aos_azimuth: az,
+                    los_azimuth: az,
+                });
+            }
+            (Some((az, el, _)), true) => {
+                let pass = current.as_mut().unwrap();
+                pass.los = unix(&t);
+                pass.los_azimuth = az;
+                if el > pass.max_elevation {
+                    pass.max_elevation = el;
+                    pass.max_elevation_time = unix(&t);
+                }
+            }
+            (None, true) => {
+                let mut pass = current.take().unwrap();
+                let los = refine_crossing(sat, site, min_elevation, prev, t);
+                pass.los = unix(&los);
+                if let Some((az, _, _)) = look_at(sat, site, &los) {
+                    pass.los_azimuth = az;
+                }
+                passes.push(pass);
+            }
+            (None, false) => {}
+        }
+        prev = t;
+        t += dt;
+    }
+    passes.extend(current);
+    passes
+}
+
+/// Network snapshots of path queries with an explicit time, by time (µs),
+/// and the state the queries keep apart from the live network.
+#[derive(Default)]
+pub struct SnapshotCache {
+    graphs: VecDeque<(i64, Arc<SatGraph>)>,
+    /// scenario and the frame it was built in
+    scenario: Option<(u32, Arc<Scenario>)>,
+    /// frame of the last build and number of builds in it
+    budget: (u32, usize),
+    /// routing instance of the queries, so they never change the routes of
+    /// the live data links
+    router: Option<(RoutingKind, Box<dyn RoutingAlgorithm>)>,
+}
+
+/// The query routing instance of `kind`, rebuilt when the live router changes.
+fn query_router(
+    router: &mut Option<(RoutingKind, Box<dyn RoutingAlgorithm>)>,
+    kind: RoutingKind,
+) -> &mut dyn RoutingAlgorithm {
+    if router.as_ref().is_some_and(|(k, _)| *k != kind) {
+        *router = None;
+    }
+    &mut *router.get_or_insert_with(|| (kind, kind.build())).1
+}
+
+/// World access needed to answer [`SimQuery`]s.
+#[derive(SystemParam)]
+pub struct QueryWorld<'w, 's> {
+    clock: Res<'w, SimClock>,
+    frame: Res<'w, FrameCount>,
+    graph: Res<'w, SatGraph>,
+    topology: Res<'w, TopologyConfig>,
+    handover: Res<'w, HandoverConfig>,
+    router: Res<'w, Router>,
+    contacts: Res<'w, LiveContacts>,
+    source: ScenarioSource<'w, 's>,
+    snapshots: Local<'s, SnapshotCache>,
+}
+
+impl<'w, 's> QueryWorld<'w, 's> {
+    pub fn answer(&mut self, request: &QueryRequest) -> QueryReply {
+        let result = if request.version != QUERY_SCHEMA_VERSION {
+            Err(QueryError::UnsupportedVersion(request.version))
+        } else {
+            self.run(&request.query)
+        };
+        QueryReply::new(request.id, result)
+    }
+
+    /// Takes one build from the budget of the frame.
+    fn spend(&mut self) -> Result<(), QueryError> {
+        let frame = self.frame.0;
+        let budget = &mut self.snapshots.budget;
+        if budget.0 != frame {
+            *budget = (frame, 0);
+        }
+        if budget.1 >= BUILDS_PER_FRAME {
+            return Err(QueryError::Busy);
+        }
+        budget.1 += 1;
+        Ok(())
+    }
+
+    /// Scenario of the live world, built at most once per frame and within
+    /// the budget.
+    fn scenario(&mut self) -> Result<Arc<Scenario>, QueryError> {
+        let frame = self.frame.0;
+        if let Some((built, scenario)) = &self.snapshots.scenario {
+            if *built == frame {
+                return Ok(scenario.clone());
+            }
+        }
+        self.spend()?;
+        let scenario = Arc::new(self.source.build());
+        self.snapshots.scenario = Some((frame, scenario.clone()));
+        Ok(scenario)
+    }
+
+    /// Network at `t`, from the cache or built within the budget of the frame.
+    fn snapshot(
+        &mut self,
+        scenario: &Scenario,
+        t: &DateTime<Utc>,
+    ) -> Result<Arc<SatGraph>, QueryError> {
+        if self.source.elements_changed() || self.topology.is_changed() {
+            self.snapshots.graphs.clear();
+        }
+        let key = t.timestamp_micros();
+        if let Some((_, graph)) = self.snapshots.graphs.iter().find(|(k, _)| *k == key) {
+            return Ok(graph.clone());
+        }
+        self.spend()?;
+        let graph = Arc::new(scenario.snapshot(t, &self.topology, self.handover.min_elevation));
+        let cache = &mut *self.snapshots;
+        if cache.graphs.len() >= SNAPSHOT_CACHE {
+            cache.graphs.pop_front();
+        }
+        cache.graphs.push_back((key, graph.clone()));
+        Ok(graph)
+    }
+
+    fn time(&self, t: Option<f64>) -> Result<DateTime<Utc>, QueryError> {
+        t.map_or(Ok(self.clock.now), from_unix)
+    }
+
+    fn site(&self, site: &Site) -> Result<(f64, f64, f64), QueryError> {
+        match site {
+            Site::Station(id) => self
+                .source
+                .ground()
+                .iter()
+                .find(|gs| gs.id == *id)
+                .map(|gs| gs.lla)
+                .ok_or(QueryError::UnknownStation(*id)),
+            Site::Coordinates { lat, lon, alt } => {
+                if !(lat.abs() <= 90.0) || !lon.is_finite() || !alt.is_finite() {
+                    Err(QueryError::InvalidArgument(format!("invalid site {}, {}", lat, lon)))
+                } else {
+                    Ok((*lat, *lon, alt * 1000.0))
+                }
+            }
+        }
+    }
+
+    fn run(&mut self, query: &SimQuery) -> Result<QueryResult, QueryError> {
+        match query {
+            SimQuery::SatelliteState { norad_id, time } => {
+                let t = self.time(*time)?;
+                let sat = self
+                    .source
+                    .satellite(*norad_id)
+                    .ok_or(QueryError::UnknownSatellite(*norad_id))?;
+                let (pos, vel) = propagate_sat_at(&sat.epoch, &sat.constants, &t)
+                    .map_err(|_| QueryError::Diverged(*norad_id))?;
+                let lla = teme_to_lla(&pos.0, &t);
+                Ok(QueryResult::SatelliteState(SatelliteStateReply {
+                    norad_id: *norad_id,
+                    name: sat.name,
+                    time: unix(&t),
+                    position: pos.0,
+                    velocity: vel.0,
+                    lat: lla.0,
+                    lon: lla.1,
+                    alt: lla.2,
+                    tle_age: (t.naive_utc() - sat.epoch).num_milliseconds() as f64 / 1000.0,
+                }))
+            }
+            SimQuery::Path { from, to, time } => {
+                let t = self.time(*time)?;
+                let scenario = self.scenario()?;
+                let station = |id: u64| {
+                    scenario
+                        .ground
+                        .iter()
+                        .find(|gs| gs.id == id)
+                        .map(|gs| gs.entity)
+                        .ok_or(QueryError::UnknownStation(id))
+                };
+                let (a, b) = (station(*from)?, station(*to)?);
+                let snapshot = match time {
+                    Some(_) => Some(self.snapshot(&scenario, &t)?),
+                    None => None,
+                };
+                let graph = snapshot.as_deref().unwrap_or(&*self.graph);
+                // contact graph routing uses the live prediction, it is too
+                // expensive to predict the contacts for a query
+                let contacts = match self.router.algorithm.contact_plan() {
+                    Some(_) => {
+                        let live = self.contacts.graph.as_deref().ok_or(QueryError::Busy)?;
+                        let elapsed = (t - live.start).num_milliseconds() as f64 / 1000.0;
+                        if !(0.0..live.end).contains(&elapsed) {
+                            return Err(QueryError::InvalidArgument(
+                                "time outside the predicted contact graph".into(),
+                            ));
+                        }
+                        Some(live)
+                    }
+                    None => None,
+                };
+                let ctx = RoutingContext {
+                    graph,
+                    time: t,
+                    topology: &self.topology,
+                    min_elevation: self.handover.min_elevation,
+                    scenario: contacts.is_none().then_some(&*scenario),
+                    contacts,
+                };
+                let route = query_router(&mut self.snapshots.router, self.router.kind)
+                    .route(&ctx, a, b)
+                    .ok_or(QueryError::NoPath { from: *from, to: *to })?;
+
+                let nodes = route
+                    .path
+                    .nodes
+                    .iter()
+                    .map(|e| {
+                        if let Some(gs) = scenario.ground.iter().find(|gs| gs.entity == *e) {
+                            PathNode {
+                                kind: "ground_station",
+                                id: gs.id,
+                                entity: e.to_bits(),
+                                name: gs.name.clone(),
+                            }
+                        } else {
+                            let sat = scenario.sats.iter().find(|s| s.entity == *e);
+                            PathNode {
+                                kind: "satellite",
+                                id: sat.map(|s| s.norad_id).unwrap_or_default(),
+                                entity: e.to_bits(),
+                                name: sat.map(|s| s.name.clone()).unwrap_or_default(),
+                            }
+                        }
+                    })
+                    .collect();
+                let hop_distances: Vec<f64> = route
+                    .path
+                    .nodes
+                    .windows(2)
+                    .map(|w| match (graph.position(w[0]), graph.position(w[1])) {
+                        (Some(a), Some(b)) => {
+                            let d = (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2);
+                            d.sqrt()
+                        }
+                        _ => f64::NAN,
+                    })
+                    .collect();
+                let hop_latencies: Vec<f64> =
+                    hop_distances.iter().map(|d| d / LIGHT_SPEED).collect();
+                Ok(QueryResult::Path(PathReply {
+                    time: unix(&t),
+                    algorithm: self.router.algorithm.name().to_string(),
+                    nodes,
+                    latency: route.path.latency(),
+                    delay: route.delay,
+                    distance: hop_distances.iter().sum(),
+                    hop_latencies,
+                }))
+            }
+            SimQuery::Visibility {
+                site,
+                time,
+                min_elevation,
+            } => {
+                let t = self.time(*time)?;
+                let site = self.site(site)?;
+                let scenario = self.scenario()?;
+                let mask = min_elevation.unwrap_or(self.handover.min_elevation);
+                let mut satellites: Vec<_> = scenario
+                    .sats
+                    .iter()
+                    .filter_map(|s| {
+                        let (azimuth, elevation, range) = look_at(s, site, &t)?;
+                        (elevation >= mask).then(|| VisibleSat {
+                            norad_id: s.norad_id,
+                            name: s.name.clone(),
+                            azimuth,
+                            elevation,
+                            range,
+                        })
+                    })
+                    .collect();
+                satellites.sort_by(|a, b| b.elevation.total_cmp(&a.elevation));
+                Ok(QueryResult::Visibility {
+                    time: unix(&t),
+                    satellites,
+                })
+            }
+            SimQuery::Passes {
+                norad_id,
+                site,
+                start,
+                duration,
+                step,
+                min_elevation,
+            } => {
+                if !(1.0..=7.0 * 86400.0).contains(duration) || !(1.0..=600.0).contains(step) {
+                    return Err(QueryError::InvalidArgument(
+                        "duration must be within 1 s and 7 days, step within 1 and 600 s".into(),
+                    ));
+                }
+                let t = self.time(*start)?;
+                let sat = self
+                    .source
+                    .satellite(*norad_id)
+                    .ok_or(QueryError::UnknownSatellite(*norad_id))?;
+                let site = self.site(site)?;
+                let mask = min_elevation.unwrap_or(self.handover.min_elevation);
+                Ok(QueryResult::Passes {
+                    norad_id: *norad_id,
+                    passes: predict_passes(&sat, site, t, *duration, *step, mask),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,793 @@
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    datalink::LatencySummary,
+    export::csv::escape,
+    topology::{GraphPath, SatGraph, Scenario, TopologyConfig, LIGHT_SPEED},
+    util::geometry,
+};
+
+/// Inputs available to a routing algorithm for one routing decision.
+pub struct RoutingContext<'a> {
+    /// topology at `time`
+    pub graph: &'a SatGraph,
+    pub time: DateTime<Utc>,
+    pub topology: &'a TopologyConfig,
+    pub min_elevation: f64,
+    /// only set when the algorithm asks for it, see [`RoutingAlgorithm::needs_scenario`]
+    pub scenario: Option<&'a Scenario>,
+    /// precomputed contacts, used by batch runs to share one contact graph
+    pub contacts: Option<&'a ContactGraph>,
+}
+
+/// A route and the time the first bit needs to reach the destination (s).
+/// `delay` equals the propagation delay of the path except for store-and-forward routing.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub path: GraphPath,
+    pub delay: f64,
+}
+
+impl From<GraphPath> for Route {
+    fn from(path: GraphPath) -> Self {
+        let delay = path.latency();
+        Self { path, delay }
+    }
+}
+
+/// Computes the path of a data link between two ground stations.
+pub trait RoutingAlgorithm: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the algorithm needs to propagate the constellation itself.
+    /// Building a [`Scenario`] clones every element set, so only request it when needed.
+    fn needs_scenario(&self) -> bool {
+        false
+    }
+
+    /// Horizon and step (s) of the contact graph the algorithm routes on, if
+    /// any. The live data links pass a graph built in the background as
+    /// [`RoutingContext::contacts`], see [`LiveContacts`].
+    fn contact_plan(&self) -> Option<(f64, f64)> {
+        None
+    }
+
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route>;
+}
+
+/// Plain Dijkstra on the current topology, recomputed on every request.
+#[derive(Default)]
+pub struct ShortestPathRouting;
+
+impl RoutingAlgorithm for ShortestPathRouting {
+    fn name(&self) -> &'static str {
+        "Shortest Path"
+    }
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route> {
+        ctx.graph.shortest_path(src, dst).map(Route::from)
+    }
+}
+
+/**
+Virtual topology (snapshot) routing.
+
+Time is divided into intervals in which the topology is treated as fixed.
+Routes are computed once per interval and kept until the interval ends,
+unless an edge disappears earlier.
+*/
+pub struct SnapshotRouting {
+    /// length of one snapshot (s)
+    pub interval: f64,
+    table: HashMap<(Entity, Entity), (i64, GraphPath)>,
+}
+
+impl Default for SnapshotRouting {
+    fn default() -> Self {
+        Self {
+            interval: 60.0,
+            table: HashMap::new(),
+        }
+    }
+}
+
+impl RoutingAlgorithm for SnapshotRouting {
+    fn name(&self) -> &'static str {
+        "Snapshot"
+    }
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route> {
+        let snapshot = (ctx.time.timestamp_millis() as f64 / 1000.0 / self.interval).floor() as i64;
+        if let Some((s, path)) = self.table.get(&(src, dst)) {
+            let cost = ctx.graph.cost_of(&path.nodes);
+            if *s == snapshot && cost.is_finite() {
+                return Some(
+                    GraphPath {
+                        nodes: path.nodes.clone(),
+                        cost,
+                    }
+                    .into(),
+                );
+            }
+        }
+        let path = ctx.graph.shortest_path(src, dst)?;
+        self.table.insert((src, dst), (snapshot, path.clone()));
+        Some(path.into())
+    }
+}
+
+/**
+Virtual node (footprint based) routing.
+
+The Earth surface is divided into a fixed grid of logical locations, each one
+served by the satellite closest to its centre. Routes are computed on the
+logical grid, which does not change with satellite motion, then mapped to
+the satellites currently serving the cells.
+*/
+pub struct VirtualNodeRouting {
+    /// number of latitude bands
+    pub rows: usize,
+    /// number of longitude bands
+    pub cols: usize,
+}
+
+impl Default for VirtualNodeRouting {
+    fn default() -> Self {
+        Self { rows: 12, cols: 24 }
+    }
+}
+
+impl VirtualNodeRouting {
+    fn cell(&self, p: [f64; 3]) -> (usize, usize) {
+        let (lat, lon) = geometry::ecef_to_latlon(p);
+        let r = (((lat + 90.0) / 180.0) * self.rows as f64) as usize;
+        let c = (((lon + 180.0) / 360.0) * self.cols as f64) as usize;
+        (r.min(self.rows - 1), c.min(self.cols - 1))
+    }
+
+    /// Satellite whose sub satellite point is the closest to each cell centre.
+    fn virtual_nodes(&self, graph: &SatGraph) -> HashMap<(usize, usize), Entity> {
+        let mut best: HashMap<(usize, usize), (Entity, f64)> = HashMap::new();
+        for (i, e) in graph.nodes.iter().enumerate() {
+            if !graph.transit[i] {
+                continue;
+            }
+            let cell = self.cell(graph.pos[i]);
+            let (lat, lon) = geometry::ecef_to_latlon(graph.pos[i]);
+            let centre_lat = (cell.0 as f64 + 0.5) * 180.0 / self.rows as f64 - 90.0;
+            let centre_lon = (cell.1 as f64 + 0.5) * 360.0 / self.cols as f64 - 180.0;
+            let d = crate::util::distance::geodegree((lat, lon), (centre_lat, centre_lon));
+            match best.get(&cell) {
+                Some((_, bd)) if *bd <= d => {}
+                _ => {
+                    best.insert(cell, (*e, d));
+                }
+            }
+        }
+        best.into_iter().map(|(k, (e, _))| (k, e)).collect()
+    }
+
+    /// Minimum hop walk on the cell grid, longitude wraps around.
+    fn logical_route(&self, from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut cells = vec![from];
+        let (mut r, mut c) = from;
+        let cols = self.cols as i64;
+        let mut dc = to.1 as i64 - c as i64;
+        if dc > cols / 2 {
+            dc -= cols;
+        } else if dc < -cols / 2 {
+            dc += cols;
+        }
+        while (r, c) != to {
+            // alternate between the two directions to stay close to the great circle
+            let step_col = dc != 0 && (r == to.0 || cells.len() % 2 == 0);
+            if step_col {
+                let s = dc.signum();
+                c = ((c as i64 + s).rem_euclid(cols)) as usize;
+                dc -= s;
+            } else if r < to.0 {
+                r += 1;
+            } else {
+                r -= 1;
+            }
+            cells.push((r, c));
+        }
+        cells
+    }
+}
+
+impl RoutingAlgorithm for VirtualNodeRouting {
+    fn name(&self) -> &'static str {
+        "Virtual Node"
+    }
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route> {
+        let graph = ctx.graph;
+        let nearest_access = |gs: Entity| {
+            let p = graph.position(gs)?;
+            graph
+                .neighbours(gs)
+                .into_iter()
+                .filter_map(|s| graph.position(s).map(|q| (s, q)))
+                .min_by(|a, b| {
+                    let da = (a.1[0] - p[0]).powi(2) + (a.1[1] - p[1]).powi(2) + (a.1[2] - p[2]).powi(2);
+                    let db = (b.1[0] - p[0]).powi(2) + (b.1[1] - p[1]).powi(2) + (b.1[2] - p[2]).powi(2);
+                    da.total_cmp(&db)
+                })
+        };
+        let (first, first_pos) = nearest_access(src)?;
+        let (last, last_pos) = nearest_access(dst)?;
+
+        let vn = self.virtual_nodes(graph);
+        let mut hops = vec![first];
+        for cell in self.logical_route(self.cell(first_pos), self.cell(last_pos)) {
+            if let Some(s) = vn.get(&cell) {
+                if hops.last() != Some(s) {
+                    hops.push(*s);
+                }
+            }
+        }
+        if hops.last() != Some(&last) {
+            hops.push(last);
+        }
+
+        // map the logical hops to physical links
+        let mut nodes = vec![src, first];
+        for w in hops.windows(2) {
+            if graph.has_edge(w[0], w[1]) {
+                nodes.push(w[1]);
+                continue;
+            }
+            let (a, b) = (graph.index[&w[0]], graph.index[&w[1]]);
+            let (segment, _) = graph.dijkstra_idx(a, b, &HashSet::new(), &HashSet::new())?;
+            nodes.extend(segment[1..].iter().map(|i| graph.nodes[*i]));
+        }
+        nodes.push(dst);
+        // the mapping can revisit a satellite, cut the loops
+        let mut seen = HashMap::new();
+        let mut path: Vec<Entity> = Vec::new();
+        for n in nodes {
+            if let Some(i) = seen.get(&n) {
+                path.truncate(*i + 1);
+                seen.retain(|_, v| *v <= *i);
+                continue;
+            }
+            seen.insert(n, path.len());
+            path.push(n);
+        }
+        let cost = graph.cost_of(&path);
+        cost.is_finite().then(|| GraphPath { nodes: path, cost }.into())
+    }
+}
+
+/**
+Datagram Routing Algorithm style forwarding.
+
+Every satellite forwards hop by hop to the neighbour closest to the
+destination, without global knowledge. A packet is dropped when no
+neighbour makes progress or the hop limit is reached.
+*/
+pub struct DatagramRouting {
+    pub max_hops: usize,
+}
+
+impl Default for DatagramRouting {
+    fn default() -> Self {
+        Self { max_hops: 64 }
+    }
+}
+
+impl RoutingAlgorithm for DatagramRouting {
+    fn name(&self) -> &'static str {
+        "Datagram (DRA)"
+    }
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route> {
+        let graph = ctx.graph;
+        let target = graph.position(dst)?;
+        let remaining = |e: Entity| {
+            graph.position(e).map_or(f64::INFINITY, |p| {
+                ((p[0] - target[0]).powi(2) + (p[1] - target[1]).powi(2) + (p[2] - target[2]).powi(2))
+                    .sqrt()
+            })
+        };
+        let mut path = vec![src];
+        let mut visited: HashSet<Entity> = HashSet::from([src]);
+        let mut current = src;
+        while path.len() <= self.max_hops {
+            let neighbours = graph.neighbours(current);
+            if neighbours.contains(&dst) {
+                path.push(dst);
+                let cost = graph.cost_of(&path);
+                return Some(GraphPath { nodes: path, cost }.into());
+            }
+            let here = if current == src { f64::INFINITY } else { remaining(current) };
+            let next = neighbours
+                .into_iter()
+                .filter(|n| graph.is_transit(*n) && !visited.contains(n))
+                .map(|n| (n, remaining(n)))
+                .filter(|(_, d)| *d < here)
+                .min_by(|a, b| a.1.total_cmp(&b.1))?;
+            visited.insert(next.0);
+            path.push(next.0);
+            current = next.0;
+        }
+        None
+    }
+}
+
+/// A window in which two nodes can communicate, times are seconds after [`ContactGraph::start`].
+#[derive(Clone, Debug)]
+pub struct Contact {
+    pub from: Entity,
+    pub to: Entity,
+    pub start: f64,
+    pub end: f64,
+    /// range at the start of the window (m)
+    pub range: f64,
+}
+
+/// Contacts of a series of topology snapshots.
+#[derive(Default)]
+pub struct ContactGraph {
+    pub start: DateTime<Utc>,
+    pub end: f64,
+    pub contacts: Vec<Contact>,
+    pub by_node: HashMap<Entity, Vec<usize>>,
+    pub transit: HashSet<Entity>,
+}
+
+impl ContactGraph {
+    /// Predicts the contacts of a scenario over `horizon` seconds from `start`.
+    pub fn predict(
+        scenario: &Scenario,
+        start: DateTime<Utc>,
+        horizon: f64,
+        step: f64,
+        topology: &TopologyConfig,
+        min_elevation: f64,
+    ) -> Self {
+        let steps = (horizon / step).ceil() as i64;
+        let snapshots: Vec<_> = (0..steps)
+            .map(|k| {
+                let t = start + chrono::Duration::milliseconds((k as f64 * step * 1000.0) as i64);
+                (t, scenario.snapshot(&t, topology, min_elevation))
+            })
+            .collect();
+        Self::from_snapshots(&snapshots, step)
+    }
+
+    /// Merges edges that exist in consecutive snapshots into contacts.
+    /// `snapshots` must be sorted by time and evenly spaced by `step` seconds.
+    pub fn from_snapshots(snapshots: &[(DateTime<Utc>, SatGraph)], step: f64) -> Self {
+        let Some((start, _)) = snapshots.first() else {
+            return Self::default();
+        };
+        let mut graph = ContactGraph {
+            start: *start,
+            end: step * snapshots.len() as f64,
+            ..Default::default()
+        };
+        let mut open: HashMap<(Entity, Entity), (f64, f64)> = HashMap::new();
+        for (k, (_, g)) in snapshots.iter().enumerate() {
+            let t = k as f64 * step;
+            let mut present = HashSet::new();
+            for (i, adj) in g.adj.iter().enumerate() {
+                if g.transit[i] {
+                    graph.transit.insert(g.nodes[i]);
+                }
+                for (j, d) in adj {
+                    let key = (g.nodes[i], g.nodes[*j]);
+                    present.insert(key);
+                    open.entry(key).or_insert((t, *d));
+                }
+            }
+            let mut closed: Vec<_> = open
+                .keys()
+                .filter(|k| !present.contains(k))
+                .copied()
+                .collect();
+            // contact order must not depend on the hash map
+            closed.sort_unstable();
+            for key in closed {
+                let (s, range) = open.remove(&key).unwrap();
+                graph.push(key, s, t, range);
+            }
+        }
+        let end = graph.end;
+        let mut open: Vec<_> = open.into_iter().collect();
+        open.sort_unstable_by_key(|(key, _)| *key);
+        for (key, (s, range)) in open {
+            graph.push(key, s, end, range);
+        }
+        graph
+    }
+
+    fn push(&mut self, (from, to): (Entity, Entity), start: f64, end: f64, range: f64) {
+        self.by_node.entry(from).or_default().push(self.contacts.len());
+        self.contacts.push(Contact {
+            from,
+            to,
+            start,
+            end,
+            range,
+        });
+    }
+
+    /// Earliest arrival route (CGR Dijkstra) leaving `src` at `t` seconds after start.
+    pub fn earliest_arrival(&self, src: Entity, dst: Entity, t: f64) -> Option<(Vec<Entity>, f64, f64)> {
+        #[derive(PartialEq)]
+        struct Arrival(f64, Entity);
+        impl Eq for Arrival {}
+        impl Ord for Arrival {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                other.0.total_cmp(&self.0)
+            }
+        }
+        impl PartialOrd for Arrival {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        let mut arrival: HashMap<Entity, f64> = HashMap::from([(src, t)]);
+        let mut prev: HashMap<Entity, usize> = HashMap::new();
+        let mut heap = BinaryHeap::from([Arrival(t, src)]);
+        while let Some(Arrival(at, node)) = heap.pop() {
+            if node == dst {
+                let mut nodes = vec![dst];
+                let mut range = 0.0;
+                let mut cur = dst;
+                while cur != src {
+                    let c = &self.contacts[prev[&cur]];
+                    range += c.range;
+                    cur = c.from;
+                    nodes.push(cur);
+                }
+                nodes.reverse();
+                return Some((nodes, at - t, range));
+            }
+            if at > arrival[&node] || (node != src && !self.transit.contains(&node)) {
+                continue;
+            }
+            for &ci in self.by_node.get(&node).into_iter().flatten() {
+                let c = &self.contacts[ci];
+                if c.end < at {
+                    continue;
+                }
+                let next = at.max(c.start) + c.range / LIGHT_SPEED;
+                if next < *arrival.get(&c.to).unwrap_or(&f64::INFINITY) {
+                    arrival.insert(c.to, next);
+                    prev.insert(c.to, ci);
+                    heap.push(Arrival(next, c.to));
+                }
+            }
+        }
+        None
+    }
+}
+
+/**
+Contact graph routing.
+
+Routes over predicted contacts instead of the current topology, so a path
+may wait on a satellite for a future contact (store-and-forward).
+The contact graph is predicted from the element sets over `horizon` seconds
+and rebuilt when half of it has elapsed. The live data links get it from
+[`LiveContacts`] instead, and use the shortest path until the first graph is ready.
+*/
+pub struct ContactGraphRouting {
+    pub horizon: f64,
+    pub step: f64,
+    cache: Option<ContactGraph>,
+}
+
+impl Default for ContactGraphRouting {
+    fn default() -> Self {
+        Self {
+            horizon: 600.0,
+            step: 30.0,
+            cache: None,
+        }
+    }
+}
+
+impl RoutingAlgorithm for ContactGraphRouting {
+    fn name(&self) -> &'static str {
+        "Contact Graph (CGR)"
+    }
+    fn needs_scenario(&self) -> bool {
+        true
+    }
+    fn contact_plan(&self) -> Option<(f64, f64)> {
+        Some((self.horizon, self.step))
+    }
+    fn route(&mut self, ctx: &RoutingContext, src: Entity, dst: Entity) -> Option<Route> {
+        let contacts = match (ctx.contacts, ctx.scenario) {
+            (Some(c), _) => c,
+            (None, Some(scenario)) => {
+                let stale = self.cache.as_ref().map_or(true, |c| {
+                    let elapsed = (ctx.time - c.start).num_milliseconds() as f64 / 1000.0;
+                    elapsed < 0.0 || elapsed > 0.5 * self.horizon
+                });
+                if stale {
+                    self.cache = Some(ContactGraph::predict(
+                        scenario,
+                        ctx.time,
+                        self.horizon,
+                        self.step,
+                        ctx.topology,
+                        ctx.min_elevation,
+                    ));
+                }
+                self.cache.as_ref().unwrap()
+            }
+            (None, None) => return ctx.graph.shortest_path(src, dst).map(Route::from),
+        };
+        let t = (ctx.time - contacts.start).num_milliseconds() as f64 / 1000.0;
+        let (nodes, delay, cost) = contacts.earliest_arrival(src, dst, t)?;
+        Some(Route {
+            path: GraphPath { nodes, cost },
+            delay,
+        })
+    }
+}
+
+/**
+Contact graph of the live data links. It is predicted on the runtime in the
+background, the links keep routing on the previous graph until the new one is
+ready. Recorded and replayed sessions wait for it, so the routes do not depend
+on how long the prediction takes.
+*/
+#[derive(Resource, Default)]
+pub struct LiveContacts {
+    pub graph: Option<Arc<ContactGraph>>,
+    /// horizon and step of `graph`
+    pub plan: Option<(f64, f64)>,
+    pub pending: Option<((f64, f64), tokio::task::JoinHandle<ContactGraph>)>,
+}
+
+impl LiveContacts {
+    /// Whether a new graph is needed at `now` for the plan of the router.
+    pub fn stale(&self, now: DateTime<Utc>, plan: (f64, f64)) -> bool {
+        self.plan != Some(plan)
+            || self.graph.as_ref().map_or(true, |c| {
+                let elapsed = (now - c.start).num_milliseconds() as f64 / 1000.0;
+                elapsed < 0.0 || elapsed > 0.5 * plan.0
+            })
+    }
+}
+
+/// Built-in routing algorithms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingKind {
+    #[default]
+    ShortestPath,
+    Snapshot,
+    VirtualNode,
+    Datagram,
+    ContactGraph,
+}
+
+impl RoutingKind {
+    pub const ALL: [RoutingKind; 5] = [
+        RoutingKind::ShortestPath,
+        RoutingKind::Snapshot,
+        RoutingKind::VirtualNode,
+        RoutingKind::Datagram,
+        RoutingKind::ContactGraph,
+    ];
+
+    pub fn build(&self) -> Box<dyn RoutingAlgorithm> {
+        match self {
+            RoutingKind::ShortestPath => Box::new(ShortestPathRouting),
+            RoutingKind::Snapshot => Box::new(SnapshotRouting::default()),
+            RoutingKind::VirtualNode => Box::new(VirtualNodeRouting::default()),
+            RoutingKind::Datagram => Box::new(DatagramRouting::default()),
+            RoutingKind::ContactGraph => Box::new(ContactGraphRouting::default()),
+        }
+    }
+}
+
+/// Routing algorithm used for the primary path of every data link.
+#[derive(Resource)]
+pub struct Router {
+    pub kind: RoutingKind,
+    pub algorithm: Box<dyn RoutingAlgorithm>,
+}
+
+impl Router {
+    pub fn new(kind: RoutingKind) -> Self {
+        Self {
+            kind,
+            algorithm: kind.build(),
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(RoutingKind::default())
+    }
+}
+
+/// Result of one algorithm for one link at one time step.
+#[derive(Clone, Debug)]
+pub struct RouteSample {
+    pub algorithm: &'static str,
+    pub link: String,
+    pub time: DateTime<Utc>,
+    /// None if no route was found
+    pub delay: Option<f64>,
+    pub hops: usize,
+    /// the node sequence differs from the previous path
+    pub changed: bool,
+    /// Jaccard distance between the relays of the previous path and this one
+    pub churn: f64,
+}
+
+/// Aggregated results of one algorithm over a batch run.
+#[derive(Clone, Debug)]
+pub struct AlgorithmReport {
+    pub algorithm: &'static str,
+    pub samples: usize,
+    pub failures: usize,
+    pub latency: Option<LatencySummary>,
+    pub mean_hops: f64,
+    /// number of path changes
+    pub path_changes: usize,
+    pub mean_churn: f64,
+}
+
+#[derive(Default)]
+pub struct ComparisonReport {
+    pub samples: Vec<RouteSample>,
+    pub algorithms: Vec<AlgorithmReport>,
+}
+
+/// Jaccard distance of the relay nodes of two paths: 0 for the same relays,
+/// 1 if they share none.
+fn churn(prev: &[Entity], next: &[Entity]) -> f64 {
+    let inner = |p: &[Entity]| -> HashSet<Entity> {
+        p.iter().skip(1).take(p.len().saturating_sub(2)).copied().collect()
+    };
+    let (a, b) = (inner(prev), inner(next));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    1.0 - a.intersection(&b).count() as f64 / union as f64
+}
+
+/**
+Runs every algorithm on the same series of snapshots and collects latency,
+hop count and path churn for all links of the scenario.
+*/
+pub fn compare_algorithms(
+    scenario: &Scenario,
+    kinds: &[RoutingKind],
+    start: DateTime<Utc>,
+    steps: usize,
+    step: f64,
+    topology: &TopologyConfig,
+    min_elevation: f64,
+) -> ComparisonReport {
+    let snapshots: Vec<_> = (0..steps)
+        .map(|k| {
+            let t = start + chrono::Duration::milliseconds((k as f64 * step * 1000.0) as i64);
+            (t, scenario.snapshot(&t, topology, min_elevation))
+        })
+        .collect();
+    let contacts = ContactGraph::from_snapshots(&snapshots, step);
+
+    let mut report = ComparisonReport::default();
+    for kind in kinds {
+        let mut algorithm = kind.build();
+        let mut previous: HashMap<&str, Vec<Entity>> = HashMap::new();
+        let mut samples = Vec::new();
+        for (t, graph) in &snapshots {
+            let ctx = RoutingContext {
+                graph,
+                time: *t,
+                topology,
+                min_elevation,
+                scenario: Some(scenario),
+                contacts: Some(&contacts),
+            };
+            for (name, a, b) in &scenario.links {
+                let route = algorithm.route(&ctx, *a, *b);
+                let nodes = route.as_ref().map(|r| r.path.nodes.clone()).unwrap_or_default();
+                let prev = previous.get(name.as_str());
+                let c = prev.map_or(0.0, |p| churn(p, &nodes));
+                let changed = !nodes.is_empty() && prev.is_some_and(|p| *p != nodes);
+                samples.push(RouteSample {
+                    algorithm: algorithm.name(),
+                    link: name.clone(),
+                    time: *t,
+                    delay: route.as_ref().map(|r| r.delay),
+                    hops: nodes.len().saturating_sub(1),
+                    changed,
+                    churn: c,
+                });
+                if !nodes.is_empty() {
+                    previous.insert(name.as_str(), nodes);
+                }
+            }
+        }
+        let ok: Vec<_> = samples.iter().filter(|s| s.delay.is_some()).collect();
+        report.algorithms.push(AlgorithmReport {
+            algorithm: algorithm.name(),
+            samples: samples.len(),
+            failures: samples.len() - ok.len(),
+            latency: LatencySummary::from_latencies(ok.iter().map(|s| s.delay.unwrap())),
+            mean_hops: ok.iter().map(|s| s.hops as f64).sum::<f64>() / ok.len().max(1) as f64,
+            path_changes: ok.iter().filter(|s| s.changed).count(),
+            mean_churn: ok.iter().map(|s| s.churn).sum::<f64>() / ok.len().max(1) as f64,
+        });
+        report.samples.extend(samples);
+    }
+    report
+}
+
+impl ComparisonReport {
+    /// Writes the summary table as markdown.
+    pub fn write_summary<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(
+            w,
+            "| algorithm | samples | failures | min (ms) | mean (ms) | p95 (ms) | p99 (ms) | max (ms) | mean hops | path changes | mean churn |"
+        )?;
+        writeln!(w, "|---|---|---|---|---|---|---|---|---|---|---|")?;
+        for a in &self.algorithms {
+            let l = a.latency.unwrap_or_default();
+            writeln!(
+                w,
+                "| {} | {} | {} | {:.3} | {:.3} | {:.3} | {:.3} | {:.3} | {:.2} | {} | {:.3} |",
+                a.algorithm,
+                a.samples,
+                a.failures,
+                1e3 * l.min,
+                1e3 * l.mean,
+                1e3 * l.p95,
+                1e3 * l.p99,
+                1e3 * l.max,
+                a.mean_hops,
+                a.path_changes,
+                a.mean_churn
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes every sample as CSV.
+    pub fn write_samples<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "algorithm,link,time,delay_ms,hops,changed,churn")?;
+        for s in &self.samples {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{:.4}",
+                escape(s.algorithm),
+                escape(&s.link),
+                s.time.to_rfc3339(),
+                s.delay.map(|d| format!("{:.6}", 1e3 * d)).unwrap_or_default(),
+                s.hops,
+                s.changed,
+                s.churn
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes `<stem>.md` and `<stem>.csv`.
+    pub fn save(&self, stem: &Path) -> std::io::Result<()> {
+        let mut f = std::fs::File::create(stem.with_extension("md"))?;
+        self.write_summary(&mut f)?;
+        let mut f = std::fs::File::create(stem.with_extension("csv"))?;
+        self.write_samples(&mut f)
+    }
+}
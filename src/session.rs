@@ -0,0 +1,562 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::{app::AppExit, ecs::system::SystemParam, prelude::*};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sgp4::Elements;
+
+use crate::{
+    celestrak::{tick_sim_clock, LatLonAlt, QueryConfig, SatID, SatInfo, SimClock, TLECacheConfig},
+    command::{CommandMsg, CommandPlugin, RemoteCommand},
+    datalink::{path_nodes, DataLink, DataLinkStats, GSDataLink, RebuildSchedule},
+    groundstation::{Antennas, GroundStationBundle, GroundStationID},
+    handover::{Handover, HandoverConfig, HandoverEvent, HandoverPolicyKind},
+    routing::{Router, RoutingKind},
+    topology::{SatGraph, TopologyConfig},
+};
+
+pub const SESSION_FORMAT_VERSION: u32 = 1;
+
+/// `session` section of the configuration file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// archive to write, nothing is recorded if not set
+    pub record: Option<PathBuf>,
+    /// archive to replay instead of live TLEs, wall clock and the default scene
+    pub replay: Option<PathBuf>,
+    /// quit after the last replayed frame, with exit code 1 if the outputs differ
+    pub exit_at_end: bool,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(PathBuf, std::io::Error),
+    Decode(String),
+    Version(u32),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(path, err) => write!(f, "cannot open {:?}: {}", path, err),
+            SessionError::Decode(err) => write!(f, "invalid session archive: {}", err),
+            SessionError::Version(v) => write!(
+                f,
+                "session archive version {} is not supported (expected {})",
+                v, SESSION_FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StationRecord {
+    pub id: u64,
+    pub name: String,
+    /// (deg)
+    pub lat: f64,
+    pub lon: f64,
+    /// (km)
+    pub alt: f64,
+    pub antennas: Option<usize>,
+    pub handover: Option<HandoverPolicyKind>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinkDefinition {
+    pub name: String,
+    /// ground station IDs
+    pub from: u64,
+    pub to: u64,
+}
+
+/// First record of an archive, everything needed to rebuild the initial state.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionHeader {
+    pub version: u32,
+    /// wall clock time the recording started
+    pub created: DateTime<Utc>,
+    /// in NORAD ID order
+    pub tles: Vec<Elements>,
+    pub routing: RoutingKind,
+    pub topology: TopologyConfig,
+    pub handover: HandoverConfig,
+    pub ground_stations: Vec<StationRecord>,
+    pub links: Vec<LinkDefinition>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinkOutput {
+    pub name: String,
+    /// per hop latency (s)
+    pub latencies: Vec<f32>,
+    /// per hop distance (m)
+    pub distance: Vec<f32>,
+    /// NORAD ID of every path node, nil for ground stations
+    pub norad_ids: Vec<Option<u64>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HandoverOutput {
+    pub station: u64,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// (s)
+    pub interruption: f64,
+}
+
+/// Inputs and outputs of one frame, written after the header for every frame.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FrameRecord {
+    pub frame: u64,
+    /// simulation time (unix ns)
+    pub sim_time: i64,
+    /// the satellite graph and the link routes were rebuilt
+    pub rebuilt: bool,
+    /// commands received from the external interfaces
+    pub commands: Vec<CommandMsg>,
+    /// TLE set received during the frame
+    pub tles: Option<Vec<Elements>>,
+    /// in name order
+    pub links: Vec<LinkOutput>,
+    pub handovers: Vec<HandoverOutput>,
+}
+
+impl FrameRecord {
+    /// Encoded outputs, compared byte by byte so equal outputs are equal bit for bit.
+    fn outputs(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&(self.sim_time, self.rebuilt, &self.links, &self.handovers))
+            .unwrap_or_default()
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_nanos(self.sim_time)
+    }
+}
+
+/**
+Reads a session archive: a msgpack [`SessionHeader`] followed by one
+[`FrameRecord`] per frame. A truncated last frame, e.g. after a crash while
+recording, ends the archive.
+*/
+pub fn read_archive(path: &Path) -> Result<(SessionHeader, Vec<FrameRecord>), SessionError> {
+    let file = File::open(path).map_err(|err| SessionError::Io(path.into(), err))?;
+    let mut reader = BufReader::new(file);
+    let header: SessionHeader =
+        rmp_serde::from_read(&mut reader).map_err(|err| SessionError::Decode(err.to_string()))?;
+    if header.version != SESSION_FORMAT_VERSION {
+        return Err(SessionError::Version(header.version));
+    }
+    let mut frames = Vec::new();
+    loop {
+        match rmp_serde::from_read::<_, FrameRecord>(&mut reader) {
+            Ok(frame) => frames.push(frame),
+            Err(rmp_serde::decode::Error::InvalidMarkerRead(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(err) => {
+                warn!("{:?} is truncated after {} frames: {}", path, frames.len(), err);
+                break;
+            }
+        }
+    }
+    Ok((header, frames))
+}
+
+#[derive(Resource)]
+/// Resource writing the session archive, flushed when dropped.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    frame: u64,
+    header_written: bool,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, SessionError> {
+        let file = File::create(path).map_err(|err| SessionError::Io(path.into(), err))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            path: path.into(),
+            frame: 0,
+            header_written: false,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, record: &T) -> bool {
+        match rmp_serde::encode::write_named(&mut self.writer, record) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("cannot write {:?}: {}", self.path, err);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            error!("cannot write {:?}: {}", self.path, err);
+        }
+        info!("recorded {} frames to {:?}", self.frame, self.path);
+    }
+}
+
+#[derive(Resource)]
+/// Resource holding the archive being replayed and the comparison with it.
+pub struct Replay {
+    header: SessionHeader,
+    frames: Vec<FrameRecord>,
+    next: usize,
+    mismatches: usize,
+    first_mismatch: Option<u64>,
+    exit_at_end: bool,
+}
+
+impl Replay {
+    pub fn finished(&self) -> bool {
+        self.next >= self.frames.len()
+    }
+
+    /// (replayed frames, frames whose outputs differ from the recording)
+    pub fn progress(&self) -> (usize, usize) {
+        (self.next, self.mismatches)
+    }
+}
+
+/// Everything a frame record is made of.
+#[derive(SystemParam)]
+struct FrameState<'w, 's> {
+    clock: Res<'w, SimClock>,
+    graph: Res<'w, SatGraph>,
+    sat_info: Res<'w, SatInfo>,
+    commands: EventReader<'w, 's, RemoteCommand>,
+    handovers: EventReader<'w, 's, HandoverEvent>,
+    links: Query<
+        'w,
+        's,
+        (&'static Name, Option<&'static DataLink>, Option<&'static DataLinkStats>),
+        With<GSDataLink>,
+    >,
+    stations: Query<'w, 's, &'static GroundStationID>,
+    sats: Query<'w, 's, &'static SatID>,
+}
+
+impl<'w, 's> FrameState<'w, 's> {
+    fn capture(&mut self, frame: u64) -> FrameRecord {
+        let norad = |e: Entity| self.sats.get(e).ok().map(|s| s.0);
+        let mut links: Vec<LinkOutput> = self
+            .links
+            .iter()
+            .map(|(name, path, stats)| LinkOutput {
+                name: name.to_string(),
+                latencies: stats.map(|s| s.latencies.clone()).unwrap_or_default(),
+                distance: stats.map(|s| s.distance.clone()).unwrap_or_default(),
+                norad_ids: path
+                    .map(|p| path_nodes(&p.0).into_iter().map(norad).collect())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        links.sort_by(|a, b| a.name.cmp(&b.name));
+        let handovers = self
+            .handovers
+            .read()
+            .filter_map(|ev| {
+                Some(HandoverOutput {
+                    station: self.stations.get(ev.gs).ok()?.0,
+                    from: ev.from.and_then(norad),
+                    to: norad(ev.to),
+                    interruption: ev.interruption,
+                })
+            })
+            .collect();
+        // the first frame's set is in the header
+        let tles = (frame > 0 && self.sat_info.is_changed()).then(|| sorted_tles(&self.sat_info));
+        FrameRecord {
+            frame,
+            sim_time: self.clock.now.timestamp_nanos_opt().unwrap_or(0),
+            rebuilt: self.graph.is_changed(),
+            commands: self.commands.read().map(|c| c.0.clone()).collect(),
+            tles,
+            links,
+            handovers,
+        }
+    }
+}
+
+fn sorted_tles(sat_info: &SatInfo) -> Vec<Elements> {
+    let mut tles: Vec<Elements> = sat_info.sats.values().cloned().collect();
+    tles.sort_by_key(|e| e.norad_id);
+    tles
+}
+
+fn header_pending(recorder: Option<Res<Recorder>>) -> bool {
+    recorder.is_some_and(|r| !r.header_written)
+}
+
+/// Writes the header once the scene of the first frame is complete.
+#[allow(clippy::too_many_arguments)]
+fn write_header(
+    mut cmd: Commands,
+    mut recorder: ResMut<Recorder>,
+    sat_info: Res<SatInfo>,
+    router: Res<Router>,
+    topology: Res<TopologyConfig>,
+    handover: Res<HandoverConfig>,
+    stations: Query<(
+        &GroundStationID,
+        &Name,
+        &LatLonAlt,
+        Option<&Antennas>,
+        Option<&Handover>,
+    )>,
+    links: Query<(&Name, &GSDataLink)>,
+) {
+    // query order, so replay spawns the scene in the recorded order
+    let ground_stations = stations
+        .iter()
+        .map(|(id, name, lla, antennas, policy)| StationRecord {
+            id: id.0,
+            name: name.to_string(),
+            lat: lla.0 .0,
+            lon: lla.0 .1,
+            alt: lla.0 .2,
+            antennas: antennas.map(|a| a.0),
+            handover: policy.and_then(|p| p.0.kind()),
+        })
+        .collect();
+    let links = links
+        .iter()
+        .filter_map(|(name, gs)| {
+            Some(LinkDefinition {
+                name: name.to_string(),
+                from: stations.get(gs.0 .0).ok()?.0 .0,
+                to: stations.get(gs.0 .1).ok()?.0 .0,
+            })
+        })
+        .collect();
+    let header = SessionHeader {
+        version: SESSION_FORMAT_VERSION,
+        created: Utc::now(),
+        tles: sorted_tles(&sat_info),
+        routing: router.kind,
+        topology: topology.clone(),
+        handover: handover.clone(),
+        ground_stations,
+        links,
+    };
+    if recorder.write(&header) {
+        recorder.header_written = true;
+    } else {
+        cmd.remove_resource::<Recorder>();
+    }
+}
+
+/// Records the frame and compares it with the replayed one.
+fn capture_frame(
+    mut cmd: Commands,
+    mut state: FrameState,
+    recorder: Option<ResMut<Recorder>>,
+    replay: Option<ResMut<Replay>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let frame = match (&recorder, &replay) {
+        (Some(r), _) if r.header_written => r.frame,
+        (_, Some(r)) if !r.finished() => r.next as u64,
+        _ => return,
+    };
+    let record = state.capture(frame);
+
+    if let Some(mut recorder) = recorder.filter(|r| r.header_written) {
+        if recorder.write(&record) {
+            recorder.frame += 1;
+        } else {
+            cmd.remove_resource::<Recorder>();
+        }
+    }
+
+    let Some(mut replay) = replay.filter(|r| !r.finished()) else {
+        return;
+    };
+    let expected = &replay.frames[replay.next];
+    if expected.outputs() != record.outputs() {
+        if replay.first_mismatch.is_none() {
+            warn!(
+                "replay differs from the recording at frame {} ({})",
+                expected.frame,
+                expected.time()
+            );
+            replay.first_mismatch = Some(expected.frame);
+        }
+        replay.mismatches += 1;
+    }
+    replay.next += 1;
+    if !replay.finished() {
+        return;
+    }
+    let total = replay.frames.len();
+    match replay.first_mismatch {
+        None => info!("replay of {} frames is identical to the recording", total),
+        Some(first) => warn!(
+            "replay differs from the recording in {} of {} frames, first at frame {}",
+            replay.mismatches, total, first
+        ),
+    }
+    if replay.exit_at_end {
+        exit.send(if replay.mismatches == 0 {
+            AppExit::Success
+        } else {
+            AppExit::from_code(1)
+        });
+    }
+}
+
+/// Replaces the default scene with the recorded ground stations and links.
+fn load_recorded_scene(
+    mut cmd: Commands,
+    replay: Res<Replay>,
+    stations: Query<Entity, With<GroundStationID>>,
+    links: Query<Entity, With<GSDataLink>>,
+) {
+    stations
+        .iter()
+        .chain(links.iter())
+        .for_each(|e| cmd.entity(e).despawn_recursive());
+    let mut spawned = Vec::new();
+    for s in &replay.header.ground_stations {
+        let mut e = cmd.spawn(GroundStationBundle {
+            id: GroundStationID(s.id),
+            pos: LatLonAlt((s.lat, s.lon, s.alt)),
+        });
+        e.insert(Name::new(s.name.clone()));
+        if let Some(n) = s.antennas {
+            e.insert(Antennas(n));
+        }
+        if let Some(kind) = s.handover {
+            e.insert(Handover::from(kind));
+        }
+        spawned.push((s.id, e.id()));
+    }
+    let station = |id: u64| spawned.iter().find(|s| s.0 == id).map(|s| s.1);
+    for l in &replay.header.links {
+        match (station(l.from), station(l.to)) {
+            (Some(a), Some(b)) => {
+                cmd.spawn(GSDataLink((a, b))).insert(Name::new(l.name.clone()));
+            }
+            _ => warn!("recorded link {} has no ground stations", l.name),
+        }
+    }
+}
+
+/// Feeds the recorded time, rebuilds, TLE updates and commands of the frame.
+fn replay_inputs(
+    replay: Res<Replay>,
+    mut clock: ResMut<SimClock>,
+    mut schedule: ResMut<RebuildSchedule>,
+    mut sat_info: ResMut<SatInfo>,
+    mut commands: EventWriter<RemoteCommand>,
+) {
+    let Some(frame) = replay.frames.get(replay.next) else {
+        if !clock.paused {
+            clock.set_paused(true);
+        }
+        schedule.forced = Some(false);
+        return;
+    };
+    clock.set(frame.time());
+    schedule.forced = Some(frame.rebuilt);
+    if let Some(tles) = &frame.tles {
+        sat_info.sats = tles.iter().map(|e| (e.norad_id, e.clone())).collect();
+    }
+    commands.send_batch(frame.commands.iter().cloned().map(RemoteCommand));
+}
+
+/**
+Records a session to a single archive and replays it. The archive holds the
+TLE set, the routing and visibility settings, the ground stations and links,
+and per frame the simulation time, graph rebuilds, TLE updates, external
+commands and every link and handover output.
+
+A replay drives the clock and the graph rebuilds from the recording instead
+of the wall clock, so it produces the same outputs bit for bit on the same
+build. Every replayed frame is compared with the recorded one and the result
+is logged at the end. Changes made in the UI are not recorded.
+*/
+#[derive(Default)]
+pub struct SessionPlugin {
+    pub config: SessionConfig,
+}
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut App) {
+        if self.config.record.is_none() && self.config.replay.is_none() {
+            return;
+        }
+        if !app.is_plugin_added::<CommandPlugin>() {
+            app.add_plugins(CommandPlugin);
+        }
+        if let Some(path) = &self.config.replay {
+            match read_archive(path) {
+                Ok((header, frames)) => {
+                    info!("replaying {} frames from {:?}", frames.len(), path);
+                    let world = app.world_mut();
+                    match world.get_resource_mut::<TLECacheConfig>() {
+                        Some(mut cache) => cache.cache = Some(header.tles.clone()),
+                        None => error!("SGP4Plugin must be added before SessionPlugin"),
+                    }
+                    if let Some(mut query) = world.get_resource_mut::<QueryConfig>() {
+                        query.timer.pause();
+                    }
+                    if let (Some(first), Some(mut clock)) =
+                        (frames.first(), world.get_resource_mut::<SimClock>())
+                    {
+                        clock.set(first.time());
+                    }
+                    app.insert_resource(Router::new(header.routing));
+                    app.insert_resource(header.topology.clone());
+                    app.insert_resource(header.handover.clone());
+                    app.insert_resource(Replay {
+                        header,
+                        frames,
+                        next: 0,
+                        mismatches: 0,
+                        first_mismatch: None,
+                        exit_at_end: self.config.exit_at_end,
+                    });
+                    app.add_systems(PostStartup, load_recorded_scene);
+                    app.add_systems(
+                        First,
+                        replay_inputs
+                            .after(tick_sim_clock)
+                            .run_if(resource_exists::<Replay>),
+                    );
+                }
+                Err(err) => error!("cannot replay {:?}: {}", path, err),
+            }
+        }
+        if let Some(path) = &self.config.record {
+            match Recorder::create(path) {
+                Ok(recorder) => {
+                    info!("recording session to {:?}", path);
+                    app.insert_resource(recorder);
+                }
+                Err(err) => error!("cannot record session: {}", err),
+            }
+        }
+        app.add_systems(
+            Last,
+            (write_header.run_if(header_pending), capture_frame).chain(),
+        );
+    }
+}
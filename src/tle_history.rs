@@ -0,0 +1,212 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sgp4::Elements;
+
+use crate::{
+    celestrak::{receive_task, update_every_sat, PropagationSet, SatInfo, SimClock},
+    session::Replay,
+};
+
+/// `tle_history` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TleHistoryConfig {
+    pub enabled: bool,
+    /// append-only archive, one element set in JSON per line
+    pub file: PathBuf,
+    /// propagate every satellite from its element set with the closest
+    /// preceding epoch at the simulation time instead of the latest one
+    pub select_by_epoch: bool,
+}
+
+impl Default for TleHistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: "./tle_history.jsonl".into(),
+            select_by_epoch: true,
+        }
+    }
+}
+
+/// Every element set seen so far, by NORAD ID and epoch.
+#[derive(Resource, Default)]
+pub struct TleArchive {
+    pub sets: HashMap<u64, BTreeMap<NaiveDateTime, Elements>>,
+    /// new element sets are appended to this file
+    file: Option<PathBuf>,
+}
+
+impl TleArchive {
+    /// Reads an archive file, a missing file gives an empty archive. Lines
+    /// that cannot be parsed are skipped.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut archive = TleArchive {
+            sets: HashMap::new(),
+            file: Some(path.into()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(archive),
+            Err(err) => return Err(err),
+        };
+        let mut skipped = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Elements>(&line) {
+                Ok(elements) => {
+                    archive.insert(elements);
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            warn!("{} invalid lines in {:?} skipped", skipped, path);
+        }
+        Ok(archive)
+    }
+
+    /// Adds an element set, returns false if the satellite already has one
+    /// with the same epoch.
+    pub fn insert(&mut self, elements: Elements) -> bool {
+        let sets = self.sets.entry(elements.norad_id).or_default();
+        if sets.contains_key(&elements.datetime) {
+            return false;
+        }
+        sets.insert(elements.datetime, elements);
+        true
+    }
+
+    /// Adds the element sets that are not archived yet and appends them to
+    /// the archive file. Returns the number of new sets.
+    pub fn record<'a>(&mut self, sets: impl Iterator<Item = &'a Elements>) -> io::Result<usize> {
+        let mut lines = String::new();
+        let mut added = 0;
+        for elements in sets {
+            if self.insert(elements.clone()) {
+                lines.push_str(&serde_json::to_string(elements)?);
+                lines.push('\n');
+                added += 1;
+            }
+        }
+        if let (Some(path), true) = (&self.file, added > 0) {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(lines.as_bytes())?;
+        }
+        Ok(added)
+    }
+
+    /// Element set of a satellite with the closest epoch at or before `t`,
+    /// the earliest one if all are later.
+    pub fn select(&self, norad_id: u64, t: &NaiveDateTime) -> Option<&Elements> {
+        let sets = self.sets.get(&norad_id)?;
+        sets.range(..=*t)
+            .next_back()
+            .or_else(|| sets.iter().next())
+            .map(|(_, e)| e)
+    }
+
+    /// Number of archived element sets.
+    pub fn len(&self) -> usize {
+        self.sets.values().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+}
+
+/// Archives the element sets of every fetch.
+fn record_elements(mut archive: ResMut<TleArchive>, sat_info: Res<SatInfo>) {
+    match archive.record(sat_info.sats.values()) {
+        Ok(0) => {}
+        Ok(n) => info!("{} new element sets archived, {} in total", n, archive.len()),
+        Err(err) => error!("cannot write the TLE archive: {}", err),
+    }
+}
+
+/// Replaces the element sets in [`SatInfo`] whose satellite has an archived
+/// set closer to the simulation time. `SatInfo` is only marked as changed if
+/// one was replaced, which updates the SGP4 constants of the satellites.
+fn select_elements(archive: Res<TleArchive>, clock: Res<SimClock>, mut sat_info: ResMut<SatInfo>) {
+    let t = clock.now.naive_utc();
+    let mut replaced = 0;
+    for (id, elements) in sat_info.bypass_change_detection().sats.iter_mut() {
+        if let Some(selected) = archive.select(*id, &t) {
+            if selected.datetime != elements.datetime {
+                *elements = selected.clone();
+                replaced += 1;
+            }
+        }
+    }
+    if replaced > 0 {
+        debug!("{} element sets switched at {}", replaced, clock.now);
+        sat_info.set_changed();
+    }
+}
+
+/**
+Keeps every fetched element set in an append-only archive, so that fetches
+and the overwritten TLE cache do not lose older sets. With `select_by_epoch`
+each satellite is propagated from the archived set with the closest preceding
+epoch, which gives the historical orbits when the clock is set to the past.
+Session replays use the recorded element sets only.
+*/
+#[derive(Default)]
+pub struct TleHistoryPlugin {
+    pub config: TleHistoryConfig,
+}
+
+impl Plugin for TleHistoryPlugin {
+    fn build(&self, app: &mut App) {
+        if !self.config.enabled {
+            return;
+        }
+        let archive = match TleArchive::load(&self.config.file) {
+            Ok(archive) => {
+                info!(
+                    "{} element sets of {} satellites in {:?}",
+                    archive.len(),
+                    archive.sets.len(),
+                    self.config.file
+                );
+                archive
+            }
+            Err(err) => {
+                error!("cannot read {:?}: {}", self.config.file, err);
+                TleArchive::default()
+            }
+        };
+        app.insert_resource(archive);
+        app.add_systems(
+            Update,
+            record_elements
+                .run_if(resource_changed::<SatInfo>)
+                .run_if(not(resource_exists::<Replay>))
+                .after(receive_task)
+                .before(update_every_sat)
+                .in_set(PropagationSet),
+        );
+        if self.config.select_by_epoch {
+            app.add_systems(
+                Update,
+                select_elements
+                    .run_if(not(resource_exists::<Replay>))
+                    .after(record_elements)
+                    .before(update_every_sat)
+                    .in_set(PropagationSet),
+            );
+        }
+    }
+}
@@ -0,0 +1,657 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use bevy::log::error;
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use sgp4::Elements;
+
+use crate::celestrak::TleError;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Result of a fetch.
+pub enum Fetched {
+    Updated(Vec<Elements>),
+    /// the source did not change since the last fetch
+    NotModified,
+}
+
+/**
+A source of element sets. Providers remember what they returned last, so a
+fetch can answer [`Fetched::NotModified`] without transferring the data again.
+*/
+pub trait TleProvider: Send + Sync {
+    /// Short description for the log.
+    fn name(&self) -> String;
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Fetched, TleError>>;
+
+    /// Whether the element sets are kept in a cache file. Local sources are
+    /// read directly instead.
+    fn cached(&self) -> bool {
+        true
+    }
+}
+
+/// Cache file of a provider in the working directory, named after the
+/// provider so that switching sources never starts from the element sets of
+/// another one. `None` if the provider is not cached.
+pub fn cache_file(provider: &dyn TleProvider) -> Option<PathBuf> {
+    if !provider.cached() {
+        return None;
+    }
+    let mut slug = String::new();
+    for c in provider.name().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+    let slug: String = slug.trim_matches('_').chars().take(200).collect();
+    Some(PathBuf::from(format!("./tle_{}.json", slug)))
+}
+
+/// Encoding of element sets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TleFormat {
+    /// OMM as JSON array, as served by CelesTrak with `FORMAT=JSON`
+    #[default]
+    Json,
+    /// two or three line element sets
+    Tle,
+}
+
+/// Parses element sets, `source` names the origin in errors.
+pub fn parse_elements(
+    text: &str,
+    format: TleFormat,
+    source: &str,
+) -> Result<Vec<Elements>, TleError> {
+    let elements = match format {
+        TleFormat::Json => serde_json::from_str::<Vec<Elements>>(text)
+            .map_err(|err| TleError::Format(source.into(), err.to_string()))?,
+        TleFormat::Tle => {
+            let first = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+            let parsed = if first.starts_with("1 ") {
+                sgp4::parse_2les(text)
+            } else {
+                sgp4::parse_3les(text)
+            };
+            parsed.map_err(|err| TleError::Format(source.into(), format!("{:?}", err)))?
+        }
+    };
+    if elements.is_empty() {
+        return Err(TleError::Empty);
+    }
+    Ok(elements)
+}
+
+/// `tle` section of the configuration file, the source of the element sets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TleProviderConfig {
+    /// a CelesTrak group, e.g. `STARLINK`
+    Celestrak { group: String },
+    /// any HTTP endpoint serving element sets
+    Http { url: String, format: TleFormat },
+    /// `.json` (OMM) and `.tle`/`.txt` files of a local directory
+    Directory { path: PathBuf },
+    /// a file read once at startup and never updated, for offline runs
+    Fixture {
+        path: PathBuf,
+        #[serde(default)]
+        format: TleFormat,
+    },
+    /// a Space-Track compatible API, `query` is the request path after
+    /// `basicspacedata/query/`, e.g. `class/gp/OBJECT_NAME/STARLINK~~/decay_date/null-val`
+    SpaceTrack {
+        url: String,
+        identity: String,
+        password: String,
+        query: String,
+    },
+}
+
+impl Default for TleProviderConfig {
+    fn default() -> Self {
+        TleProviderConfig::Celestrak {
+            group: "STARLINK".into(),
+        }
+    }
+}
+
+impl TleProviderConfig {
+    pub fn build(&self) -> Arc<dyn TleProvider> {
+        match self {
+            TleProviderConfig::Celestrak { group } => Arc::new(HttpProvider::new(
+                format!(
+                    "https://celestrak.org/NORAD/elements/gp.php?GROUP={}&FORMAT=JSON",
+                    group
+                ),
+                TleFormat::Json,
+            )),
+            TleProviderConfig::Http { url, format } => {
+                Arc::new(HttpProvider::new(url.clone(), *format))
+            }
+            TleProviderConfig::Directory { path } => Arc::new(DirectoryProvider::new(path.clone())),
+            TleProviderConfig::Fixture { path, format } => {
+                match FixtureProvider::from_file(path, *format) {
+                    Ok(provider) => Arc::new(provider),
+                    Err(err) => {
+                        error!("{}", err);
+                        Arc::new(FixtureProvider::new(Vec::new()))
+                    }
+                }
+            }
+            TleProviderConfig::SpaceTrack {
+                url,
+                identity,
+                password,
+                query,
+            } => Arc::new(SpaceTrackProvider::new(
+                url.clone(),
+                identity.clone(),
+                password.clone(),
+                query.clone(),
+            )),
+        }
+    }
+}
+
+/// Time limit of a whole HTTP request, so an unresponsive host fails the fetch.
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time limit of establishing the connection.
+pub const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn http_client() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+}
+
+/// Cache validators of the last response.
+#[derive(Default)]
+struct Validators {
+    etag: Option<header::HeaderValue>,
+    last_modified: Option<header::HeaderValue>,
+}
+
+/// Element sets over HTTP with conditional requests (`If-None-Match`,
+/// `If-Modified-Since`).
+pub struct HttpProvider {
+    url: String,
+    format: TleFormat,
+    client: reqwest::Client,
+    validators: Mutex<Validators>,
+}
+
+impl HttpProvider {
+    pub fn new(url: String, format: TleFormat) -> Self {
+        Self {
+            url,
+            format,
+            client: http_client().build().unwrap_or_default(),
+            validators: Mutex::default(),
+        }
+    }
+
+    async fn get(&self) -> Result<Fetched, TleError> {
+        let mut request = self.client.get(&self.url);
+        {
+            let validators = self.validators.lock().unwrap();
+            if let Some(etag) = &validators.etag {
+                request = request.header(header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(modified) = &validators.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, modified.clone());
+            }
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+        let response = response.error_for_status()?;
+        let etag = response.headers().get(header::ETAG).cloned();
+        let last_modified = response.headers().get(header::LAST_MODIFIED).cloned();
+        let text = response.text().await?;
+        let elements = parse_elements(&text, self.format, &self.url)?;
+        // only remembered once the body was accepted
+        *self.validators.lock().unwrap() = Validators {
+            etag,
+            last_modified,
+        };
+        Ok(Fetched::Updated(elements))
+    }
+}
+
+impl TleProvider for HttpProvider {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Fetched, TleError>> {
+        Box::pin(self.get())
+    }
+}
+
+/// Element sets from the files of a local directory. The directory counts as
+/// unchanged while no file is newer than at the last fetch.
+pub struct DirectoryProvider {
+    path: PathBuf,
+    /// newest modification time and number of the files at the last fetch
+    modified: Mutex<Option<(SystemTime, usize)>>,
+}
+
+impl DirectoryProvider {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: Mutex::default(),
+        }
+    }
+
+    fn format_of(path: &Path) -> Option<TleFormat> {
+        match path.extension()?.to_str()? {
+            "json" => Some(TleFormat::Json),
+            "tle" | "txt" | "3le" => Some(TleFormat::Tle),
+            _ => None,
+        }
+    }
+
+    fn read(&self) -> Result<Fetched, TleError> {
+        let io = |err| TleError::Io(self.path.clone(), err);
+        let mut files: Vec<(PathBuf, TleFormat, SystemTime)> = Vec::new();
+        for entry in std::fs::read_dir(&self.path).map_err(io)? {
+            let entry = entry.map_err(io)?;
+            let path = entry.path();
+            if let Some(format) = Self::format_of(&path) {
+                let modified = entry.metadata().and_then(|m| m.modified()).map_err(io)?;
+                files.push((path, format, modified));
+            }
+        }
+        // files in name order, a later file replaces a satellite of an earlier one
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let newest = files.iter().map(|f| f.2).max().map(|t| (t, files.len()));
+        let mut last = self.modified.lock().unwrap();
+        if newest.is_some() && *last == newest {
+            return Ok(Fetched::NotModified);
+        }
+        let mut elements: Vec<Elements> = Vec::new();
+        for (path, format, _) in &files {
+            let text =
+                std::fs::read_to_string(path).map_err(|err| TleError::Io(path.clone(), err))?;
+            elements.extend(parse_elements(&text, *format, &path.to_string_lossy())?);
+        }
+        if elements.is_empty() {
+            return Err(TleError::Empty);
+        }
+        *last = newest;
+        Ok(Fetched::Updated(elements))
+    }
+}
+
+impl TleProvider for DirectoryProvider {
+    fn name(&self) -> String {
+        format!("{:?}", self.path)
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Fetched, TleError>> {
+        Box::pin(async move { self.read() })
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+}
+
+/// Fixed element sets held in memory, e.g. for offline runs. Only the first
+/// fetch returns them, an empty fixture fails with [`TleError::Empty`].
+pub struct FixtureProvider {
+    elements: Vec<Elements>,
+    fetched: Mutex<bool>,
+}
+
+impl FixtureProvider {
+    pub fn new(elements: Vec<Elements>) -> Self {
+        Self {
+            elements,
+            fetched: Mutex::new(false),
+        }
+    }
+
+    pub fn from_text(text: &str, format: TleFormat) -> Result<Self, TleError> {
+        Ok(Self::new(parse_elements(text, format, "fixture")?))
+    }
+
+    pub fn from_file(path: &Path, format: TleFormat) -> Result<Self, TleError> {
+        let text = std::fs::read_to_string(path).map_err(|err| TleError::Io(path.into(), err))?;
+        Ok(Self::new(parse_elements(
+            &text,
+            format,
+            &path.to_string_lossy(),
+        )?))
+    }
+}
+
+impl TleProvider for FixtureProvider {
+    fn name(&self) -> String {
+        format!("{} fixed element sets", self.elements.len())
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Fetched, TleError>> {
+        Box::pin(async move {
+            if self.elements.is_empty() {
+                return Err(TleError::Empty);
+            }
+            let mut fetched = self.fetched.lock().unwrap();
+            if *fetched {
+                return Ok(Fetched::NotModified);
+            }
+            *fetched = true;
+            Ok(Fetched::Updated(self.elements.clone()))
+        })
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+}
+
+/// Element sets from a Space-Track compatible API. Every fetch logs in with
+/// the credentials and requests the query as 3LE.
+pub struct SpaceTrackProvider {
+    url: String,
+    identity: String,
+    password: String,
+    query: String,
+    client: reqwest::Client,
+}
+
+impl SpaceTrackProvider {
+    pub fn new(url: String, identity: String, password: String, query: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').into(),
+            identity,
+            password,
+            query: query.trim_matches('/').into(),
+            client: http_client().cookie_store(true).build().unwrap_or_default(),
+        }
+    }
+
+    async fn get(&self) -> Result<Fetched, TleError> {
+        self.client
+            .post(format!("{}/ajaxauth/login", self.url))
+            .form(&[("identity", &self.identity), ("password", &self.password)])
+            .send()
+            .await?
+            .error_for_status()?;
+        let url = format!(
+            "{}/basicspacedata/query/{}/format/3le",
+            self.url, self.query
+        );
+        let text = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(Fetched::Updated(parse_elements(
+            &text,
+            TleFormat::Tle,
+            &url,
+        )?))
+    }
+}
+
+impl TleProvider for SpaceTrackProvider {
+    fn name(&self) -> String {
+        format!("{}/basicspacedata/query/{}", self.url, self.query)
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Fetched, TleError>> {
+        Box::pin(self.get())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::celestrak::get_online_sat_data;
+
+    /// One OMM element set in a JSON array.
+    fn omm(norad_id: u64) -> String {
+        format!(
+            r#"[{{"OBJECT_NAME": "TEST-{0}", "OBJECT_ID": "2019-074A",
+            "EPOCH": "2024-01-01T00:00:00.000000", "MEAN_MOTION": 15.06391,
+            "ECCENTRICITY": 0.0001, "INCLINATION": 53.0, "RA_OF_ASC_NODE": 0.0,
+            "ARG_OF_PERICENTER": 90.0, "MEAN_ANOMALY": 0.0, "EPHEMERIS_TYPE": 0,
+            "CLASSIFICATION_TYPE": "U", "NORAD_CAT_ID": {0}, "ELEMENT_SET_NO": 999,
+            "REV_AT_EPOCH": 1, "BSTAR": 0.0001, "MEAN_MOTION_DOT": 0.00001,
+            "MEAN_MOTION_DDOT": 0}}]"#,
+            norad_id
+        )
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut r = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            r.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        r.push_str("\r\n");
+        r.push_str(body);
+        r
+    }
+
+    /// Starts an HTTP stub on a free local port. `respond` gets the index and
+    /// the lowercased head of every request. Returns the URL and the heads.
+    async fn stub(
+        respond: impl Fn(usize, &str) -> String + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/gp.json", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+                let n = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(head.clone());
+                    seen.len() - 1
+                };
+                let _ = socket.write_all(respond(n, &head).as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        (url, requests)
+    }
+
+    fn updated(fetched: Fetched) -> Vec<Elements> {
+        match fetched {
+            Fetched::Updated(elements) => elements,
+            Fetched::NotModified => panic!("expected element sets"),
+        }
+    }
+
+    #[tokio::test]
+    async fn http_sends_validators_and_accepts_not_modified() {
+        let (url, requests) = stub(|_, head| {
+            if head.contains("if-none-match: \"v1\"") {
+                response("304 Not Modified", &[], "")
+            } else {
+                response(
+                    "200 OK",
+                    &[
+                        ("ETag", "\"v1\""),
+                        ("Last-Modified", "Mon, 01 Jan 2024 00:00:00 GMT"),
+                    ],
+                    &omm(44713),
+                )
+            }
+        })
+        .await;
+        let provider = HttpProvider::new(url, TleFormat::Json);
+
+        let elements = updated(provider.fetch().await.unwrap());
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].norad_id, 44713);
+        assert!(matches!(
+            provider.fetch().await.unwrap(),
+            Fetched::NotModified
+        ));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(requests[1].contains("if-modified-since: mon, 01 jan 2024 00:00:00 gmt"));
+    }
+
+    #[tokio::test]
+    async fn http_keeps_validators_of_rejected_bodies_out() {
+        let (url, requests) = stub(|n, _| match n {
+            0 => response("200 OK", &[("ETag", "\"bad\"")], "not json"),
+            _ => response("200 OK", &[("ETag", "\"v2\"")], &omm(44714)),
+        })
+        .await;
+        let provider = HttpProvider::new(url, TleFormat::Json);
+
+        assert!(matches!(provider.fetch().await, Err(TleError::Format(..))));
+        assert_eq!(updated(provider.fetch().await.unwrap()).len(), 1);
+        assert!(!requests.lock().unwrap()[1].contains("if-none-match"));
+    }
+
+    #[tokio::test]
+    async fn http_failures_are_retried() {
+        let (url, requests) = stub(|n, _| match n {
+            0 => response("503 Service Unavailable", &[], ""),
+            _ => response("200 OK", &[], &omm(44715)),
+        })
+        .await;
+        let provider = HttpProvider::new(url, TleFormat::Json);
+
+        let elements = updated(get_online_sat_data(&provider).await.unwrap());
+        assert_eq!(elements[0].norad_id, 44715);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_bodies_are_not_retried() {
+        let (url, requests) = stub(|_, _| response("200 OK", &[], "not json")).await;
+        let provider = HttpProvider::new(url, TleFormat::Json);
+
+        let result = get_online_sat_data(&provider).await;
+        assert!(matches!(result, Err(TleError::Format(..))));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cache_files_are_per_provider() {
+        let http = HttpProvider::new("http://127.0.0.1:8080/gp.json".into(), TleFormat::Json);
+        let celestrak = TleProviderConfig::default().build();
+
+        let file = cache_file(celestrak.as_ref()).unwrap();
+        assert_eq!(
+            file,
+            Path::new(
+                "./tle_https_celestrak_org_NORAD_elements_gp_php_GROUP_STARLINK_FORMAT_JSON.json"
+            )
+        );
+        assert!(cache_file(&http).is_some_and(|f| f != file));
+        assert_eq!(cache_file(&DirectoryProvider::new("./tles".into())), None);
+        let fixture = FixtureProvider::from_text(&omm(44716), TleFormat::Json).unwrap();
+        assert_eq!(cache_file(&fixture), None);
+    }
+
+    #[tokio::test]
+    async fn directory_detects_new_and_modified_files() {
+        let dir = std::env::temp_dir().join(format!("rustsat-tle-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.json"), omm(1)).unwrap();
+        std::fs::write(dir.join("notes.md"), "ignored").unwrap();
+        let provider = DirectoryProvider::new(dir.clone());
+
+        assert_eq!(updated(provider.fetch().await.unwrap()).len(), 1);
+        assert!(matches!(
+            provider.fetch().await.unwrap(),
+            Fetched::NotModified
+        ));
+
+        // a new file changes the count even within the same mtime
+        std::fs::write(dir.join("b.json"), omm(2)).unwrap();
+        assert_eq!(updated(provider.fetch().await.unwrap()).len(), 2);
+        assert!(matches!(
+            provider.fetch().await.unwrap(),
+            Fetched::NotModified
+        ));
+
+        // a newer modification time of an existing file
+        std::fs::write(dir.join("a.json"), omm(3)).unwrap();
+        File::options()
+            .write(true)
+            .open(dir.join("a.json"))
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let mut ids: Vec<u64> = updated(provider.fetch().await.unwrap())
+            .iter()
+            .map(|e| e.norad_id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [2, 3]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fixture_is_returned_once() {
+        let provider = FixtureProvider::from_text(&omm(4), TleFormat::Json).unwrap();
+        assert_eq!(updated(provider.fetch().await.unwrap())[0].norad_id, 4);
+        assert!(matches!(
+            provider.fetch().await.unwrap(),
+            Fetched::NotModified
+        ));
+
+        let missing = TleProviderConfig::Fixture {
+            path: "/nonexistent/tle.json".into(),
+            format: TleFormat::Json,
+        };
+        assert!(matches!(
+            missing.build().fetch().await,
+            Err(TleError::Empty)
+        ));
+    }
+}
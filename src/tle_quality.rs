@@ -0,0 +1,110 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::{PropagationSet, SatID, SatInfo, SimClock, TLETimeStamp, TleQuality},
+    topology::{build_sat_graph, TopologyConfig},
+};
+
+/// Earth gravitational parameter (km³/s²)
+const MU: f64 = 398600.4418;
+/// Equatorial radius (km)
+const EARTH_RADIUS_KM: f64 = 6378.137;
+
+/// `tle_quality` section of the configuration file.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TleQualityConfig {
+    /// an element set older or newer than this at the simulation time is stale (days)
+    pub stale_after: f64,
+    /// orbits with a lower perigee are taken as decaying (km)
+    pub min_perigee: f64,
+    /// orbits with a larger B* drag term are taken as decaying (1/earth radii)
+    pub max_bstar: f64,
+    /// leave flagged satellites out of the network graph
+    pub exclude_from_routing: bool,
+}
+
+impl Default for TleQualityConfig {
+    fn default() -> Self {
+        Self {
+            stale_after: 7.0,
+            min_perigee: 150.0,
+            max_bstar: 0.01,
+            exclude_from_routing: false,
+        }
+    }
+}
+
+/// Perigee height of the mean orbit of an element set (km).
+pub fn perigee_height(elements: &sgp4::Elements) -> f64 {
+    let n = elements.mean_motion * std::f64::consts::TAU / 86400.0;
+    let a = (MU / (n * n)).cbrt();
+    a * (1.0 - elements.eccentricity) - EARTH_RADIUS_KM
+}
+
+/// Recomputes the orbit checks when the element sets change.
+fn assess_elements(
+    config: Res<TleQualityConfig>,
+    sat_info: Res<SatInfo>,
+    mut sats: Query<(&SatID, &mut TleQuality)>,
+) {
+    if !sat_info.is_changed() && !config.is_changed() {
+        return;
+    }
+    for (id, mut quality) in sats.iter_mut() {
+        let Some(elements) = sat_info.sats.get(&id.0) else {
+            continue;
+        };
+        quality.perigee = perigee_height(elements);
+        quality.decayed =
+            quality.perigee < config.min_perigee || elements.drag_term > config.max_bstar;
+    }
+}
+
+/// Updates the TLE age of every satellite at the simulation time.
+fn update_age(
+    config: Res<TleQualityConfig>,
+    clock: Res<SimClock>,
+    mut sats: Query<(&TLETimeStamp, &mut TleQuality)>,
+) {
+    let now = clock.now.naive_utc();
+    sats.par_iter_mut().for_each(|(ts, mut quality)| {
+        let age = (now - ts.0).num_seconds() as f64 / 86400.0;
+        let stale = age.abs() > config.stale_after;
+        // keep the change detection quiet when nothing moved
+        if quality.age != age || quality.stale != stale {
+            quality.age = age;
+            quality.stale = stale;
+        }
+    });
+}
+
+/**
+Flags satellites whose element set is stale at the simulation time, whose
+orbit is decaying (low perigee or high B*), or that SGP4 cannot propagate.
+The flags are kept in the [`TleQuality`] component of every satellite and
+shown in the Satellite Data table. With `exclude_from_routing` flagged
+satellites are left out of the network graph.
+*/
+#[derive(Default)]
+pub struct TleQualityPlugin {
+    pub config: TleQualityConfig,
+}
+
+impl Plugin for TleQualityPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.config.clone());
+        match app.world_mut().get_resource_mut::<TopologyConfig>() {
+            Some(mut topology) => topology.exclude_flagged = self.config.exclude_from_routing,
+            None => error!("DatalinkPlugin must be added before TleQualityPlugin"),
+        }
+        app.add_systems(
+            Update,
+            (assess_elements, update_age)
+                .chain()
+                .after(PropagationSet)
+                .before(build_sat_graph),
+        );
+    }
+}
@@ -0,0 +1,640 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sgp4::Constants;
+
+use crate::{
+    celestrak::{propagate_sat_at, teme_to_lla, LatLonAlt, SatID, SatInfo, TleQuality},
+    datalink::GSDataLink,
+    groundstation::{Antennas, GroundStationID, NearestSat},
+    handover::AccessSats,
+    util::geometry,
+};
+
+pub const LIGHT_SPEED: f64 = 299792458.0;
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+/// Resource holding the parameters of the satellite network graph.
+pub struct TopologyConfig {
+    /// maximum inter-satellite link range (m)
+    pub max_isl_range: f64,
+    /// number of laser terminals per satellite
+    pub isl_per_sat: usize,
+    /// minimum height of an inter-satellite link above the surface (m)
+    pub isl_clearance: f64,
+    /// number of paths kept by the k shortest path search
+    pub k_paths: usize,
+    pub disjoint: Disjointness,
+    /// time needed to notice a broken primary path (s)
+    pub detection_time: f64,
+    /// leave satellites flagged by the TLE quality checks out of the graph
+    #[serde(default)]
+    pub exclude_flagged: bool,
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        Self {
+            max_isl_range: 5_000_000.0,
+            isl_per_sat: 4,
+            isl_clearance: 80_000.0,
+            k_paths: 3,
+            disjoint: Disjointness::Node,
+            detection_time: 0.05,
+            exclude_flagged: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disjointness {
+    /// backup shares no edge with the primary
+    Link,
+    /// backup shares no intermediate node with the primary
+    Node,
+}
+
+/// A path through the [`SatGraph`], `cost` is its length (m).
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphPath {
+    pub nodes: Vec<Entity>,
+    pub cost: f64,
+}
+
+impl GraphPath {
+    /// One way propagation delay (s).
+    pub fn latency(&self) -> f64 {
+        self.cost / LIGHT_SPEED
+    }
+}
+
+/**
+Snapshot of the satellite network. Satellites are connected to their nearest
+neighbours in line of sight, shortest links first, until both ends use all of
+their `isl_per_sat` terminals. Ground stations are connected to the satellites
+they have an active access link with.
+Ground stations never relay traffic, they only appear as path endpoints.
+*/
+#[derive(Resource, Default)]
+pub struct SatGraph {
+    pub nodes: Vec<Entity>,
+    pub index: HashMap<Entity, usize>,
+    /// ECEF position (m)
+    pub pos: Vec<[f64; 3]>,
+    pub adj: Vec<Vec<(usize, f64)>>,
+    pub transit: Vec<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct State {
+    cost: f64,
+    node: usize,
+}
+
+impl Eq for State {}
+
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn dist(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+impl SatGraph {
+    /// Builds the graph from satellite positions and ground station access links.
+    /// sats: (entity, ECEF m), ground: (entity, ECEF m, access satellites)
+    pub fn build(
+        sats: &[(Entity, [f64; 3])],
+        ground: &[(Entity, [f64; 3], Vec<Entity>)],
+        cfg: &TopologyConfig,
+    ) -> Self {
+        let mut g = SatGraph::default();
+        for (e, p) in sats {
+            g.add_node(*e, *p, true);
+        }
+        // candidate pairs within range, found through a grid of cells as large
+        // as the range so only the 27 surrounding cells have to be searched
+        let max_sq = cfg.max_isl_range.powi(2);
+        let cell = if cfg.max_isl_range.is_finite() && cfg.max_isl_range > 0.0 {
+            cfg.max_isl_range
+        } else {
+            f64::INFINITY
+        };
+        let key = |p: &[f64; 3]| {
+            if cell.is_infinite() {
+                return [0i64; 3];
+            }
+            p.map(|x| (x / cell).floor() as i64)
+        };
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for (i, p) in g.pos.iter().enumerate() {
+            grid.entry(key(p)).or_default().push(i);
+        }
+        let mut pairs: Vec<(f64, usize, usize)> = Vec::new();
+        for (i, a) in g.pos.iter().enumerate() {
+            let k = key(a);
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        if cell.is_infinite() && (dx, dy, dz) != (0, 0, 0) {
+                            continue;
+                        }
+                        let Some(members) = grid.get(&[k[0] + dx, k[1] + dy, k[2] + dz]) else {
+                            continue;
+                        };
+                        for &j in members.iter().filter(|j| **j > i) {
+                            let b = &g.pos[j];
+                            let d_sq = (a[0] - b[0]).powi(2)
+                                + (a[1] - b[1]).powi(2)
+                                + (a[2] - b[2]).powi(2);
+                            if d_sq <= max_sq {
+                                pairs.push((d_sq.sqrt(), i, j));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        // shortest links first, a link is only made while both ends have a
+        // free terminal, so no satellite exceeds `isl_per_sat`
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        let mut degree = vec![0usize; sats.len()];
+        for (d, i, j) in pairs {
+            if degree[i] >= cfg.isl_per_sat || degree[j] >= cfg.isl_per_sat {
+                continue;
+            }
+            if !geometry::line_of_sight(g.pos[i], g.pos[j], cfg.isl_clearance) {
+                continue;
+            }
+            g.add_edge(i, j, d);
+            degree[i] += 1;
+            degree[j] += 1;
+        }
+        for (e, p, access) in ground {
+            let i = g.add_node(*e, *p, false);
+            for s in access {
+                if let Some(&j) = g.index.get(s) {
+                    let d = dist(p, &g.pos[j]);
+                    g.add_edge(i, j, d);
+                }
+            }
+        }
+        g
+    }
+
+    fn add_node(&mut self, e: Entity, p: [f64; 3], transit: bool) -> usize {
+        let i = self.nodes.len();
+        self.nodes.push(e);
+        self.index.insert(e, i);
+        self.pos.push(p);
+        self.adj.push(Vec::new());
+        self.transit.push(transit);
+        i
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize, d: f64) {
+        if a == b || self.adj[a].iter().any(|(x, _)| *x == b) {
+            return;
+        }
+        self.adj[a].push((b, d));
+        self.adj[b].push((a, d));
+    }
+
+    pub fn has_edge(&self, a: Entity, b: Entity) -> bool {
+        match (self.index.get(&a), self.index.get(&b)) {
+            (Some(a), Some(b)) => self.adj[*a].iter().any(|(x, _)| x == b),
+            _ => false,
+        }
+    }
+
+    /// Nodes connected to `e`.
+    pub fn neighbours(&self, e: Entity) -> Vec<Entity> {
+        self.index
+            .get(&e)
+            .map(|i| self.adj[*i].iter().map(|(j, _)| self.nodes[*j]).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn position(&self, e: Entity) -> Option<[f64; 3]> {
+        self.index.get(&e).map(|i| self.pos[*i])
+    }
+
+    pub fn is_transit(&self, e: Entity) -> bool {
+        self.index.get(&e).map_or(false, |i| self.transit[*i])
+    }
+
+    /// Length (m) of a path given as entities, infinite if an edge is missing.
+    pub fn cost_of(&self, nodes: &[Entity]) -> f64 {
+        let idx: Option<Vec<usize>> = nodes.iter().map(|e| self.index.get(e).copied()).collect();
+        idx.map_or(f64::INFINITY, |idx| self.path_cost(&idx))
+    }
+
+    fn to_path(&self, nodes: &[usize], cost: f64) -> GraphPath {
+        GraphPath {
+            nodes: nodes.iter().map(|i| self.nodes[*i]).collect(),
+            cost,
+        }
+    }
+
+    fn path_cost(&self, nodes: &[usize]) -> f64 {
+        nodes
+            .windows(2)
+            .map(|w| {
+                self.adj[w[0]]
+                    .iter()
+                    .find(|(x, _)| *x == w[1])
+                    .map_or(f64::INFINITY, |(_, d)| *d)
+            })
+            .sum()
+    }
+
+    pub(crate) fn dijkstra_idx(
+        &self,
+        src: usize,
+        dst: usize,
+        banned_nodes: &HashSet<usize>,
+        banned_edges: &HashSet<(usize, usize)>,
+    ) -> Option<(Vec<usize>, f64)> {
+        let mut cost = vec![f64::INFINITY; self.nodes.len()];
+        let mut prev = vec![usize::MAX; self.nodes.len()];
+        let mut heap = BinaryHeap::new();
+        cost[src] = 0.0;
+        heap.push(State {
+            cost: 0.0,
+            node: src,
+        });
+        while let Some(State { cost: c, node }) = heap.pop() {
+            if node == dst {
+                let mut path = vec![dst];
+                let mut cur = dst;
+                while cur != src {
+                    cur = prev[cur];
+                    path.push(cur);
+                }
+                path.reverse();
+                return Some((path, c));
+            }
+            if c > cost[node] || (node != src && !self.transit[node]) {
+                continue;
+            }
+            for &(next, d) in &self.adj[node] {
+                if banned_nodes.contains(&next) || banned_edges.contains(&edge_key(node, next)) {
+                    continue;
+                }
+                let nc = c + d;
+                if nc < cost[next] {
+                    cost[next] = nc;
+                    prev[next] = node;
+                    heap.push(State {
+                        cost: nc,
+                        node: next,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Shortest path between two nodes.
+    pub fn shortest_path(&self, a: Entity, b: Entity) -> Option<GraphPath> {
+        let (src, dst) = (*self.index.get(&a)?, *self.index.get(&b)?);
+        self.dijkstra_idx(src, dst, &HashSet::new(), &HashSet::new())
+            .map(|(p, c)| self.to_path(&p, c))
+    }
+
+    /// Yen's algorithm, returns up to `k` loopless paths ordered by length.
+    pub fn k_shortest_paths(&self, a: Entity, b: Entity, k: usize) -> Vec<GraphPath> {
+        let (Some(&src), Some(&dst)) = (self.index.get(&a), self.index.get(&b)) else {
+            return Vec::new();
+        };
+        let Some(first) = self.dijkstra_idx(src, dst, &HashSet::new(), &HashSet::new()) else {
+            return Vec::new();
+        };
+        let mut found: Vec<(Vec<usize>, f64)> = vec![first];
+        let mut candidates: Vec<(Vec<usize>, f64)> = Vec::new();
+
+        while found.len() < k {
+            let last = found.last().unwrap().0.clone();
+            for i in 0..last.len() - 1 {
+                let spur = last[i];
+                let root = &last[..=i];
+                let mut banned_edges = HashSet::new();
+                for (p, _) in &found {
+                    if p.len() > i && &p[..=i] == root {
+                        banned_edges.insert(edge_key(p[i], p[i + 1]));
+                    }
+                }
+                let banned_nodes: HashSet<usize> = root[..i].iter().copied().collect();
+                if let Some((spur_path, _)) =
+                    self.dijkstra_idx(spur, dst, &banned_nodes, &banned_edges)
+                {
+                    let mut total = root[..i].to_vec();
+                    total.extend(spur_path);
+                    if !candidates.iter().any(|(p, _)| *p == total)
+                        && !found.iter().any(|(p, _)| *p == total)
+                    {
+                        let c = self.path_cost(&total);
+                        candidates.push((total, c));
+                    }
+                }
+            }
+            if candidates.is_empty() {
+                break;
+            }
+            let best = candidates
+                .iter()
+                .enumerate()
+                .min_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+                .map(|(i, _)| i)
+                .unwrap();
+            found.push(candidates.swap_remove(best));
+        }
+        found.iter().map(|(p, c)| self.to_path(p, *c)).collect()
+    }
+
+    /**
+    Shortest path and a disjoint backup.
+
+    Uses the two step approach: the backup is the shortest path after removing
+    the primary's edges (or intermediate nodes). It is simple and matches how
+    a primary is usually provisioned first, but can miss a pair in trap
+    topologies where Suurballe's algorithm would find one.
+    */
+    pub fn disjoint_pair(
+        &self,
+        a: Entity,
+        b: Entity,
+        kind: Disjointness,
+    ) -> Option<(GraphPath, Option<GraphPath>)> {
+        let primary = self.shortest_path(a, b)?;
+        let backup = self.disjoint_backup(&primary, kind);
+        Some((primary, backup))
+    }
+
+    /// Shortest path disjoint from `primary`, see [`SatGraph::disjoint_pair`].
+    pub fn disjoint_backup(&self, primary: &GraphPath, kind: Disjointness) -> Option<GraphPath> {
+        let primary: Vec<usize> = primary
+            .nodes
+            .iter()
+            .map(|e| self.index.get(e).copied())
+            .collect::<Option<_>>()?;
+        if primary.len() < 2 {
+            return None;
+        }
+        let (src, dst) = (primary[0], primary[primary.len() - 1]);
+        let mut banned_nodes = HashSet::new();
+        let mut banned_edges = HashSet::new();
+        match kind {
+            Disjointness::Link => {
+                primary.windows(2).for_each(|w| {
+                    banned_edges.insert(edge_key(w[0], w[1]));
+                });
+            }
+            Disjointness::Node => {
+                banned_nodes.extend(primary[1..primary.len() - 1].iter().copied());
+                if primary.len() == 2 {
+                    banned_edges.insert(edge_key(primary[0], primary[1]));
+                }
+            }
+        }
+        self.dijkstra_idx(src, dst, &banned_nodes, &banned_edges)
+            .map(|(p, c)| self.to_path(&p, c))
+    }
+}
+
+/// Ground station as seen by offline snapshot computations.
+#[derive(Clone, Debug)]
+pub struct ScenarioGs {
+    pub entity: Entity,
+    pub id: u64,
+    pub name: String,
+    /// (lat deg, lon deg, alt m)
+    pub lla: (f64, f64, f64),
+    pub antennas: usize,
+}
+
+/// Satellite as seen by offline snapshot computations.
+pub struct ScenarioSat {
+    pub entity: Entity,
+    pub norad_id: u64,
+    pub name: String,
+    pub epoch: NaiveDateTime,
+    pub constants: Constants,
+    /// inclination of the element set (deg)
+    pub inclination: f64,
+}
+
+impl ScenarioSat {
+    pub fn from_elements(entity: Entity, elements: &sgp4::Elements) -> Option<Self> {
+        Some(Self {
+            entity,
+            norad_id: elements.norad_id,
+            name: elements.object_name.clone().unwrap_or_default(),
+            epoch: elements.datetime,
+            constants: Constants::from_elements(elements).ok()?,
+            inclination: elements.inclination,
+        })
+    }
+}
+
+/**
+Everything needed to rebuild the network at an arbitrary time without the ECS:
+satellites propagate from their element sets and ground stations attach to
+their nearest visible satellites, one per antenna.
+*/
+#[derive(Default)]
+pub struct Scenario {
+    pub sats: Vec<ScenarioSat>,
+    pub ground: Vec<ScenarioGs>,
+    /// (name, ground station a, ground station b)
+    pub links: Vec<(String, Entity, Entity)>,
+    /// satellites flagged by the TLE quality checks when the scenario was
+    /// built, left out of snapshots with [`TopologyConfig::exclude_flagged`]
+    pub flagged: HashSet<Entity>,
+}
+
+impl Scenario {
+    /// Satellite positions at `t` (ECEF m), diverged satellites are skipped.
+    pub fn sat_positions(&self, t: &DateTime<Utc>) -> Vec<(Entity, [f64; 3])> {
+        self.sats
+            .iter()
+            .filter_map(|s| {
+                let (pos, _) = propagate_sat_at(&s.epoch, &s.constants, t).ok()?;
+                let lla = teme_to_lla(&pos.0, t);
+                Some((s.entity, geometry::geodetic_to_ecef(lla.0, lla.1, 1000.0 * lla.2)))
+            })
+            .collect()
+    }
+
+    /// Satellites above `min_elevation` of a ground station, nearest first.
+    /// Returns (entity, slant range m, elevation deg).
+    pub fn visible_from(
+        gs: &ScenarioGs,
+        sats: &[(Entity, [f64; 3])],
+        min_elevation: f64,
+    ) -> Vec<(Entity, f64, f64)> {
+        let o = geometry::geodetic_to_ecef(gs.lla.0, gs.lla.1, gs.lla.2);
+        let mut visible: Vec<_> = sats
+            .iter()
+            .filter_map(|(e, p)| {
+                let el = geometry::elevation(o, *p);
+                (el >= min_elevation).then(|| (*e, dist(&o, p), el))
+            })
+            .collect();
+        visible.sort_by(|a, b| a.1.total_cmp(&b.1));
+        visible
+    }
+
+    /// Network graph at time `t`.
+    pub fn snapshot(
+        &self,
+        t: &DateTime<Utc>,
+        cfg: &TopologyConfig,
+        min_elevation: f64,
+    ) -> SatGraph {
+        let mut sats = self.sat_positions(t);
+        if cfg.exclude_flagged {
+            sats.retain(|(e, _)| !self.flagged.contains(e));
+        }
+        let ground: Vec<_> = self
+            .ground
+            .iter()
+            .map(|gs| {
+                let access = Self::visible_from(gs, &sats, min_elevation)
+                    .into_iter()
+                    .take(gs.antennas.max(1))
+                    .map(|(e, _, _)| e)
+                    .collect();
+                (gs.entity, geometry::geodetic_to_ecef(gs.lla.0, gs.lla.1, gs.lla.2), access)
+            })
+            .collect();
+        SatGraph::build(&sats, &ground, cfg)
+    }
+}
+
+/// Builds a [`Scenario`] from the live world.
+#[derive(SystemParam)]
+pub struct ScenarioSource<'w, 's> {
+    info: Res<'w, SatInfo>,
+    sats: Query<'w, 's, (Entity, &'static SatID, Option<&'static TleQuality>)>,
+    gs: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static GroundStationID,
+            &'static LatLonAlt,
+            Option<&'static Name>,
+            Option<&'static Antennas>,
+        ),
+        With<GroundStationID>,
+    >,
+    links: Query<'w, 's, (&'static GSDataLink, Option<&'static Name>)>,
+}
+
+impl<'w, 's> ScenarioSource<'w, 's> {
+    /// Whether the element sets changed since the system last ran.
+    pub fn elements_changed(&self) -> bool {
+        self.info.is_changed()
+    }
+
+    /// A single satellite by NORAD ID, without building the whole scenario.
+    pub fn satellite(&self, norad_id: u64) -> Option<ScenarioSat> {
+        let elements = self.info.sats.get(&norad_id)?;
+        let (entity, ..) = self.sats.iter().find(|(_, id, _)| id.0 == norad_id)?;
+        ScenarioSat::from_elements(entity, elements)
+    }
+
+    pub fn ground(&self) -> Vec<ScenarioGs> {
+        self.gs
+            .iter()
+            .map(|(e, id, llt, name, antennas)| ScenarioGs {
+                entity: e,
+                id: id.0,
+                name: name.map(|n| n.to_string()).unwrap_or_default(),
+                lla: (llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2),
+                antennas: antennas.map_or(1, |a| a.0),
+            })
+            .collect()
+    }
+
+    pub fn build(&self) -> Scenario {
+        let sats = self
+            .sats
+            .iter()
+            .filter_map(|(e, id, _)| ScenarioSat::from_elements(e, self.info.sats.get(&id.0)?))
+            .collect();
+        let flagged = self
+            .sats
+            .iter()
+            .filter(|(_, _, quality)| quality.is_some_and(|q| q.flagged()))
+            .map(|(e, ..)| e)
+            .collect();
+        let ground = self.ground();
+        let links = self
+            .links
+            .iter()
+            .map(|(l, name)| {
+                let name = name.map(|n| n.to_string()).unwrap_or_default();
+                (name, l.0 .0, l.0 .1)
+            })
+            .collect();
+        Scenario {
+            sats,
+            ground,
+            links,
+            flagged,
+        }
+    }
+}
+
+/// Rebuilds the [`SatGraph`] from the current positions.
+pub fn build_sat_graph(
+    mut graph: ResMut<SatGraph>,
+    cfg: Res<TopologyConfig>,
+    sats: Query<(Entity, &LatLonAlt, Option<&TleQuality>), With<SatID>>,
+    gs: Query<(Entity, &LatLonAlt, Option<&AccessSats>, Option<&NearestSat>), With<GroundStationID>>,
+) {
+    let sats: Vec<_> = sats
+        .iter()
+        .filter(|(_, _, quality)| !(cfg.exclude_flagged && quality.is_some_and(|q| q.flagged())))
+        .map(|(e, llt, _)| (e, llt))
+        .map(|(e, llt)| (e, geometry::geodetic_to_ecef(llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2)))
+        .collect();
+    let ground: Vec<_> = gs
+        .iter()
+        .map(|(e, llt, access, nearest)| {
+            let mut linked: Vec<Entity> = access
+                .map(|a| a.links.iter().filter(|l| l.is_active()).map(|l| l.eid).collect())
+                .unwrap_or_default();
+            if linked.is_empty() {
+                linked.extend(nearest.map(|n| n.eid));
+            }
+            (
+                e,
+                geometry::geodetic_to_ecef(llt.0 .0, llt.0 .1, 1000.0 * llt.0 .2),
+                linked,
+            )
+        })
+        .collect();
+    *graph = SatGraph::build(&sats, &ground, &cfg);
+}
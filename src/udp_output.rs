@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    celestrak::SimClock,
+    datalink::{DataLinkStats, GSDataLink},
+};
+
+/// "RSAT" read as little endian u32.
+pub const FRAME_MAGIC: u32 = 0x5441_5352;
+pub const FRAME_VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 32;
+pub const LINK_LEN: usize = 16;
+
+/// Set when the link currently has a path.
+pub const FLAG_UP: u16 = 1;
+/// Set when the delay was extrapolated from the last two samples.
+pub const FLAG_EXTRAPOLATED: u16 = 2;
+
+/// `udp` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpConfig {
+    pub enabled: bool,
+    /// local address of the sending socket
+    pub bind: String,
+    /// every frame is sent to all targets
+    pub targets: Vec<String>,
+    /// frames per second
+    pub rate: f64,
+    /// the scheduler sleeps until this long before a deadline and spins for the rest (µs)
+    pub spin: u64,
+    /// links in frame order, their index is the link ID. Empty to send all
+    /// links with IDs in order of appearance.
+    pub links: Vec<String>,
+    /// extrapolate the delay between two frame-rate samples
+    pub extrapolate: bool,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:0".into(),
+            targets: vec!["127.0.0.1:5600".into()],
+            rate: 1000.0,
+            spin: 200,
+            links: Vec::new(),
+            extrapolate: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct DelaySample {
+    id: u32,
+    hops: u16,
+    up: bool,
+    /// one way delay (s)
+    delay: f64,
+    /// change of the delay per second of simulation time
+    slope: f64,
+}
+
+/// Latest state written by the ECS and read by the scheduler thread.
+#[derive(Default)]
+struct Shared {
+    links: Vec<DelaySample>,
+    /// simulation time at `wall`, and its rate
+    sim_time: f64,
+    wall: Option<Instant>,
+    rate: f64,
+}
+
+#[derive(Default)]
+struct Counters {
+    frames: AtomicU64,
+    late: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Links that fit into one UDP datagram, further links are left out.
+const MAX_FRAME_LINKS: usize = (65507 - 32) / 16;
+
+/**
+Frame layout, little endian:
+
+| offset | type | |
+| --- | --- | --- |
+| 0 | u32 | magic `RSAT` |
+| 4 | u16 | version |
+| 6 | u16 | number of links n |
+| 8 | u64 | sequence number, the tick index, gaps mean skipped ticks |
+| 16 | f64 | simulation time of the tick (unix s) |
+| 24 | u64 | wall clock at send (unix ns) |
+| 32 + 16 i | u32 | link ID |
+| 36 + 16 i | u16 | hops |
+| 38 + 16 i | u16 | flags, see `FLAG_UP` and `FLAG_EXTRAPOLATED` |
+| 40 + 16 i | f64 | one way delay (s) |
+*/
+fn encode_frame(
+    buf: &mut Vec<u8>,
+    seq: u64,
+    sim_time: f64,
+    links: &[DelaySample],
+    elapsed: f64,
+    extrapolate: bool,
+) {
+    buf.clear();
+    buf.extend_from_slice(&FRAME_MAGIC.to_le_bytes());
+    buf.extend_from_slice(&FRAME_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(links.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&sim_time.to_le_bytes());
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    buf.extend_from_slice(&wall.to_le_bytes());
+    for l in links {
+        let mut flags = if l.up { FLAG_UP } else { 0 };
+        let mut delay = l.delay;
+        if extrapolate && l.up && l.slope != 0.0 {
+            delay = (delay + l.slope * elapsed).max(0.0);
+            flags |= FLAG_EXTRAPOLATED;
+        }
+        buf.extend_from_slice(&l.id.to_le_bytes());
+        buf.extend_from_slice(&l.hops.to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&delay.to_le_bytes());
+    }
+}
+
+/**
+Sends a frame at every multiple of the period since start. Deadlines are
+absolute so the rate does not drift. Ticks that are already past when the
+thread wakes up are skipped and counted as late, the sequence number still
+advances so receivers can see the gap.
+*/
+fn run_scheduler(
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    config: UdpConfig,
+    shared: Arc<Mutex<Shared>>,
+    stop: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+) {
+    let period = Duration::from_secs_f64(1.0 / config.rate);
+    let spin = Duration::from_micros(config.spin);
+    let start = Instant::now();
+    let mut buf = Vec::with_capacity(HEADER_LEN + LINK_LEN * 8);
+    let mut links = Vec::new();
+    let mut seq: u64 = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let deadline = start + period.mul_f64(seq as f64);
+        let now = Instant::now();
+        if deadline > now + spin {
+            std::thread::sleep(deadline - now - spin);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+
+        let (sim_time, elapsed) = {
+            let shared = shared.lock().unwrap();
+            links.clear();
+            links.extend_from_slice(&shared.links);
+            let elapsed = shared
+                .wall
+                .map_or(0.0, |w| deadline.saturating_duration_since(w).as_secs_f64() * shared.rate);
+            (shared.sim_time + elapsed, elapsed)
+        };
+        encode_frame(&mut buf, seq, sim_time, &links, elapsed, config.extrapolate);
+        for target in &targets {
+            if socket.send_to(&buf, *target).is_err() {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        counters.frames.fetch_add(1, Ordering::Relaxed);
+
+        seq += 1;
+        let behind = Instant::now().saturating_duration_since(start + period.mul_f64(seq as f64));
+        if behind > Duration::ZERO {
+            let skipped = (behind.as_secs_f64() / period.as_secs_f64()).ceil() as u64;
+            counters.late.fetch_add(skipped, Ordering::Relaxed);
+            seq += skipped;
+        }
+    }
+}
+
+#[derive(Resource)]
+/// Resource owning the UDP scheduler thread, which stops when the resource is dropped.
+pub struct UdpOutput {
+    shared: Arc<Mutex<Shared>>,
+    stop: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    thread: Option<JoinHandle<()>>,
+    ids: HashMap<String, u32>,
+    fixed: bool,
+    /// previous sample per link ID, for the slope
+    last: HashMap<u32, (f64, f64, usize)>,
+}
+
+impl UdpOutput {
+    pub fn start(config: &UdpConfig) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(&config.bind)?;
+        let mut targets = Vec::new();
+        for target in &config.targets {
+            targets.extend(target.to_socket_addrs()?);
+        }
+        let shared = Arc::new(Mutex::new(Shared {
+            rate: 1.0,
+            ..Default::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(Counters::default());
+        let thread = {
+            let (shared, stop, counters) = (shared.clone(), stop.clone(), counters.clone());
+            let config = config.clone();
+            std::thread::Builder::new()
+                .name("udp-output".into())
+                .spawn(move || run_scheduler(socket, targets, config, shared, stop, counters))?
+        };
+        Ok(Self {
+            shared,
+            stop,
+            counters,
+            thread: Some(thread),
+            ids: config
+                .links
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.clone(), i as u32))
+                .collect(),
+            fixed: !config.links.is_empty(),
+            last: HashMap::new(),
+        })
+    }
+
+    /// (frames sent, ticks skipped, send errors)
+    pub fn counters(&self) -> (u64, u64, u64) {
+        (
+            self.counters.frames.load(Ordering::Relaxed),
+            self.counters.late.load(Ordering::Relaxed),
+            self.counters.errors.load(Ordering::Relaxed),
+        )
+    }
+
+    fn link_id(&mut self, name: &str) -> Option<u32> {
+        if let Some(id) = self.ids.get(name) {
+            return Some(*id);
+        }
+        if self.fixed {
+            return None;
+        }
+        let id = self.ids.len() as u32;
+        info!("UDP output: link {} has ID {}", name, id);
+        self.ids.insert(name.to_string(), id);
+        Some(id)
+    }
+}
+
+impl Drop for UdpOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let (frames, late, errors) = self.counters();
+        info!(
+            "UDP output stopped: {} frames, {} ticks skipped, {} send errors",
+            frames, late, errors
+        );
+    }
+}
+
+/// Hands the current link delays to the scheduler thread.
+fn update_udp_output(
+    mut output: ResMut<UdpOutput>,
+    clock: Res<SimClock>,
+    q: Query<(&Name, Option<&DataLinkStats>), With<GSDataLink>>,
+) {
+    let sim_time = clock.now.timestamp_micros() as f64 / 1e6;
+    let mut links = Vec::new();
+    for (name, stats) in q.iter() {
+        let Some(id) = output.link_id(name) else {
+            continue;
+        };
+        let (delay, hops) = stats.map_or((0.0, 0), |s| {
+            (s.latencies.iter().map(|l| *l as f64).sum(), s.latencies.len())
+        });
+        // the slope is only meaningful while the path keeps its hops
+        let slope = match output.last.get(&id) {
+            Some(&(t, d, h)) if h == hops && sim_time > t => (delay - d) / (sim_time - t),
+            _ => 0.0,
+        };
+        output.last.insert(id, (sim_time, delay, hops));
+        links.push(DelaySample {
+            id,
+            hops: hops as u16,
+            up: hops > 0,
+            delay,
+            slope,
+        });
+    }
+    links.sort_by_key(|l| l.id);
+    links.truncate(MAX_FRAME_LINKS);
+    let mut shared = output.shared.lock().unwrap();
+    shared.links = links;
+    shared.sim_time = sim_time;
+    shared.wall = Some(Instant::now());
+    shared.rate = if clock.paused { 0.0 } else { clock.rate };
+}
+
+/**
+Sends the one way delay of every link over UDP at a fixed rate, from a
+thread of its own so the rate does not depend on the render frame rate.
+The frame layout is described at `encode_frame`.
+*/
+#[derive(Default)]
+pub struct UdpOutputPlugin {
+    pub config: UdpConfig,
+}
+
+impl Plugin for UdpOutputPlugin {
+    fn build(&self, app: &mut App) {
+        if !self.config.enabled {
+            return;
+        }
+        if !(1.0..=100_000.0).contains(&self.config.rate) {
+            error!("UDP output rate {} Hz is out of range", self.config.rate);
+            return;
+        }
+        match UdpOutput::start(&self.config) {
+            Ok(output) => {
+                info!(
+                    "UDP output at {} Hz to {:?}",
+                    self.config.rate, self.config.targets
+                );
+                app.insert_resource(output);
+                app.add_systems(PostUpdate, update_udp_output);
+            }
+            Err(err) => error!("cannot start UDP output on {}: {}", self.config.bind, err),
+        }
+    }
+}
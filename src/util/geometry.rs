@@ -0,0 +1,67 @@
+use map_3d::deg2rad;
+
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_F: f64 = 1.0 / 298.257223563;
+
+/// Converts geodetic coordinates (lat deg, lon deg, alt m) to WGS84 ECEF (m).
+pub fn geodetic_to_ecef(lat: f64, lon: f64, alt: f64) -> [f64; 3] {
+    let e_sq = 2.0 * WGS84_F - WGS84_F.powi(2);
+    let (lat, lon) = (deg2rad(lat), deg2rad(lon));
+    let n = WGS84_A / (1.0 - e_sq * lat.sin().powi(2)).sqrt();
+    [
+        (n + alt) * lat.cos() * lon.cos(),
+        (n + alt) * lat.cos() * lon.sin(),
+        (n * (1.0 - e_sq) + alt) * lat.sin(),
+    ]
+}
+
+/// Returns (azimuth deg, elevation deg, slant range m) of a target seen from an observer.
+/// observer: (lat, lon, alt m), target: (lat, lon, alt m)
+pub fn look_angles(observer: (f64, f64, f64), target: (f64, f64, f64)) -> (f64, f64, f64) {
+    let o = geodetic_to_ecef(observer.0, observer.1, observer.2);
+    let t = geodetic_to_ecef(target.0, target.1, target.2);
+    let d = [t[0] - o[0], t[1] - o[1], t[2] - o[2]];
+    let (lat, lon) = (deg2rad(observer.0), deg2rad(observer.1));
+
+    let east = -lon.sin() * d[0] + lon.cos() * d[1];
+    let north = -lat.sin() * lon.cos() * d[0] - lat.sin() * lon.sin() * d[1] + lat.cos() * d[2];
+    let up = lat.cos() * lon.cos() * d[0] + lat.cos() * lon.sin() * d[1] + lat.sin() * d[2];
+
+    let range = (east.powi(2) + north.powi(2) + up.powi(2)).sqrt();
+    let az = east.atan2(north).to_degrees().rem_euclid(360.0);
+    let el = (up / range).asin().to_degrees();
+    (az, el, range)
+}
+
+/// Elevation (deg) of a target seen from an observer, both in ECEF (m).
+pub fn elevation(observer: [f64; 3], target: [f64; 3]) -> f64 {
+    let d = [target[0] - observer[0], target[1] - observer[1], target[2] - observer[2]];
+    let n = (observer[0].powi(2) + observer[1].powi(2) + observer[2].powi(2)).sqrt();
+    let r = (d[0].powi(2) + d[1].powi(2) + d[2].powi(2)).sqrt();
+    if n == 0.0 || r == 0.0 {
+        return 90.0;
+    }
+    // geocentric vertical, close enough to the ellipsoid normal for visibility checks
+    let up = (observer[0] * d[0] + observer[1] * d[1] + observer[2] * d[2]) / (n * r);
+    up.clamp(-1.0, 1.0).asin().to_degrees()
+}
+
+/// Spherical latitude and longitude (deg) of an ECEF point.
+pub fn ecef_to_latlon(p: [f64; 3]) -> (f64, f64) {
+    let lon = p[1].atan2(p[0]).to_degrees();
+    let lat = p[2].atan2((p[0].powi(2) + p[1].powi(2)).sqrt()).to_degrees();
+    (lat, lon)
+}
+
+/// Returns true if the straight line between two ECEF points (m) clears the
+/// Earth by at least `margin` meters (spherical Earth).
+pub fn line_of_sight(a: [f64; 3], b: [f64; 3], margin: f64) -> bool {
+    let d = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let dd = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+    if dd == 0.0 {
+        return true;
+    }
+    let t = (-(a[0] * d[0] + a[1] * d[1] + a[2] * d[2]) / dd).clamp(0.0, 1.0);
+    let p = [a[0] + t * d[0], a[1] + t * d[1], a[2] + t * d[2]];
+    (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt() > map_3d::EARTH_RADIUS + margin
+}
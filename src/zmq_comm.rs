@@ -1,15 +1,86 @@
 use std::{
     string::String,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use bevy::{app::AppExit, prelude::*, time::common_conditions::on_timer};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use zmq::*;
 
-use crate::datalink::*;
+use crate::{
+    celestrak::{SatID, SimClock},
+    command::{CommandAck, CommandMsg, CommandPlugin, RemoteCommand},
+    datalink::*,
+    query::{QueryError, QueryReply, QueryRequest, QueryWorld, RequestHeader, QUERY_SCHEMA_VERSION},
+};
+
+/// Address of a socket and whether the socket binds it or connects to it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub address: String,
+    #[serde(default)]
+    pub bind: bool,
+}
+
+impl Endpoint {
+    fn new(address: &str, bind: bool) -> Self {
+        Self {
+            address: address.into(),
+            bind,
+        }
+    }
+
+    fn open(&self, ctx: &zmq::Context, kind: SocketType) -> zmq::Result<ZmqSocket> {
+        let socket = ctx.socket(kind)?;
+        socket.set_linger(1)?;
+        if self.bind {
+            socket.bind(&self.address)?;
+        } else {
+            socket.connect(&self.address)?;
+        }
+        Ok(ZmqSocket(socket))
+    }
+}
+
+/// `zmq` section of the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZmqConfig {
+    pub enabled: bool,
+    /// PUB socket for link data and command acknowledgements
+    pub publish: Endpoint,
+    /// SUB socket receiving commands
+    pub commands: Endpoint,
+    /// REP socket answering queries, disabled if the address is empty
+    pub query: Endpoint,
+    /// link data messages per second and link
+    pub publish_rate: f64,
+    /// link data is published on `topic_prefix` + link name
+    pub topic_prefix: String,
+    /// subscription filter of the command socket
+    pub command_topic: String,
+    pub ack_topic: String,
+    /// delay before a failed socket is opened again (s)
+    pub reconnect_interval: f64,
+}
+
+impl Default for ZmqConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            publish: Endpoint::new("tcp://127.0.0.1:5551", false),
+            commands: Endpoint::new("tcp://127.0.0.1:5552", false),
+            query: Endpoint::new("tcp://127.0.0.1:5553", true),
+            publish_rate: 60.0,
+            topic_prefix: String::new(),
+            command_topic: String::new(),
+            ack_topic: "ack".into(),
+            reconnect_interval: 2.0,
+        }
+    }
+}
 
 pub struct ZmqSocket(Socket);
 #[derive(Default, Resource)]
@@ -17,61 +88,225 @@ pub struct ZMQContext {
     pub ctx: zmq::Context,
     pub tx: Option<ZmqSocket>,
     pub rx: Option<ZmqSocket>,
-    pub tx_address: String,
-    pub rx_address: String,
-    pub topic: String,
+    /// REP socket answering queries
+    pub rep: Option<ZmqSocket>,
+    pub config: ZmqConfig,
+    /// next attempt to open the missing sockets
+    retry_at: Option<Instant>,
 }
+
+/// Message published for every data link on `topic_prefix` + link name.
+/// Encoded as a msgpack array in field order.
 #[derive(Default, Serialize)]
 pub struct DataLinkMsg {
+    /// per hop latency (s)
     pub latencies: Vec<f32>,
+    /// per hop distance (m)
     pub distance: Vec<f32>,
+    /// wall clock unix timestamp (s)
     pub ts: f64,
+    pub link: String,
+    /// entity IDs of the path nodes, from the first ground station to the last
+    pub hops: Vec<u64>,
+    /// NORAD ID of every hop, nil for ground stations
+    pub norad_ids: Vec<Option<u64>>,
+    /// simulation time as unix timestamp (s)
+    pub sim_time: f64,
 }
 unsafe impl Sync for ZmqSocket {}
 
+/// Drops a socket after an error other than `EAGAIN`, it is opened again later.
+fn check(socket: &mut Option<ZmqSocket>, err: zmq::Error, what: &str) {
+    if err == zmq::Error::EAGAIN {
+        return;
+    }
+    error!("{} failed: {}, reopening the socket", what, err);
+    socket.take();
+}
+
+/// Opens every missing socket, failed ones are retried after `reconnect_interval`.
 fn connect_sockets(mut ctx: ResMut<ZMQContext>) {
-    let s1 = ZmqSocket(ctx.ctx.socket(zmq::PUB).unwrap());
-    // let s2 = ZmqSocket(ctx.ctx.socket(zmq::SUB).unwrap());
-    ctx.tx.replace(s1);
-    //ctx.rx.replace(s2);
-    ctx.tx.as_ref().unwrap().0.set_linger(1).unwrap();
-    ctx.tx.as_ref().unwrap().0.connect(&ctx.tx_address).unwrap();
-    // ctx.rx.as_ref().unwrap().0.connect(&ctx.rx_address).unwrap();
-    // ctx.rx
-    //     .as_ref()
-    //     .unwrap()
-    //     .0
-    //     .set_subscribe(ctx.topic.as_bytes())
-    //     .unwrap();
+    let now = Instant::now();
+    if ctx.retry_at.is_some_and(|t| now < t) {
+        return;
+    }
+    let ctx = &mut *ctx;
+    let mut failed = false;
+    if ctx.tx.is_none() {
+        match ctx.config.publish.open(&ctx.ctx, zmq::PUB) {
+            Ok(s) => ctx.tx = Some(s),
+            Err(err) => {
+                error!("cannot open publish socket {}: {}", ctx.config.publish.address, err);
+                failed = true;
+            }
+        }
+    }
+    if ctx.rx.is_none() {
+        let socket = ctx.config.commands.open(&ctx.ctx, zmq::SUB).and_then(|s| {
+            s.0.set_subscribe(ctx.config.command_topic.as_bytes())?;
+            Ok(s)
+        });
+        match socket {
+            Ok(s) => ctx.rx = Some(s),
+            Err(err) => {
+                error!("cannot open command socket {}: {}", ctx.config.commands.address, err);
+                failed = true;
+            }
+        }
+    }
+    if ctx.rep.is_none() && !ctx.config.query.address.is_empty() {
+        match ctx.config.query.open(&ctx.ctx, zmq::REP) {
+            Ok(s) => ctx.rep = Some(s),
+            Err(err) => {
+                error!("cannot open query socket {}: {}", ctx.config.query.address, err);
+                failed = true;
+            }
+        }
+    }
+    ctx.retry_at = failed.then(|| now + Duration::from_secs_f64(ctx.config.reconnect_interval.max(0.1)));
+}
+
+fn decode_query(payload: &[u8]) -> Result<QueryRequest, QueryReply> {
+    let header: RequestHeader = rmp_serde::from_slice(payload)
+        .map_err(|err| QueryReply::new(0, Err(QueryError::Malformed(err.to_string()))))?;
+    if header.version != QUERY_SCHEMA_VERSION {
+        return Err(QueryReply::new(
+            header.id,
+            Err(QueryError::UnsupportedVersion(header.version)),
+        ));
+    }
+    rmp_serde::from_slice(payload)
+        .map_err(|err| QueryReply::new(header.id, Err(QueryError::Malformed(err.to_string()))))
+}
+
+/**
+Answers the pending requests on the REP socket. Every request gets exactly one
+reply, failures are reported in the reply instead of panicking.
+*/
+fn serve_queries(mut ctx: ResMut<ZMQContext>, mut world: QueryWorld) {
+    loop {
+        let Some(rep) = ctx.rep.as_ref() else {
+            return;
+        };
+        let payload = match rep.0.recv_bytes(zmq::DONTWAIT) {
+            Ok(payload) => payload,
+            Err(err) => return check(&mut ctx.rep, err, "receiving a query"),
+        };
+        let reply = match decode_query(&payload) {
+            Ok(request) => world.answer(&request),
+            Err(reply) => reply,
+        };
+        let data = rmp_serde::to_vec_named(&reply).unwrap_or_else(|err| {
+            let reply = QueryReply::new(reply.id, Err(QueryError::Malformed(err.to_string())));
+            rmp_serde::to_vec_named(&reply).unwrap_or_default()
+        });
+        if let Err(err) = rep.0.send(data, 0) {
+            // a REP socket that could not reply is stuck, so it is always reopened
+            error!("cannot send reply {}: {}", reply.id, err);
+            ctx.rep.take();
+            return;
+        }
+    }
 }
 
-fn publish_data(ctx: Res<ZMQContext>, q: Query<(&Name, &DataLinkStats)>) {
+/**
+Reads the pending commands from the SUB socket without blocking.
+A command is a multipart message `[topic, msgpack CommandMsg]`, malformed
+messages are answered with an error acknowledgement.
+*/
+fn receive_commands(
+    mut ctx: ResMut<ZMQContext>,
+    mut commands: EventWriter<RemoteCommand>,
+    mut acks: EventWriter<CommandAck>,
+) {
+    loop {
+        let Some(rx) = ctx.rx.as_ref() else {
+            return;
+        };
+        let frames = match rx.0.recv_multipart(zmq::DONTWAIT) {
+            Ok(frames) => frames,
+            Err(err) => return check(&mut ctx.rx, err, "receiving a command"),
+        };
+        let Some(payload) = frames.last() else {
+            continue;
+        };
+        match rmp_serde::from_slice::<CommandMsg>(payload) {
+            Ok(msg) => {
+                commands.send(RemoteCommand(msg));
+            }
+            Err(err) => {
+                warn!("malformed command: {}", err);
+                acks.send(CommandAck::error(0, format!("malformed command: {}", err)));
+            }
+        }
+    }
+}
+
+/// Publishes the acknowledgements as `[ack_topic, msgpack CommandAck]` with field names.
+fn publish_acks(mut ctx: ResMut<ZMQContext>, mut acks: EventReader<CommandAck>) {
+    for ack in acks.read() {
+        let Some(tx) = ctx.tx.as_ref() else {
+            warn!("acknowledgement {} dropped, publish socket is closed", ack.id);
+            continue;
+        };
+        let data = match rmp_serde::to_vec_named(ack) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("cannot encode acknowledgement {}: {}", ack.id, err);
+                continue;
+            }
+        };
+        let msg = [ctx.config.ack_topic.as_bytes(), data.as_ref()];
+        if let Err(err) = tx.0.send_multipart(&msg, zmq::DONTWAIT) {
+            check(&mut ctx.tx, err, "publishing an acknowledgement");
+        }
+    }
+}
+
+fn publish_data(
+    mut ctx: ResMut<ZMQContext>,
+    clock: Res<SimClock>,
+    q: Query<(&Name, &DataLinkStats, Option<&DataLink>)>,
+    sats: Query<&SatID>,
+) {
     let ts = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs_f64();
-    if ctx.tx.is_none() {
-        return;
+    for (name, stats, path) in q.iter() {
+        let Some(tx) = ctx.tx.as_ref() else {
+            return;
+        };
+        let hops: Vec<Entity> = path.map(|p| path_nodes(&p.0)).unwrap_or_default();
+        let s = DataLinkMsg {
+            latencies: stats.latencies.to_owned(),
+            distance: stats.distance.to_owned(),
+            ts,
+            link: name.to_string(),
+            norad_ids: hops.iter().map(|e| sats.get(*e).ok().map(|id| id.0)).collect(),
+            hops: hops.iter().map(|e| e.to_bits()).collect(),
+            sim_time: clock.now.timestamp_micros() as f64 / 1e6,
+        };
+        let data = match rmp_serde::to_vec(&s) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("cannot encode {}: {}", name, err);
+                continue;
+            }
+        };
+        let topic = format!("{}{}", ctx.config.topic_prefix, name);
+        let msg = [topic.as_bytes(), data.as_ref()];
+        if let Err(err) = tx.0.send_multipart(&msg, zmq::DONTWAIT) {
+            check(&mut ctx.tx, err, "publishing link data");
+        }
     }
-    q.iter().for_each(|(name, stats)| {
-        let mut s = DataLinkMsg::default();
-        s.latencies = stats.latencies.to_owned();
-        s.distance = stats.distance.to_owned();
-
-        s.ts = ts;
-        let data = rmp_serde::to_vec(&s).unwrap();
-        let msg = [name.as_bytes(), data.as_ref()];
-        ctx.tx
-            .as_ref()
-            .unwrap()
-            .0
-            .send_multipart(&msg, zmq::DONTWAIT)
-            .unwrap();
-    });
 }
 
+/// Publishes link data and serves commands and queries over ZeroMQ, see [`ZmqConfig`].
 #[derive(Default)]
-pub struct ZMQPlugin;
+pub struct ZMQPlugin {
+    pub config: ZmqConfig,
+}
 
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
 
@@ -79,12 +314,28 @@ pub struct CommStage;
 
 impl Plugin for ZMQPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, connect_sockets);
+        if !self.config.enabled {
+            return;
+        }
+        if !app.is_plugin_added::<CommandPlugin>() {
+            app.add_plugins(CommandPlugin);
+        }
+        app.insert_resource(ZMQContext {
+            config: self.config.clone(),
+            ..Default::default()
+        });
+        app.add_systems(PreUpdate, (connect_sockets, receive_commands).chain());
+        app.add_systems(Update, serve_queries);
+        app.add_systems(PostUpdate, publish_acks);
 
-        app.add_systems(
-            PostUpdate,
-            publish_data.run_if(on_timer(Duration::from_secs_f32(1.0 / 60.0))),
-        );
+        if self.config.publish_rate > 0.0 {
+            app.add_systems(
+                PostUpdate,
+                publish_data.run_if(on_timer(Duration::from_secs_f64(
+                    1.0 / self.config.publish_rate,
+                ))),
+            );
+        }
         //app.configure_set(CommStage.after(CoreSet::PostUpdate));
     }
 }